@@ -0,0 +1,47 @@
+use crate::tasks::eight::provider::PokedexSource;
+use crate::tasks::eighteen::graphql::GraphqlLimits;
+use crate::tasks::eighteen::OrphanPolicy;
+use crate::tasks::eleven::limits::ImageLimits;
+use crate::tasks::eleven::store::AssetStoreConfig;
+use crate::tasks::fourteen::sanitize::SanitizerConfig;
+use crate::tasks::fourteen::templates::TemplateConfig;
+use crate::tasks::thirteen::tenants::TenantConfig;
+use crate::tasks::twelve::store::TimedStoreConfig;
+use std::str::FromStr;
+
+#[derive(Clone, Debug, Default)]
+pub struct AppConfig {
+    pub pokedex: PokedexSource,
+    pub assets: AssetStoreConfig,
+    pub images: ImageLimits,
+    pub timed_store: TimedStoreConfig,
+    pub orphans: OrphanPolicy,
+    pub graphql: GraphqlLimits,
+    pub tenants: TenantConfig,
+    pub templates: TemplateConfig,
+    pub sanitizer: SanitizerConfig,
+}
+
+impl AppConfig {
+    pub fn from_env() -> Self {
+        AppConfig {
+            pokedex: PokedexSource::from_env(),
+            assets: AssetStoreConfig::from_env(),
+            images: ImageLimits::from_env(),
+            timed_store: TimedStoreConfig::from_env(),
+            orphans: OrphanPolicy::from_env(),
+            graphql: GraphqlLimits::from_env(),
+            tenants: TenantConfig::from_env(),
+            templates: TemplateConfig::from_env(),
+            sanitizer: SanitizerConfig::from_env(),
+        }
+    }
+}
+
+// Reads an environment variable, falling back to the default when it is unset or unparsable
+pub fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    std::env::var(key)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
@@ -1,19 +1,61 @@
+mod config;
 mod tasks;
 
+use crate::config::AppConfig;
+use crate::tasks::eight::provider::PokemonProvider;
+use crate::tasks::eighteen::graphql::OrdersSchema;
+use crate::tasks::eighteen::OrphanPolicy;
+use crate::tasks::eleven::limits::ImageLimits;
+use crate::tasks::eleven::store::AssetStore;
+use crate::tasks::fourteen::sanitize::Sanitizer;
+use crate::tasks::fourteen::templates::Templates;
 use crate::tasks::nineteen::Room;
+use crate::tasks::thirteen::tenants::Tenants;
+use crate::tasks::twelve::store::{SystemClock, TimedStore};
 use actix::Addr;
 use actix_web::{web, web::ServiceConfig};
 use shuttle_actix_web::ShuttleActixWeb;
-use shuttle_shared_db;
 use sqlx::PgPool;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex as SyncMutex};
 use tokio::sync::Mutex;
+use ulid::Generator;
 
 pub struct AppState {
-    pool: PgPool,
     rooms: Mutex<HashMap<i32, Addr<Room>>>,
     view_count: Arc<SyncMutex<usize>>,
+    pokedex: Box<dyn PokemonProvider>,
+    assets: AssetStore,
+    image_limits: ImageLimits,
+    timed_store: TimedStore,
+    ulid_generator: SyncMutex<Generator>,
+    orphan_policy: OrphanPolicy,
+    tenants: Tenants,
+    graphql: OrdersSchema,
+    templates: Templates,
+    sanitizer: Sanitizer,
+}
+
+impl AppState {
+    pub fn new(pool: PgPool, config: AppConfig) -> Self {
+        AppState {
+            rooms: Mutex::new(HashMap::new()),
+            view_count: Arc::new(SyncMutex::new(0_usize)),
+            pokedex: config
+                .pokedex
+                .build()
+                .expect("Failed to set up the Pokédex"),
+            assets: AssetStore::new(config.assets),
+            image_limits: config.images,
+            timed_store: TimedStore::new(config.timed_store, Arc::new(SystemClock)),
+            ulid_generator: SyncMutex::new(Generator::new()),
+            orphan_policy: config.orphans,
+            tenants: Tenants::new(pool, config.tenants),
+            graphql: config.graphql.schema(),
+            templates: Templates::new(config.templates),
+            sanitizer: Sanitizer::new(config.sanitizer),
+        }
+    }
 }
 
 #[shuttle_runtime::main]
@@ -23,11 +65,8 @@ async fn main(
     )]
     pool: PgPool,
 ) -> ShuttleActixWeb<impl FnOnce(&mut ServiceConfig) + Send + Clone + 'static> {
-    let state = web::Data::new(AppState {
-        pool,
-        rooms: Mutex::new(HashMap::new()),
-        view_count: Arc::new(SyncMutex::new(0_usize)),
-    });
+    let state = web::Data::new(AppState::new(pool, AppConfig::from_env()));
+    state.timed_store.spawn_sweeper();
     let config = move |cfg: &mut ServiceConfig| {
         cfg.app_data(state)
             .app_data(web::PayloadConfig::new(1024 * 1024)) // 1MB
@@ -42,24 +81,55 @@ async fn main(
             .service(tasks::seven::bake_recipe)
             .service(tasks::eight::pokemon_weight)
             .service(tasks::eight::pokemon_drop)
+            .service(tasks::eight::simulate_drop)
+            .service(tasks::eight::pokemon_batch)
+            .service(tasks::eleven::list_assets)
+            .service(tasks::eleven::upload_assets)
+            .service(tasks::eleven::delete_asset)
+            .service(tasks::eleven::asset_by_hash)
             .service(tasks::eleven::assets)
             .service(tasks::eleven::count_red_pixels)
+            .service(tasks::eleven::analyze_image)
             .service(tasks::twelve::save_string)
             .service(tasks::twelve::load_string)
+            .service(tasks::twelve::list_keys)
+            .service(tasks::twelve::get_key)
+            .service(tasks::twelve::delete_key)
             .service(tasks::twelve::convert_ulids_to_uuids)
+            .service(tasks::twelve::convert_uuids_to_ulids)
+            .service(tasks::twelve::generate_ulids)
+            .service(tasks::twelve::inspect_id)
             .service(tasks::twelve::analyze_ulids)
+            .service(tasks::twelve::ulid_analytics)
             .service(tasks::thirteen::sql)
             .service(tasks::thirteen::reset)
             .route("/13/orders", web::post().to(tasks::thirteen::add_orders))
+            .service(tasks::thirteen::import_orders)
+            .service(tasks::thirteen::export_orders)
             .service(tasks::thirteen::total_orders)
             .service(tasks::thirteen::most_popular_gift)
+            .service(tasks::thirteen::order_trends)
+            .service(tasks::thirteen::list_orders)
+            .service(tasks::thirteen::create_order)
+            .service(tasks::thirteen::get_order)
+            .service(tasks::thirteen::update_order)
+            .service(tasks::thirteen::delete_order)
             .service(tasks::fourteen::unsafe_endpoint)
             .service(tasks::fourteen::safe_endpoint)
+            .service(tasks::fourteen::sanitized_endpoint)
+            .service(tasks::fourteen::markdown_endpoint)
+            .service(tasks::fourteen::render_template)
             .service(tasks::fifteen::validate_password)
             .service(tasks::fifteen::game)
             .service(tasks::eighteen::reset_advanced)
             .route("/18/orders", web::post().to(tasks::thirteen::add_orders))
             .service(tasks::eighteen::add_regions)
+            .service(tasks::eighteen::import_regions)
+            .service(tasks::eighteen::export_regions)
+            .service(tasks::eighteen::orphans)
+            .service(tasks::thirteen::feed::order_feed)
+            .service(tasks::eighteen::graphql::graphql)
+            .service(tasks::thirteen::tenants::list_tenants)
             .service(tasks::eighteen::total_regions)
             .service(tasks::eighteen::top_list)
             .service(tasks::nineteen::ping_pong)
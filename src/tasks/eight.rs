@@ -1,47 +1,226 @@
-use actix_web::{get, web, HttpResponse, Responder};
-
-#[get("/8/weight/{pokemon_id}")]
-pub async fn pokemon_weight(pokemon_id: web::Path<i64>) -> impl Responder {
-    let rustemon_client = rustemon::client::RustemonClient::default();
-    let pokemon_res = rustemon::pokemon::pokemon::get_by_id(*pokemon_id, &rustemon_client).await;
-    if let Ok(pokemon) = pokemon_res {
-        let weight_kg = pokemon.weight as f64 / 10_f64;
-        HttpResponse::Ok().body(weight_kg.to_string())
+pub(crate) mod cache;
+pub(crate) mod physics;
+pub(crate) mod provider;
+
+use crate::tasks::eight::physics::{
+    Drag, Fall, Gravity, DEFAULT_DRAG_COEFFICIENT, DEFAULT_HEIGHT_M,
+};
+use crate::tasks::eight::provider::{PokemonRecord, PokemonRef};
+use crate::AppState;
+use actix_web::{get, post, web, HttpResponse, Responder};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+const BATCH_CONCURRENCY: usize = 8;
+const MAX_BATCH_SIZE: usize = 151;
+
+async fn lookup(state: &AppState, pokemon: &str) -> Result<PokemonRecord, String> {
+    let pokemon = PokemonRef::parse(pokemon)?;
+    state.pokedex.pokemon(&pokemon).await
+}
+
+fn weight_kg(pokemon: &PokemonRecord) -> f64 {
+    pokemon.weight as f64 / 10_f64
+}
+
+fn default_fall(pokemon: &PokemonRecord) -> Fall {
+    Fall {
+        mass_kg: weight_kg(pokemon),
+        height_m: DEFAULT_HEIGHT_M,
+        gravity: Gravity::from_preset("default").unwrap(),
+        drag: None,
+    }
+}
+
+#[get("/8/weight/{pokemon}")]
+pub async fn pokemon_weight(
+    state: web::Data<AppState>,
+    pokemon: web::Path<String>,
+) -> impl Responder {
+    if let Ok(pokemon) = lookup(&state, &pokemon).await {
+        HttpResponse::Ok().body(weight_kg(&pokemon).to_string())
     } else {
         HttpResponse::BadRequest().body("Bad request")
     }
 }
 
-const G: f64 = 9.825_f64;
-const HEIGHT_M: f64 = 10_f64;
-#[get("/8/drop/{pokemon_id}")]
-pub async fn pokemon_drop(pokemon_id: web::Path<i64>) -> impl Responder {
-    let rustemon_client = rustemon::client::RustemonClient::default();
-    let pokemon_res = rustemon::pokemon::pokemon::get_by_id(*pokemon_id, &rustemon_client).await;
-    if let Ok(pokemon) = pokemon_res {
-        let weight_kg = pokemon.weight as f64 / 10_f64;
+#[get("/8/drop/{pokemon}")]
+pub async fn pokemon_drop(
+    state: web::Data<AppState>,
+    pokemon: web::Path<String>,
+) -> impl Responder {
+    let impact = lookup(&state, &pokemon)
+        .await
+        .ok()
+        .and_then(|pokemon| default_fall(&pokemon).simulate());
+    if let Some(impact) = impact {
+        HttpResponse::Ok().body(format!("{:.14}", impact.momentum))
+    } else {
+        HttpResponse::BadRequest().body("Bad request")
+    }
+}
 
-        // Calculate the final velocity just before impact
-        let final_velocity = f64::sqrt(2_f64 * G * HEIGHT_M);
+const MAX_HEIGHT_M: f64 = 10_000_f64;
 
-        // Calculate momentum
-        let momentum = weight_kg * final_velocity;
+#[derive(Deserialize)]
+struct SimulationQuery {
+    height: Option<f64>,
+    gravity: Option<String>,
+    drag: Option<bool>,
+    drag_coefficient: Option<f64>,
+}
 
-        HttpResponse::Ok().body(format!("{:.14}", momentum))
-    } else {
-        HttpResponse::BadRequest().body("Bad request")
+#[get("/8/drop/{pokemon}/simulate")]
+pub async fn simulate_drop(
+    state: web::Data<AppState>,
+    pokemon: web::Path<String>,
+    query: web::Query<SimulationQuery>,
+) -> impl Responder {
+    let height_m = query.height.unwrap_or(DEFAULT_HEIGHT_M);
+    if !(height_m > 0_f64 && height_m <= MAX_HEIGHT_M) {
+        return HttpResponse::BadRequest().body(format!(
+            "Height must be greater than 0 and at most {} metres",
+            MAX_HEIGHT_M
+        ));
+    }
+    let Some(gravity) = Gravity::parse(query.gravity.as_deref().unwrap_or("default")) else {
+        return HttpResponse::BadRequest().body("Unknown gravity preset");
+    };
+    let drag_coefficient = query.drag_coefficient.unwrap_or(DEFAULT_DRAG_COEFFICIENT);
+    if !(drag_coefficient >= 0_f64 && drag_coefficient.is_finite()) {
+        return HttpResponse::BadRequest().body("Drag coefficient must not be negative");
+    }
+
+    let Ok(pokemon) = lookup(&state, &pokemon).await else {
+        return HttpResponse::BadRequest().body("Bad request");
+    };
+    let body_height_m = pokemon.height as f64 / 10_f64;
+    let fall = Fall {
+        mass_kg: weight_kg(&pokemon),
+        height_m,
+        gravity,
+        drag: query
+            .drag
+            .unwrap_or(false)
+            .then(|| Drag::for_body_height(body_height_m, drag_coefficient)),
+    };
+    let Some(impact) = fall.simulate() else {
+        return HttpResponse::BadRequest().body("The fall never reaches the ground");
+    };
+
+    HttpResponse::Ok().json(json!({
+        "pokemon": pokemon.name,
+        "mass_kg": fall.mass_kg,
+        "height_m": height_m,
+        "gravity": gravity.acceleration,
+        "air_density": gravity.air_density,
+        "drag": fall.drag.is_some(),
+        "time_s": impact.time_s,
+        "impact_velocity": impact.velocity,
+        "momentum": impact.momentum,
+        "kinetic_energy": impact.kinetic_energy,
+    }))
+}
+
+// Accepts both `25` and `"pikachu"` in batch requests
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BatchItem {
+    Id(i64),
+    Name(String),
+}
+
+#[derive(Serialize)]
+struct BatchResult {
+    query: String,
+    #[serde(flatten)]
+    outcome: BatchOutcome,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum BatchOutcome {
+    Found {
+        id: i64,
+        name: String,
+        weight: f64,
+        drop_momentum: f64,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+#[post("/8/batch")]
+pub async fn pokemon_batch(
+    state: web::Data<AppState>,
+    items: web::Json<Vec<BatchItem>>,
+) -> impl Responder {
+    if items.len() > MAX_BATCH_SIZE {
+        return HttpResponse::PayloadTooLarge().body(format!(
+            "At most {} Pokémon can be looked up at once",
+            MAX_BATCH_SIZE
+        ));
     }
+
+    let queries = items.into_inner().into_iter().map(|item| match item {
+        BatchItem::Id(id) => id.to_string(),
+        BatchItem::Name(name) => name,
+    });
+    // `buffered` keeps the results in request order while limiting upstream concurrency
+    let results: Vec<BatchResult> = futures::stream::iter(queries)
+        .map(|query| {
+            let state = state.clone();
+            async move {
+                let outcome = match lookup(&state, &query).await {
+                    Ok(pokemon) => match default_fall(&pokemon).simulate() {
+                        Some(impact) => BatchOutcome::Found {
+                            weight: weight_kg(&pokemon),
+                            drop_momentum: impact.momentum,
+                            id: pokemon.id,
+                            name: pokemon.name,
+                        },
+                        None => BatchOutcome::Failed {
+                            error: "The fall never reaches the ground".to_string(),
+                        },
+                    },
+                    Err(error) => BatchOutcome::Failed { error },
+                };
+                BatchResult { query, outcome }
+            }
+        })
+        .buffered(BATCH_CONCURRENCY)
+        .collect()
+        .await;
+
+    HttpResponse::Ok().json(results)
 }
 
 #[cfg(test)]
 mod test {
+    use crate::config::AppConfig;
+    use crate::tasks::eight::provider::PokedexSource;
     use actix_web::{test, App};
+    use sqlx::postgres::PgPoolOptions;
 
     use super::*;
 
+    fn set_up_state() -> web::Data<AppState> {
+        let pool = PgPoolOptions::new()
+            .connect_lazy("postgres://localhost/postgres")
+            .unwrap();
+        let config = AppConfig {
+            pokedex: PokedexSource::Fixture("static/pokedex.json".into()),
+            ..Default::default()
+        };
+        web::Data::new(AppState::new(pool, config))
+    }
+
     #[actix_web::test]
     async fn test_pokemon_weight() {
-        let app = test::init_service(App::new().service(pokemon_weight)).await;
+        let app =
+            test::init_service(App::new().app_data(set_up_state()).service(pokemon_weight)).await;
 
         let req = test::TestRequest::get().uri("/8/weight/25").to_request();
         let res = test::call_service(&app, req).await;
@@ -55,7 +234,8 @@ mod test {
 
     #[actix_web::test]
     async fn test_pokemon_drop() {
-        let app = test::init_service(App::new().service(pokemon_drop)).await;
+        let app =
+            test::init_service(App::new().app_data(set_up_state()).service(pokemon_drop)).await;
 
         let req = test::TestRequest::get().uri("/8/drop/25").to_request();
         let res = test::call_service(&app, req).await;
@@ -66,4 +246,74 @@ mod test {
             .expect("Failed to convert response to string");
         assert_eq!(res_body, "84.10707461325713");
     }
+
+    #[actix_web::test]
+    async fn test_simulate_drop() {
+        let app =
+            test::init_service(App::new().app_data(set_up_state()).service(simulate_drop)).await;
+
+        let req = test::TestRequest::get()
+            .uri("/8/drop/25/simulate")
+            .to_request();
+        let res: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(res["pokemon"], "pikachu");
+        assert_eq!(
+            format!("{:.14}", res["momentum"].as_f64().unwrap()),
+            "84.10707461325713"
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/8/drop/25/simulate?height=20&gravity=moon&drag=true")
+            .to_request();
+        let res: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        // No atmosphere on the moon, so drag has nothing to act on
+        assert_eq!(res["drag"], true);
+        let velocity = res["impact_velocity"].as_f64().unwrap();
+        assert!((velocity - f64::sqrt(2.0 * 1.62 * 20.0)).abs() < 1e-9);
+
+        for uri in [
+            "/8/drop/25/simulate?gravity=pluto",
+            "/8/drop/25/simulate?height=10000&drag=true&drag_coefficient=1e308",
+        ] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), 400, "{}", uri);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_pokemon_by_name() {
+        let app =
+            test::init_service(App::new().app_data(set_up_state()).service(pokemon_weight)).await;
+
+        let req = test::TestRequest::get()
+            .uri("/8/weight/Pikachu")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert!(res.status().is_success());
+        assert_eq!(test::read_body(res).await, "6");
+    }
+
+    #[actix_web::test]
+    async fn test_pokemon_batch() {
+        let app =
+            test::init_service(App::new().app_data(set_up_state()).service(pokemon_batch)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/8/batch")
+            .set_json(json!([25, "snorlax", "missingno", "not a name"]))
+            .to_request();
+        let res: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+        assert_eq!(res[0]["query"], "25");
+        assert_eq!(res[0]["name"], "pikachu");
+        assert_eq!(
+            format!("{:.14}", res[0]["drop_momentum"].as_f64().unwrap()),
+            "84.10707461325713"
+        );
+        assert_eq!(res[1]["id"], 143);
+        assert_eq!(res[1]["weight"], 460.0);
+        assert!(res[2]["error"].is_string());
+        assert!(res[3]["error"].is_string());
+    }
 }
@@ -0,0 +1,275 @@
+use crate::config::env_or;
+use crate::tasks::eight::provider::{PokemonProvider, PokemonRecord, PokemonRef};
+use async_trait::async_trait;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use lru::LruCache;
+use rustemon::client::{CacheMode, RustemonClient, RustemonClientBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+
+#[derive(Clone, Debug)]
+pub struct PokeApiCacheConfig {
+    pub ttl: Duration,
+    pub capacity: usize,
+    pub disk_dir: Option<PathBuf>,
+}
+
+impl Default for PokeApiCacheConfig {
+    fn default() -> Self {
+        PokeApiCacheConfig {
+            ttl: Duration::from_secs(24 * 60 * 60),
+            capacity: 1024,
+            disk_dir: None,
+        }
+    }
+}
+
+impl PokeApiCacheConfig {
+    pub fn from_env() -> Self {
+        let default = PokeApiCacheConfig::default();
+        PokeApiCacheConfig {
+            ttl: Duration::from_secs(env_or("POKEAPI_CACHE_TTL_SECS", default.ttl.as_secs())),
+            capacity: env_or("POKEAPI_CACHE_CAPACITY", default.capacity),
+            disk_dir: std::env::var("POKEAPI_CACHE_DIR").ok().map(PathBuf::from),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct DiskEntry {
+    stored_at: u64,
+    record: PokemonRecord,
+}
+
+type Lookup = Shared<BoxFuture<'static, Result<PokemonRecord, String>>>;
+
+pub struct PokeApiCache {
+    client: Arc<RustemonClient>,
+    ttl: Duration,
+    disk_dir: Option<PathBuf>,
+    memory: Mutex<LruCache<String, (Instant, PokemonRecord)>>,
+    in_flight: Mutex<HashMap<String, Lookup>>,
+}
+
+impl PokeApiCache {
+    pub fn new(config: PokeApiCacheConfig) -> Self {
+        // Caching is handled here, so the client's own HTTP cache is turned off
+        let client = RustemonClientBuilder::default()
+            .with_mode(CacheMode::NoStore)
+            .try_build()
+            .unwrap_or_default();
+        let capacity = NonZeroUsize::new(config.capacity).unwrap_or(NonZeroUsize::MIN);
+
+        PokeApiCache {
+            client: Arc::new(client),
+            ttl: config.ttl,
+            disk_dir: config.disk_dir,
+            memory: Mutex::new(LruCache::new(capacity)),
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn lookup(&self, pokemon: &PokemonRef) -> Result<PokemonRecord, String> {
+        let key = format!("pokemon-{}", pokemon);
+        if let Some(record) = self.memory_get(&key) {
+            return Ok(record);
+        }
+
+        // Concurrent lookups for the same key share a single upstream request
+        let lookup = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_insert_with(|| {
+                let client = self.client.clone();
+                let path = self.disk_path(&key);
+                let ttl = self.ttl;
+                let pokemon = pokemon.clone();
+                async move {
+                    fetch(path, ttl, || async move {
+                        let result = match &pokemon {
+                            PokemonRef::Id(id) => {
+                                rustemon::pokemon::pokemon::get_by_id(*id, &client).await
+                            }
+                            PokemonRef::Name(name) => {
+                                rustemon::pokemon::pokemon::get_by_name(name, &client).await
+                            }
+                        };
+                        result.map(PokemonRecord::from).map_err(|e| e.to_string())
+                    })
+                    .await
+                }
+                .boxed()
+                .shared()
+            })
+            .clone();
+
+        let result = lookup.clone().await;
+
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight
+            .get(&key)
+            .is_some_and(|current| current.ptr_eq(&lookup))
+        {
+            in_flight.remove(&key);
+            if let Ok(record) = &result {
+                self.memory
+                    .lock()
+                    .unwrap()
+                    .put(key, (Instant::now(), record.clone()));
+            }
+        }
+
+        result
+    }
+
+    fn memory_get(&self, key: &str) -> Option<PokemonRecord> {
+        let mut memory = self.memory.lock().unwrap();
+        match memory.get(key) {
+            Some((stored_at, record)) if stored_at.elapsed() < self.ttl => Some(record.clone()),
+            Some(_) => {
+                memory.pop(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn disk_path(&self, key: &str) -> Option<PathBuf> {
+        self.disk_dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.json", key)))
+    }
+}
+
+#[async_trait]
+impl PokemonProvider for PokeApiCache {
+    async fn pokemon(&self, pokemon: &PokemonRef) -> Result<PokemonRecord, String> {
+        self.lookup(pokemon).await
+    }
+}
+
+async fn fetch<F, Fut>(
+    path: Option<PathBuf>,
+    ttl: Duration,
+    upstream: F,
+) -> Result<PokemonRecord, String>
+where
+    F: FnOnce() -> Fut,
+    Fut: std::future::Future<Output = Result<PokemonRecord, String>>,
+{
+    if let Some(path) = &path {
+        if let Some(record) = read_disk(path, ttl).await {
+            return Ok(record);
+        }
+    }
+
+    let record = upstream().await?;
+
+    if let Some(path) = &path {
+        // A failed write only costs a refetch after restart
+        let _ = write_disk(path, &record).await;
+    }
+
+    Ok(record)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+async fn read_disk(path: &PathBuf, ttl: Duration) -> Option<PokemonRecord> {
+    let contents = fs::read(path).await.ok()?;
+    let entry: DiskEntry = serde_json::from_slice(&contents).ok()?;
+    if unix_now().saturating_sub(entry.stored_at) < ttl.as_secs() {
+        Some(entry.record)
+    } else {
+        None
+    }
+}
+
+async fn write_disk(path: &PathBuf, record: &PokemonRecord) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    let entry = DiskEntry {
+        stored_at: unix_now(),
+        record: record.clone(),
+    };
+    fs::write(path, serde_json::to_vec(&entry)?).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pikachu() -> PokemonRecord {
+        PokemonRecord {
+            id: 25,
+            name: "pikachu".to_string(),
+            weight: 60,
+            height: 4,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_disk_entry_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = PokeApiCacheConfig {
+            disk_dir: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        };
+        let path = dir.path().join("pokemon-25.json");
+        write_disk(&path, &pikachu()).await.unwrap();
+
+        // A fresh cache reads the persisted entry without going upstream
+        let cache = PokeApiCache::new(config);
+        assert_eq!(cache.pokemon(&PokemonRef::Id(25)).await, Ok(pikachu()));
+    }
+
+    #[actix_web::test]
+    async fn test_expired_disk_entry_is_refetched() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pokemon-25.json");
+        write_disk(&path, &pikachu()).await.unwrap();
+
+        let result = fetch(Some(path), Duration::ZERO, || async {
+            Err("upstream called".to_string())
+        })
+        .await;
+        assert_eq!(result, Err("upstream called".to_string()));
+    }
+
+    #[actix_web::test]
+    async fn test_concurrent_lookups_are_coalesced() {
+        let dir = tempfile::tempdir().unwrap();
+        write_disk(&dir.path().join("pokemon-25.json"), &pikachu())
+            .await
+            .unwrap();
+        let cache = PokeApiCache::new(PokeApiCacheConfig {
+            disk_dir: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        });
+
+        let (a, b) = futures::join!(
+            cache.pokemon(&PokemonRef::Id(25)),
+            cache.pokemon(&PokemonRef::Id(25))
+        );
+        assert_eq!(a, Ok(pikachu()));
+        assert_eq!(b, Ok(pikachu()));
+        assert!(cache.in_flight.lock().unwrap().is_empty());
+
+        // Once loaded, the entry is served from memory even if the disk copy goes away
+        std::fs::remove_file(dir.path().join("pokemon-25.json")).unwrap();
+        assert_eq!(cache.pokemon(&PokemonRef::Id(25)).await, Ok(pikachu()));
+    }
+}
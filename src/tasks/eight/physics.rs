@@ -0,0 +1,216 @@
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+// The challenge's value for Earth's gravity, kept as the default so plain drops stay unchanged
+pub const DEFAULT_GRAVITY: f64 = 9.825_f64;
+pub const DEFAULT_HEIGHT_M: f64 = 10_f64;
+// Drag coefficient of a sphere, the crudest reasonable shape for a Pokémon
+pub const DEFAULT_DRAG_COEFFICIENT: f64 = 0.47_f64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Gravity {
+    pub acceleration: f64,
+    // Air density at the surface in kg/m³
+    pub air_density: f64,
+}
+
+impl Gravity {
+    pub fn from_preset(preset: &str) -> Option<Gravity> {
+        let (acceleration, air_density) = match preset.to_lowercase().as_str() {
+            "default" => (DEFAULT_GRAVITY, 1.225),
+            "earth" => (9.80665, 1.225),
+            "moon" => (1.62, 0.0),
+            "mars" => (3.721, 0.020),
+            "venus" => (8.87, 65.0),
+            "jupiter" => (24.79, 0.16),
+            _ => return None,
+        };
+        Some(Gravity {
+            acceleration,
+            air_density,
+        })
+    }
+
+    // Accepts either a preset name or a plain acceleration in m/s²
+    pub fn parse(value: &str) -> Option<Gravity> {
+        Gravity::from_preset(value).or_else(|| {
+            value
+                .parse::<f64>()
+                .ok()
+                .filter(|g| g.is_finite() && *g > 0.0)
+                .map(|acceleration| Gravity {
+                    acceleration,
+                    air_density: 1.225,
+                })
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Fall {
+    pub mass_kg: f64,
+    pub height_m: f64,
+    pub gravity: Gravity,
+    // Drag is only applied when a cross-section is given
+    pub drag: Option<Drag>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Drag {
+    pub cross_section_m2: f64,
+    pub coefficient: f64,
+}
+
+impl Drag {
+    // Treats the Pokémon as a sphere whose diameter is its height
+    pub fn for_body_height(body_height_m: f64, coefficient: f64) -> Drag {
+        let radius = body_height_m / 2_f64;
+        Drag {
+            cross_section_m2: PI * radius * radius,
+            coefficient,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Impact {
+    pub time_s: f64,
+    pub velocity: f64,
+    pub momentum: f64,
+    pub kinetic_energy: f64,
+}
+
+impl Fall {
+    // `None` when drag is so strong the fall never reaches the ground in finite time
+    pub fn simulate(&self) -> Option<Impact> {
+        let g = self.gravity.acceleration;
+        let (time_s, velocity) = match self.drag {
+            Some(drag) if self.gravity.air_density > 0.0 && self.mass_kg > 0.0 => {
+                // Deceleration per unit of squared velocity
+                let k = 0.5 * self.gravity.air_density * drag.coefficient * drag.cross_section_m2
+                    / self.mass_kg;
+                fall_with_drag(self.height_m, g, k)
+            }
+            _ => (
+                f64::sqrt(2_f64 * self.height_m / g),
+                f64::sqrt(2_f64 * g * self.height_m),
+            ),
+        };
+        if !(time_s.is_finite() && velocity.is_finite()) {
+            return None;
+        }
+
+        Some(Impact {
+            time_s,
+            velocity,
+            momentum: self.mass_kg * velocity,
+            kinetic_energy: 0.5 * self.mass_kg * velocity * velocity,
+        })
+    }
+}
+
+// Closed form of dv/dt = g - k·v² from rest, with terminal velocity v_t = √(g/k):
+// v² = v_t²·(1 - e^(-2kh)) and t = (v_t/g)·acosh(e^(kh)), the latter rewritten so that
+// large kh does not overflow. Quadratic drag from rest has this exact solution, so it
+// stands in for numerical integration: there is no step size to pick, and long falls
+// do not pile up step error or run for millions of steps. The tests check it against
+// a fine-step integration.
+fn fall_with_drag(height_m: f64, g: f64, k: f64) -> (f64, f64) {
+    let terminal = f64::sqrt(g / k);
+    let y = k * height_m;
+    let settled = -f64::exp_m1(-2_f64 * y);
+    let time_s = terminal / g * (y + f64::ln_1p(f64::sqrt(settled)));
+    (time_s, terminal * f64::sqrt(settled))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fall(drag: Option<Drag>) -> Fall {
+        Fall {
+            mass_kg: 6.0,
+            height_m: DEFAULT_HEIGHT_M,
+            gravity: Gravity::from_preset("earth").unwrap(),
+            drag,
+        }
+    }
+
+    #[test]
+    fn test_vacuum_drop_matches_closed_form() {
+        let impact = fall(None).simulate().unwrap();
+        assert!((impact.velocity - f64::sqrt(2.0 * 9.80665 * 10.0)).abs() < 1e-12);
+        assert!((impact.time_s - f64::sqrt(2.0 * 10.0 / 9.80665)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_drag_approaches_terminal_velocity() {
+        let drag = Drag::for_body_height(0.4, DEFAULT_DRAG_COEFFICIENT);
+        let slow = fall(Some(drag)).simulate().unwrap();
+        assert!(slow.velocity < fall(None).simulate().unwrap().velocity);
+        assert!(slow.time_s > fall(None).simulate().unwrap().time_s);
+
+        let mut long = fall(Some(drag));
+        long.height_m = 10_000.0;
+        let k = 0.5 * 1.225 * drag.coefficient * drag.cross_section_m2 / 6.0;
+        let terminal = f64::sqrt(9.80665 / k);
+        let impact = long.simulate().unwrap();
+        assert!((impact.velocity - terminal).abs() < 1e-6);
+        // Past the first seconds a long fall runs at terminal velocity, a fixed ln 2 · v_t/g behind
+        let cruising = 10_000.0 / terminal + terminal / 9.80665 * std::f64::consts::LN_2;
+        assert!((impact.time_s - cruising).abs() < 1e-6);
+
+        // A terminal velocity of a few centimetres a second still lands, days later
+        let mut feather = fall(Some(Drag::for_body_height(0.4, 1e6)));
+        feather.height_m = 10_000.0;
+        assert!(feather.simulate().unwrap().time_s > 1e5);
+        let mut stuck = fall(Some(Drag::for_body_height(0.4, f64::MAX)));
+        stuck.height_m = 10_000.0;
+        assert!(stuck.simulate().is_none());
+    }
+
+    // Classic RK4 on (distance fallen, velocity), interpolating the step that lands
+    fn integrate(height_m: f64, g: f64, k: f64) -> (f64, f64) {
+        let dt = 1e-4;
+        let accel = |v: f64| g - k * v * v;
+        let (mut t, mut x, mut v) = (0.0, 0.0, 0.0);
+        loop {
+            let (k1x, k1v) = (v, accel(v));
+            let (k2x, k2v) = (v + dt / 2.0 * k1v, accel(v + dt / 2.0 * k1v));
+            let (k3x, k3v) = (v + dt / 2.0 * k2v, accel(v + dt / 2.0 * k2v));
+            let (k4x, k4v) = (v + dt * k3v, accel(v + dt * k3v));
+            let next_x = x + dt / 6.0 * (k1x + 2.0 * k2x + 2.0 * k3x + k4x);
+            let next_v = v + dt / 6.0 * (k1v + 2.0 * k2v + 2.0 * k3v + k4v);
+            if next_x >= height_m {
+                let fraction = (height_m - x) / (next_x - x);
+                return (t + fraction * dt, v + fraction * (next_v - v));
+            }
+            (t, x, v) = (t + dt, next_x, next_v);
+        }
+    }
+
+    #[test]
+    fn test_drag_matches_integration() {
+        for (height_m, body_height_m) in [(10.0, 0.4), (50.0, 1.5), (300.0, 0.1)] {
+            let mut drop = fall(Some(Drag::for_body_height(
+                body_height_m,
+                DEFAULT_DRAG_COEFFICIENT,
+            )));
+            drop.height_m = height_m;
+            let drag = drop.drag.unwrap();
+            let k = 0.5 * 1.225 * drag.coefficient * drag.cross_section_m2 / drop.mass_kg;
+            let (time_s, velocity) = integrate(height_m, 9.80665, k);
+            let impact = drop.simulate().unwrap();
+            assert!((impact.time_s - time_s).abs() < 1e-6 * time_s);
+            assert!((impact.velocity - velocity).abs() < 1e-6 * velocity);
+        }
+    }
+
+    #[test]
+    fn test_gravity_parsing() {
+        assert_eq!(Gravity::parse("Moon").unwrap().acceleration, 1.62);
+        assert_eq!(Gravity::parse("3.5").unwrap().acceleration, 3.5);
+        assert!(Gravity::parse("-1").is_none());
+        assert!(Gravity::parse("pluto").is_none());
+    }
+}
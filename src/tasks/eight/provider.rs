@@ -0,0 +1,179 @@
+use crate::tasks::eight::cache::{PokeApiCache, PokeApiCacheConfig};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PokemonRecord {
+    pub id: i64,
+    pub name: String,
+    // Hectograms, as reported by PokéAPI
+    pub weight: i64,
+    // Decimetres, as reported by PokéAPI
+    pub height: i64,
+}
+
+impl From<rustemon::model::pokemon::Pokemon> for PokemonRecord {
+    fn from(pokemon: rustemon::model::pokemon::Pokemon) -> Self {
+        PokemonRecord {
+            id: pokemon.id,
+            name: pokemon.name,
+            weight: pokemon.weight,
+            height: pokemon.height,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PokemonRef {
+    Id(i64),
+    Name(String),
+}
+
+impl PokemonRef {
+    // Numeric input is treated as an ID, anything else as a PokéAPI name such as "mr-mime"
+    pub fn parse(value: &str) -> Result<PokemonRef, String> {
+        let value = value.trim();
+        if let Ok(id) = value.parse::<i64>() {
+            return Ok(PokemonRef::Id(id));
+        }
+
+        let name = value.to_lowercase();
+        if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            Ok(PokemonRef::Name(name))
+        } else {
+            Err(format!("Invalid Pokémon name or ID: {}", value))
+        }
+    }
+}
+
+impl fmt::Display for PokemonRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PokemonRef::Id(id) => write!(f, "{}", id),
+            PokemonRef::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+#[async_trait]
+pub trait PokemonProvider: Send + Sync {
+    async fn pokemon(&self, pokemon: &PokemonRef) -> Result<PokemonRecord, String>;
+}
+
+#[derive(Clone, Debug)]
+pub enum PokedexSource {
+    Live(PokeApiCacheConfig),
+    Fixture(PathBuf),
+}
+
+impl Default for PokedexSource {
+    fn default() -> Self {
+        PokedexSource::Live(PokeApiCacheConfig::default())
+    }
+}
+
+impl PokedexSource {
+    pub fn from_env() -> Self {
+        match std::env::var("POKEDEX_SOURCE").as_deref() {
+            Ok("fixture") => PokedexSource::Fixture(
+                std::env::var("POKEDEX_FIXTURE")
+                    .unwrap_or_else(|_| "static/pokedex.json".to_string())
+                    .into(),
+            ),
+            _ => PokedexSource::Live(PokeApiCacheConfig::from_env()),
+        }
+    }
+
+    pub fn build(self) -> Result<Box<dyn PokemonProvider>, String> {
+        match self {
+            PokedexSource::Live(config) => Ok(Box::new(PokeApiCache::new(config))),
+            PokedexSource::Fixture(path) => Ok(Box::new(FixturePokedex::load(&path)?)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct FixtureFile {
+    pokemon: Vec<PokemonRecord>,
+}
+
+pub struct FixturePokedex {
+    by_id: HashMap<i64, PokemonRecord>,
+    ids_by_name: HashMap<String, i64>,
+}
+
+impl FixturePokedex {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let file: FixtureFile = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&contents).map_err(|e| e.to_string())?,
+            _ => serde_json::from_str(&contents).map_err(|e| e.to_string())?,
+        };
+
+        Ok(FixturePokedex {
+            ids_by_name: file
+                .pokemon
+                .iter()
+                .map(|record| (record.name.to_lowercase(), record.id))
+                .collect(),
+            by_id: file
+                .pokemon
+                .into_iter()
+                .map(|record| (record.id, record))
+                .collect(),
+        })
+    }
+}
+
+#[async_trait]
+impl PokemonProvider for FixturePokedex {
+    async fn pokemon(&self, pokemon: &PokemonRef) -> Result<PokemonRecord, String> {
+        let id = match pokemon {
+            PokemonRef::Id(id) => Some(*id),
+            PokemonRef::Name(name) => self.ids_by_name.get(name).copied(),
+        };
+        id.and_then(|id| self.by_id.get(&id))
+            .cloned()
+            .ok_or_else(|| format!("No Pokémon {} in the Pokédex", pokemon))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_fixture_pokedex_formats_agree() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml_path = dir.path().join("pokedex.toml");
+        std::fs::write(
+            &toml_path,
+            "[[pokemon]]\nid = 25\nname = \"pikachu\"\nweight = 60\nheight = 4\n",
+        )
+        .unwrap();
+
+        let from_json = FixturePokedex::load(Path::new("static/pokedex.json")).unwrap();
+        let from_toml = FixturePokedex::load(&toml_path).unwrap();
+        let pikachu = PokemonRef::parse("Pikachu").unwrap();
+        assert_eq!(
+            from_json.pokemon(&pikachu).await.unwrap(),
+            from_toml.pokemon(&PokemonRef::Id(25)).await.unwrap()
+        );
+        assert!(from_toml.pokemon(&PokemonRef::Id(26)).await.is_err());
+    }
+
+    #[test]
+    fn test_pokemon_ref_parsing() {
+        assert_eq!(PokemonRef::parse(" 25 "), Ok(PokemonRef::Id(25)));
+        assert_eq!(
+            PokemonRef::parse("Mr-Mime"),
+            Ok(PokemonRef::Name("mr-mime".to_string()))
+        );
+        assert!(PokemonRef::parse("../secrets").is_err());
+        assert!(PokemonRef::parse("").is_err());
+    }
+}
@@ -1,81 +1,235 @@
+pub(crate) mod graphql;
+pub(crate) mod ranking;
+
+use crate::tasks::eighteen::ranking::{RankedGift, Scope, TopListQuery};
+use crate::tasks::thirteen::bulk::{self, BulkQuery, BulkRow};
+use crate::tasks::thirteen::copy;
+use crate::tasks::thirteen::feed::{self, Change};
+use crate::tasks::thirteen::tenants::{NewTenant, Tenant};
+use crate::tasks::thirteen::totals;
 use crate::AppState;
-use actix_web::{error, get, post, web, Error, HttpResponse, Responder};
+use actix_web::{error, get, post, web, Error, HttpRequest, HttpResponse, Responder};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use sqlx::{Executor, Row};
+use sqlx::postgres::PgArguments;
+use sqlx::query::Query;
+use sqlx::{Executor, Postgres, Row};
+
+// What happens to an order whose region does not exist
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OrphanPolicy {
+    #[default]
+    Reject,
+    // A region named after its id is created for the order
+    Placeholder,
+    // A new order is set aside in `orders_quarantine` and reported as skipped, while moving an
+    // existing order to an unknown region is refused like `Reject`
+    Quarantine,
+}
+
+impl OrphanPolicy {
+    pub fn from_env() -> Self {
+        match std::env::var("ORPHAN_ORDERS").as_deref() {
+            Ok("placeholder") => OrphanPolicy::Placeholder,
+            Ok("quarantine") => OrphanPolicy::Quarantine,
+            _ => OrphanPolicy::Reject,
+        }
+    }
+
+    // The foreign key always holds; the other policies step in before it is checked
+    fn trigger(&self) -> &'static str {
+        match self {
+            OrphanPolicy::Reject => "",
+            OrphanPolicy::Placeholder => {
+                "
+                CREATE OR REPLACE FUNCTION orders_placeholder_region() RETURNS trigger AS $$
+                BEGIN
+                  INSERT INTO regions (id, name)
+                  VALUES (NEW.region_id, 'Region ' || NEW.region_id)
+                  ON CONFLICT (id) DO NOTHING;
+                  RETURN NEW;
+                END
+                $$ LANGUAGE plpgsql;
+
+                CREATE TRIGGER orders_orphans
+                BEFORE INSERT OR UPDATE OF region_id ON orders
+                FOR EACH ROW WHEN (NEW.region_id IS NOT NULL)
+                EXECUTE FUNCTION orders_placeholder_region();"
+            }
+            OrphanPolicy::Quarantine => {
+                "
+                CREATE OR REPLACE FUNCTION orders_quarantine_orphan() RETURNS trigger AS $$
+                BEGIN
+                  IF NOT EXISTS (SELECT 1 FROM regions WHERE id = NEW.region_id) THEN
+                    INSERT INTO orders_quarantine (id, region_id, gift_name, quantity, created_at)
+                    VALUES (NEW.id, NEW.region_id, NEW.gift_name, NEW.quantity, NEW.created_at);
+                    RETURN NULL;
+                  END IF;
+                  RETURN NEW;
+                END
+                $$ LANGUAGE plpgsql;
+
+                CREATE TRIGGER orders_orphans
+                BEFORE INSERT ON orders
+                FOR EACH ROW WHEN (NEW.region_id IS NOT NULL)
+                EXECUTE FUNCTION orders_quarantine_orphan();"
+            }
+        }
+    }
+}
 
 #[post("/18/reset")]
-async fn reset_advanced(state: web::Data<AppState>) -> impl Responder {
-    let res = state
-        .pool
-        .execute(
-            "
-            DROP TABLE IF EXISTS regions;
-            DROP TABLE IF EXISTS orders;
-
-            CREATE TABLE regions (
-              id INT PRIMARY KEY,
-              name VARCHAR(50)
-            );
-
-            CREATE TABLE orders (
-              id INT PRIMARY KEY,
-              region_id INT,
-              gift_name VARCHAR(50),
-              quantity INT
-            );",
-        )
-        .await;
+async fn reset_advanced(
+    state: web::Data<AppState>,
+    NewTenant(tenant): NewTenant,
+) -> impl Responder {
+    let schema = format!(
+        "
+        DROP TABLE IF EXISTS orders;
+        DROP TABLE IF EXISTS orders_quarantine;
+        DROP TABLE IF EXISTS order_totals;
+        DROP TABLE IF EXISTS regions;
+        DROP TABLE IF EXISTS idempotency_keys;
+
+        CREATE TABLE regions (
+          id INT PRIMARY KEY,
+          name VARCHAR(50)
+        );
 
-    if let Ok(_) = res {
+        CREATE TABLE orders (
+          id INT PRIMARY KEY,
+          region_id INT REFERENCES regions (id) ON DELETE RESTRICT,
+          gift_name VARCHAR(50),
+          quantity INT,
+          created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        );
+
+        CREATE TABLE orders_quarantine (
+          id INT,
+          region_id INT,
+          gift_name VARCHAR(50),
+          quantity INT,
+          created_at TIMESTAMPTZ,
+          quarantined_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        );
+        {}
+        {}",
+        totals::SCHEMA,
+        state.orphan_policy.trigger()
+    );
+    let res = tenant.pool.execute(schema.as_str()).await;
+
+    if res.is_ok() {
         HttpResponse::Ok().body("Orders reset!")
     } else {
         HttpResponse::InternalServerError().body("Failed to reset orders!")
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 struct Region {
     id: i64,
     name: String,
 }
 
+impl BulkRow for Region {
+    const TABLE: &'static str = "regions";
+    const COLUMNS: &'static [&'static str] = &["id", "name"];
+
+    fn id(&self) -> i64 {
+        self.id
+    }
+
+    fn bind<'q>(
+        &'q self,
+        query: Query<'q, Postgres, PgArguments>,
+    ) -> Query<'q, Postgres, PgArguments> {
+        query.bind(self.id).bind(&self.name)
+    }
+    fn validate(&self) -> Result<(), String> {
+        bulk::int_column("id", self.id)
+    }
+}
+
 #[post("/18/regions")]
 async fn add_regions(
-    state: web::Data<AppState>,
+    req: HttpRequest,
+    tenant: Tenant,
+    query: web::Query<BulkQuery>,
     regions: web::Json<Vec<Region>>,
 ) -> Result<HttpResponse, Error> {
-    let mut transaction = state
-        .pool
-        .begin()
-        .await
-        .map_err(|e| error::ErrorInternalServerError(e))?;
+    let (response, written) =
+        bulk::bulk_insert(&tenant.pool, &req, query.on_conflict, regions.into_inner()).await?;
+    // Region names show up in the totals
+    if !written.is_empty() {
+        feed::publish(&tenant, Change::Imported);
+    }
+    Ok(response)
+}
 
-    for region in regions.into_inner() {
-        sqlx::query("INSERT INTO regions (id, name) VALUES ($1, $2)")
-            .bind(region.id)
-            .bind(region.name)
-            .execute(&mut *transaction)
-            .await
-            .map_err(|e| error::ErrorInternalServerError(e))?;
+#[post("/18/regions/import")]
+async fn import_regions(
+    tenant: Tenant,
+    query: web::Query<BulkQuery>,
+    payload: web::Payload,
+) -> Result<HttpResponse, Error> {
+    let response = copy::import_csv::<Region>(&tenant.pool, payload, query.on_conflict).await?;
+    if response.status().is_success() {
+        feed::publish(&tenant, Change::Imported);
     }
+    Ok(response)
+}
 
-    transaction
-        .commit()
-        .await
-        .map_err(|e| error::ErrorInternalServerError(e))?;
+#[get("/18/regions/export")]
+async fn export_regions(tenant: Tenant) -> Result<HttpResponse, Error> {
+    copy::export_csv::<Region>(&tenant.pool).await
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct Orphan {
+    id: i64,
+    region_id: i64,
+    gift_name: String,
+    quantity: i64,
+    quarantined: bool,
+}
+
+// Orders left without a region, either from before the foreign key or set aside by quarantine
+#[get("/18/orphans")]
+async fn orphans(tenant: Tenant) -> Result<HttpResponse, Error> {
+    let orphans = sqlx::query_as::<_, Orphan>(
+        "SELECT o.id::BIGINT, o.region_id::BIGINT, o.gift_name, o.quantity::BIGINT, FALSE AS quarantined
+        FROM orders o
+        LEFT JOIN regions r ON r.id = o.region_id
+        WHERE o.region_id IS NOT NULL AND r.id IS NULL
+        UNION ALL
+        SELECT id::BIGINT, region_id::BIGINT, gift_name, quantity::BIGINT, TRUE
+        FROM orders_quarantine q
+        WHERE NOT EXISTS (SELECT 1 FROM regions r WHERE r.id = q.region_id)
+        ORDER BY id",
+    )
+    .fetch_all(&tenant.pool)
+    .await
+    .map_err(error::ErrorInternalServerError)?;
 
-    Ok(HttpResponse::Ok().body("Successfully added regions!"))
+    let mut missing_regions: Vec<i64> = orphans.iter().map(|orphan| orphan.region_id).collect();
+    missing_regions.sort_unstable();
+    missing_regions.dedup();
+
+    Ok(HttpResponse::Ok().json(json!({
+        "orphans": orphans,
+        "missing_regions": missing_regions,
+    })))
 }
 
 #[get("/18/regions/total")]
-async fn total_regions(state: web::Data<AppState>) -> impl Responder {
+async fn total_regions(tenant: Tenant) -> impl Responder {
     if let Ok(rows) = sqlx::query(
-        "SELECT name, SUM(quantity) FROM regions
-            INNER JOIN orders ON regions.id = orders.region_id
+        "SELECT name, SUM(quantity)::BIGINT FROM regions
+            INNER JOIN order_totals ON regions.id = order_totals.region_id
             GROUP BY name;",
     )
-    .fetch_all(&state.pool)
+    .fetch_all(&tenant.pool)
     .await
     {
         let mut result: Vec<Value> = rows
@@ -90,7 +244,7 @@ async fn total_regions(state: web::Data<AppState>) -> impl Responder {
             a["region"]
                 .as_str()
                 .unwrap_or("")
-                .cmp(&b["region"].as_str().unwrap_or(""))
+                .cmp(b["region"].as_str().unwrap_or(""))
         });
 
         HttpResponse::Ok().json(result)
@@ -100,61 +254,79 @@ async fn total_regions(state: web::Data<AppState>) -> impl Responder {
 }
 
 #[get("/18/regions/top_list/{number}")]
-async fn top_list(state: web::Data<AppState>, number: web::Path<i32>) -> impl Responder {
-    match sqlx::query_as::<_, (String, Option<Vec<String>>)>("
-        WITH ranked_gifts AS (
-            SELECT
-                regions.name AS name,
-                orders.gift_name,
-                SUM(orders.quantity) AS total_quantity,
-                ROW_NUMBER() OVER(PARTITION BY regions.id ORDER BY SUM(orders.quantity) DESC, orders.gift_name) AS rank
-            FROM orders
-            INNER JOIN regions ON regions.id = orders.region_id
-            GROUP BY regions.id, orders.gift_name
-        )
-        SELECT
-            regions.name,
-            ARRAY_AGG(gift_name ORDER BY total_quantity DESC, gift_name) FILTER (WHERE rank <= $1 AND gift_name IS NOT NULL) AS top_gifts
-        FROM regions
-        LEFT JOIN ranked_gifts ON regions.name = ranked_gifts.name
-        GROUP BY regions.name
-        ORDER BY regions.name")
-        .bind(number.into_inner())
-        .fetch_all(&state.pool)
+async fn top_list(
+    tenant: Tenant,
+    number: web::Path<i32>,
+    query: web::Query<TopListQuery>,
+) -> Result<HttpResponse, Error> {
+    let rows = query
+        .build(*number)
+        .build_query_as::<RankedGift>()
+        .fetch_all(&tenant.pool)
         .await
-    {
-        Ok(rows) => {
-            let result: Vec<Value> = rows
-                .into_iter()
-                .map(|(region_name, top_gifts)| {
-                    json!({"region": region_name, "top_gifts": top_gifts.unwrap_or_else(Vec::new)})
-                })
-                .collect();
-
-            HttpResponse::Ok().json(result)
+        .map_err(error::ErrorInternalServerError)?;
+
+    // Rows come sorted by region, with a single gift-less row for regions with nothing listed
+    let mut result: Vec<Value> = Vec::new();
+    let mut current = None;
+    for gift in rows {
+        if result.is_empty() || gift.region_id != current {
+            current = gift.region_id;
+            result.push(json!({"region": gift.region, "top_gifts": []}));
         }
-        Err(err) => {
-            println!("err: {}", err);
-            HttpResponse::InternalServerError().body("Failed to fetch top list")
-        },
+        let Some(gift_name) = gift.gift_name else {
+            continue;
+        };
+        let entry = if query.details {
+            json!({
+                "gift_name": gift_name,
+                "quantity": gift.quantity,
+                "share": gift.share,
+                "rank": gift.rank,
+            })
+        } else {
+            json!(gift_name)
+        };
+        if let Some(Value::Array(top_gifts)) =
+            result.last_mut().map(|region| &mut region["top_gifts"])
+        {
+            top_gifts.push(entry);
+        }
+    }
+    if result.is_empty() && query.scope == Scope::Global {
+        result.push(json!({"region": null, "top_gifts": []}));
     }
+
+    Ok(HttpResponse::Ok().json(result))
 }
 
 #[cfg(test)]
 mod test {
+    use crate::config::AppConfig;
     use crate::tasks;
+    use actix_web::body::{BoxBody, MessageBody};
+    use actix_web::dev::Payload;
+    use actix_web::error::PayloadError;
+    use actix_web::http::{header, StatusCode};
     use actix_web::web::Bytes;
     use actix_web::{test, App};
+    use futures::future::poll_fn;
+    use futures::Stream;
     use serde_json::json;
     use serial_test::serial;
     use sqlx::postgres::PgPoolOptions;
-    use std::sync::{Arc, Mutex};
+    use std::pin::Pin;
+    use std::time::Duration;
     use tokio::fs;
     use toml::Table;
 
     use super::*;
 
     async fn set_up_sql() -> web::Data<AppState> {
+        set_up_sql_with(AppConfig::default()).await
+    }
+
+    async fn set_up_sql_with(config: AppConfig) -> web::Data<AppState> {
         let secrets_contents = fs::read_to_string("Secrets.dev.toml").await.unwrap();
         let secrets = secrets_contents.parse::<Table>().unwrap();
         let password = secrets
@@ -170,11 +342,7 @@ mod test {
             password, port
         );
         let pool = PgPoolOptions::new().connect(&database_url).await.unwrap();
-        web::Data::new(AppState {
-            pool,
-            rooms: Default::default(),
-            view_count: Arc::new(Mutex::new(0)),
-        })
+        web::Data::new(AppState::new(pool, config))
     }
 
     #[actix_web::test]
@@ -186,6 +354,7 @@ mod test {
                 .app_data(state.clone())
                 .service(reset_advanced)
                 .service(add_regions)
+                .service(import_regions)
                 .route("/18/orders", web::post().to(tasks::thirteen::add_orders))
                 .service(total_regions),
         )
@@ -248,6 +417,26 @@ mod test {
         ])
         .to_string();
         assert_eq!(body, Bytes::from(expected_response));
+
+        // Regions with orders are replaced in place, from JSON as well as CSV
+        let req = test::TestRequest::post()
+            .uri("/18/regions?on_conflict=replace")
+            .set_json(json!([{"id":5,"name":"Afrika"}]))
+            .to_request();
+        let summary: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(summary["updated"], json!([5]));
+        let req = test::TestRequest::post()
+            .uri("/18/regions/import?on_conflict=replace")
+            .set_payload("id,name\n6,Azië\n")
+            .to_request();
+        let report: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(report["updated"], 1);
+        let req = test::TestRequest::get()
+            .uri("/18/regions/total")
+            .to_request();
+        let totals: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(totals[0], json!({"region":"Afrika","total":5}));
+        assert_eq!(totals[1], json!({"region":"Azië","total":9}));
     }
 
     #[actix_web::test]
@@ -319,4 +508,446 @@ mod test {
         .to_string();
         assert_eq!(body, Bytes::from(expected_response));
     }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_top_list_options() {
+        let state = set_up_sql().await;
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(reset_advanced)
+                .service(add_regions)
+                .route("/18/orders", web::post().to(tasks::thirteen::add_orders))
+                .service(top_list),
+        )
+        .await;
+
+        let req = test::TestRequest::post().uri("/18/reset").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let req = test::TestRequest::post()
+            .uri("/18/regions")
+            .set_json(json!([
+                {"id":1,"name":"North Pole"},
+                {"id":2,"name":"South Pole"},
+                {"id":3,"name":"Kiribati"},
+                {"id":4,"name":"Baker Island"}
+            ]))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let req = test::TestRequest::post()
+            .uri("/18/orders")
+            .set_json(json!([
+                {"id":1,"region_id":2,"gift_name":"Toy Train","quantity":5},
+                {"id":2,"region_id":2,"gift_name":"Toy Train","quantity":3},
+                {"id":3,"region_id":2,"gift_name":"Doll","quantity":8},
+                {"id":4,"region_id":3,"gift_name":"Toy Train","quantity":3},
+                {"id":5,"region_id":2,"gift_name":"Teddy Bear","quantity":6},
+                {"id":6,"region_id":3,"gift_name":"Action Figure","quantity":12},
+                {"id":7,"region_id":4,"gift_name":"Board Game","quantity":10},
+                {"id":8,"region_id":3,"gift_name":"Teddy Bear","quantity":1},
+                {"id":9,"region_id":3,"gift_name":"Teddy Bear","quantity":2}
+            ]))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        let cases = [
+            (
+                "/18/regions/top_list/1?details=true&region_id=2",
+                json!([{"region":"South Pole","top_gifts":[
+                    {"gift_name":"Doll","quantity":8,"share":36.36,"rank":1}
+                ]}]),
+            ),
+            (
+                "/18/regions/top_list/1?ties=rank&region_id=2",
+                json!([{"region":"South Pole","top_gifts":["Doll","Toy Train"]}]),
+            ),
+            (
+                "/18/regions/top_list/2?ties=dense_rank&region_id=2",
+                json!([{"region":"South Pole","top_gifts":["Doll","Toy Train","Teddy Bear"]}]),
+            ),
+            (
+                "/18/regions/top_list/3?gift=Teddy%20Bear&details=true&region_id=3",
+                json!([{"region":"Kiribati","top_gifts":[
+                    {"gift_name":"Teddy Bear","quantity":3,"share":16.67,"rank":2}
+                ]}]),
+            ),
+            (
+                "/18/regions/top_list/5?min_quantity=5",
+                json!([
+                    {"region":"Baker Island","top_gifts":["Board Game"]},
+                    {"region":"Kiribati","top_gifts":["Action Figure"]},
+                    {"region":"North Pole","top_gifts":[]},
+                    {"region":"South Pole","top_gifts":["Doll","Toy Train","Teddy Bear"]}
+                ]),
+            ),
+            (
+                "/18/regions/top_list/2?scope=global&details=true",
+                json!([{"region":null,"top_gifts":[
+                    {"gift_name":"Action Figure","quantity":12,"share":24.0,"rank":1},
+                    {"gift_name":"Toy Train","quantity":11,"share":22.0,"rank":2}
+                ]}]),
+            ),
+        ];
+        for (uri, expected) in cases {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let body: Value = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(body, expected, "{}", uri);
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/18/regions/top_list/2?ties=random")
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            actix_web::http::StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_orphan_policies() {
+        for policy in [
+            OrphanPolicy::Reject,
+            OrphanPolicy::Placeholder,
+            OrphanPolicy::Quarantine,
+        ] {
+            let state = set_up_sql_with(AppConfig {
+                orphans: policy,
+                ..Default::default()
+            })
+            .await;
+            let app = test::init_service(
+                App::new()
+                    .app_data(state)
+                    .service(reset_advanced)
+                    .service(add_regions)
+                    .route("/18/orders", web::post().to(tasks::thirteen::add_orders))
+                    .service(tasks::thirteen::import_orders)
+                    .service(tasks::thirteen::update_order)
+                    .service(total_regions)
+                    .service(orphans),
+            )
+            .await;
+
+            let req = test::TestRequest::post().uri("/18/reset").to_request();
+            assert!(test::call_service(&app, req).await.status().is_success());
+            let req = test::TestRequest::post()
+                .uri("/18/regions")
+                .set_json(json!([{"id":1,"name":"North Pole"}]))
+                .to_request();
+            assert!(test::call_service(&app, req).await.status().is_success());
+
+            let req = test::TestRequest::post()
+                .uri("/18/orders")
+                .set_json(json!([
+                    {"id":1,"region_id":1,"gift_name":"Sled","quantity":2},
+                    {"id":2,"region_id":9,"gift_name":"Drone","quantity":5}
+                ]))
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            let orphaned: Value = {
+                let req = test::TestRequest::get().uri("/18/orphans").to_request();
+                test::call_and_read_body_json(&app, req).await
+            };
+
+            match policy {
+                OrphanPolicy::Reject => {
+                    assert_eq!(res.status(), actix_web::http::StatusCode::BAD_REQUEST);
+                    let summary: Value = test::read_body_json(res).await;
+                    assert_eq!(summary["errors"][0]["id"], 2);
+                    assert_eq!(orphaned["orphans"], json!([]));
+                }
+                OrphanPolicy::Placeholder => {
+                    assert!(res.status().is_success());
+                    let req = test::TestRequest::get()
+                        .uri("/18/regions/total")
+                        .to_request();
+                    let totals: Value = test::call_and_read_body_json(&app, req).await;
+                    assert_eq!(
+                        totals,
+                        json!([
+                            {"region":"North Pole","total":2},
+                            {"region":"Region 9","total":5}
+                        ])
+                    );
+                    assert_eq!(orphaned["orphans"], json!([]));
+                }
+                OrphanPolicy::Quarantine => {
+                    let summary: Value = test::read_body_json(res).await;
+                    assert_eq!(summary["inserted"], json!([1]));
+                    assert_eq!(summary["skipped"], json!([2]));
+                    assert_eq!(orphaned["missing_regions"], json!([9]));
+                    assert_eq!(orphaned["orphans"][0]["quarantined"], true);
+                }
+            }
+
+            let req = test::TestRequest::post()
+                .uri("/13/orders/import")
+                .set_payload("id,region_id,gift_name,quantity\n10,1,Kite,1\n11,8,Kite,1\n")
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            if policy == OrphanPolicy::Reject {
+                assert_eq!(res.status(), actix_web::http::StatusCode::BAD_REQUEST);
+            } else {
+                let report: Value = test::read_body_json(res).await;
+                let skipped = u64::from(policy == OrphanPolicy::Quarantine);
+                assert_eq!(report["inserted"], 2 - skipped);
+                assert_eq!(report["skipped"], skipped);
+            }
+
+            let req = test::TestRequest::post()
+                .uri("/18/orders")
+                .set_json(json!([{"id":3,"region_id":1,"gift_name":"Kite","quantity":1}]))
+                .to_request();
+            assert!(test::call_service(&app, req).await.status().is_success());
+            let req = test::TestRequest::put()
+                .uri("/orders/3")
+                .set_json(json!({"region_id":42,"gift_name":"Kite","quantity":1}))
+                .to_request();
+            let status = test::call_service(&app, req).await.status();
+            if policy == OrphanPolicy::Placeholder {
+                assert!(status.is_success());
+            } else {
+                assert_eq!(status, actix_web::http::StatusCode::UNPROCESSABLE_ENTITY);
+            }
+        }
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_graphql() {
+        let state = set_up_sql_with(AppConfig {
+            graphql: graphql::GraphqlLimits {
+                max_depth: 4,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .await;
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(reset_advanced)
+                .service(top_list)
+                .service(graphql::graphql),
+        )
+        .await;
+
+        let req = test::TestRequest::post().uri("/18/reset").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        let query = |query: &str| {
+            test::TestRequest::post()
+                .uri("/graphql")
+                .set_json(json!({"query": query}))
+                .to_request()
+        };
+
+        let req = query(
+            r#"mutation {
+                addRegions(regions: [{id: 1, name: "Europe"}, {id: 2, name: "Asia"}]) { committed }
+                addOrders(orders: [
+                    {id: 1, regionId: 1, giftName: "Doll", quantity: 2},
+                    {id: 2, regionId: 1, giftName: "Kite", quantity: 5},
+                    {id: 3, regionId: 1, giftName: "Doll", quantity: 4},
+                    {id: 4, regionId: 2, giftName: "Drone", quantity: 1}
+                ]) { committed inserted }
+            }"#,
+        );
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["data"]["addRegions"]["committed"], true);
+        assert_eq!(body["data"]["addOrders"]["inserted"], json!([1, 2, 3, 4]));
+
+        // A conflicting batch is reported and nothing from it is kept
+        let req = query(
+            r#"mutation {
+                addOrders(orders: [
+                    {id: 5, regionId: 2, giftName: "Sled", quantity: 1},
+                    {id: 1, regionId: 2, giftName: "Sled", quantity: 1}
+                ]) { committed errors { id } }
+            }"#,
+        );
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            body["data"]["addOrders"],
+            json!({"committed": false, "errors": [{"id": 1}]})
+        );
+
+        let req = query(
+            r#"{
+                regions {
+                    name
+                    total
+                    topGifts(limit: 1) { giftName totalQuantity rank orders { id } }
+                }
+                totalOrders
+                popularGift
+            }"#,
+        );
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            body["data"],
+            json!({
+                "regions": [
+                    {"name": "Asia", "total": 1, "topGifts": [
+                        {"giftName": "Drone", "totalQuantity": 1, "rank": 1, "orders": [{"id": 4}]}
+                    ]},
+                    {"name": "Europe", "total": 11, "topGifts": [
+                        {"giftName": "Doll", "totalQuantity": 6, "rank": 1, "orders": [{"id": 1}, {"id": 3}]}
+                    ]}
+                ],
+                "totalOrders": 12,
+                "popularGift": "Doll"
+            })
+        );
+
+        // The ranking agrees with the REST one
+        let req = test::TestRequest::get()
+            .uri("/18/regions/top_list/1")
+            .to_request();
+        let ranked: Value = test::call_and_read_body_json(&app, req).await;
+        let regions = body["data"]["regions"].as_array().unwrap();
+        for (region, listed) in regions.iter().zip(ranked.as_array().unwrap()) {
+            assert_eq!(region["name"], listed["region"]);
+            assert_eq!(region["topGifts"][0]["giftName"], listed["top_gifts"][0]);
+        }
+
+        // Regions are loaded for the whole page of orders at once
+        let req = query("{ orders { id region { name } } }");
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            body["data"]["orders"],
+            json!([
+                {"id": 1, "region": {"name": "Europe"}},
+                {"id": 2, "region": {"name": "Europe"}},
+                {"id": 3, "region": {"name": "Europe"}},
+                {"id": 4, "region": {"name": "Asia"}}
+            ])
+        );
+
+        let req = query("mutation { deleteOrder(id: 4) deleteRegion(id: 2) }");
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            body["data"],
+            json!({"deleteOrder": true, "deleteRegion": true})
+        );
+
+        // One level past the limit
+        let req = query("{ regions { topGifts { orders { region { name } } } } }");
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert!(body["data"].is_null());
+        assert!(body["errors"][0]["message"]
+            .as_str()
+            .unwrap()
+            .contains("nested too deep"));
+
+        let req =
+            query("{ orders(first: 100) { id region { topGifts(limit: 100) { giftName } } } }");
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert!(body["errors"][0]["message"]
+            .as_str()
+            .unwrap()
+            .contains("too complex"));
+    }
+
+    // Server frames are unmasked, so a text frame is a two or four byte header and its payload
+    async fn next_event(body: &mut BoxBody, buffer: &mut Vec<u8>) -> serde_json::Value {
+        let read = async {
+            loop {
+                let header = match buffer.get(1) {
+                    Some(126) if buffer.len() >= 4 => {
+                        Some((4, u16::from_be_bytes([buffer[2], buffer[3]]) as usize))
+                    }
+                    Some(&len) if len < 126 => Some((2, len as usize)),
+                    _ => None,
+                };
+                if let Some((header, len)) = header.filter(|(h, len)| buffer.len() >= h + len) {
+                    let frame: Vec<u8> = buffer.drain(..header + len).collect();
+                    return serde_json::from_slice(&frame[header..]).unwrap();
+                }
+                let chunk = poll_fn(|cx| Pin::new(&mut *body).poll_next(cx))
+                    .await
+                    .unwrap()
+                    .unwrap();
+                buffer.extend_from_slice(&chunk);
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), read)
+            .await
+            .expect("No event within five seconds")
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_order_feed() {
+        let state = set_up_sql().await;
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(reset_advanced)
+                .service(add_regions)
+                .route("/18/orders", web::post().to(tasks::thirteen::add_orders))
+                .service(tasks::thirteen::delete_order)
+                .service(tasks::thirteen::feed::order_feed),
+        )
+        .await;
+
+        let req = test::TestRequest::post().uri("/18/reset").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let req = test::TestRequest::post()
+            .uri("/18/regions")
+            .set_json(json!([{"id":1,"name":"North Pole"}]))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        let req = test::TestRequest::get()
+            .uri("/18/ws/orders")
+            .insert_header((header::CONNECTION, "upgrade"))
+            .insert_header((header::UPGRADE, "websocket"))
+            .insert_header((header::SEC_WEBSOCKET_VERSION, "13"))
+            .insert_header((header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ=="))
+            .to_request();
+        // Left open, like a connected client, since the socket closes once its input ends
+        let input: Pin<Box<dyn Stream<Item = Result<Bytes, PayloadError>>>> =
+            Box::pin(futures::stream::pending());
+        let (req, _) = req.replace_payload(Payload::from(input));
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::SWITCHING_PROTOCOLS);
+        let mut body = res.into_body();
+        let mut buffer = Vec::new();
+        assert_eq!(
+            next_event(&mut body, &mut buffer).await,
+            json!({"type": "totals", "regions": []})
+        );
+
+        let req = test::TestRequest::post()
+            .uri("/18/orders")
+            .set_json(json!([
+                {"id":1,"region_id":1,"gift_name":"Sled","quantity":3},
+                {"id":2,"region_id":1,"gift_name":"Doll","quantity":5}
+            ]))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        for id in [1, 2] {
+            let event = next_event(&mut body, &mut buffer).await;
+            assert_eq!(event["type"], "order");
+            assert_eq!(event["order"]["id"], id);
+        }
+        assert_eq!(
+            next_event(&mut body, &mut buffer).await,
+            json!({"type": "totals", "regions": [{"region": "North Pole", "total": 8, "top_gift": "Doll"}]})
+        );
+
+        let req = test::TestRequest::delete().uri("/orders/2").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 204);
+        assert_eq!(
+            next_event(&mut body, &mut buffer).await,
+            json!({"type": "order_deleted", "id": 2})
+        );
+        assert_eq!(
+            next_event(&mut body, &mut buffer).await,
+            json!({"type": "totals", "regions": [{"region": "North Pole", "total": 3, "top_gift": "Sled"}]})
+        );
+    }
 }
@@ -0,0 +1,529 @@
+use crate::config::env_or;
+use crate::tasks::eighteen::Region;
+use crate::tasks::thirteen::bulk::{self, BulkSummary, ConflictStrategy, RowError};
+use crate::tasks::thirteen::feed::{self, Change};
+use crate::tasks::thirteen::tenants::Tenant;
+use crate::tasks::thirteen::totals;
+use crate::tasks::thirteen::trends::TimeRange;
+use crate::tasks::thirteen::{Order, ORDER_COLUMNS};
+use crate::AppState;
+use actix_web::{post, web, HttpResponse};
+use async_graphql::dataloader::{DataLoader, Loader};
+use async_graphql::{
+    ComplexObject, Context, EmptySubscription, Enum, InputObject, Object, Result, Schema,
+    SimpleObject,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use sqlx::{PgPool, Postgres, QueryBuilder, Row};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub type OrdersSchema = Schema<QueryRoot, MutationRoot, EmptySubscription>;
+
+// Largest page any list field returns
+const MAX_PAGE: i32 = 100;
+
+#[derive(Clone, Copy, Debug)]
+pub struct GraphqlLimits {
+    pub max_depth: usize,
+    // Every field costs one, and list fields multiply their children by the page size
+    pub max_complexity: usize,
+}
+
+impl Default for GraphqlLimits {
+    fn default() -> Self {
+        GraphqlLimits {
+            max_depth: 8,
+            max_complexity: 5000,
+        }
+    }
+}
+
+impl GraphqlLimits {
+    pub fn from_env() -> Self {
+        let default = GraphqlLimits::default();
+        GraphqlLimits {
+            max_depth: env_or("GRAPHQL_MAX_DEPTH", default.max_depth),
+            max_complexity: env_or("GRAPHQL_MAX_COMPLEXITY", default.max_complexity),
+        }
+    }
+
+    pub fn schema(&self) -> OrdersSchema {
+        Schema::build(QueryRoot, MutationRoot, EmptySubscription)
+            .limit_depth(self.max_depth)
+            .limit_complexity(self.max_complexity)
+            .finish()
+    }
+}
+
+fn pool<'a>(ctx: &Context<'a>) -> Result<&'a PgPool> {
+    Ok(&ctx.data::<Tenant>()?.pool)
+}
+
+fn page(first: i32) -> i64 {
+    first.clamp(0, MAX_PAGE) as i64
+}
+
+fn parse_time(value: Option<String>) -> Result<Option<DateTime<Utc>>> {
+    value
+        .map(|value| {
+            DateTime::parse_from_rfc3339(&value)
+                .map(|time| time.with_timezone(&Utc))
+                .map_err(|e| format!("invalid timestamp {}: {}", value, e).into())
+        })
+        .transpose()
+}
+
+#[derive(InputObject, Default)]
+struct OrderFilter {
+    region_id: Option<i64>,
+    gift_name: Option<String>,
+    min_quantity: Option<i64>,
+    max_quantity: Option<i64>,
+}
+
+async fn fetch_orders(
+    pool: &PgPool,
+    filter: &OrderFilter,
+    first: i32,
+    offset: i32,
+) -> Result<Vec<Order>> {
+    let mut query: QueryBuilder<Postgres> = QueryBuilder::new(ORDER_COLUMNS);
+    query.push(" WHERE TRUE");
+    if let Some(region_id) = filter.region_id {
+        query.push(" AND region_id = ").push_bind(region_id);
+    }
+    if let Some(gift_name) = &filter.gift_name {
+        query.push(" AND gift_name = ").push_bind(gift_name.clone());
+    }
+    if let Some(min_quantity) = filter.min_quantity {
+        query.push(" AND quantity >= ").push_bind(min_quantity);
+    }
+    if let Some(max_quantity) = filter.max_quantity {
+        query.push(" AND quantity <= ").push_bind(max_quantity);
+    }
+    query
+        .push(" ORDER BY id LIMIT ")
+        .push_bind(page(first))
+        .push(" OFFSET ")
+        .push_bind(offset.max(0) as i64);
+    Ok(query.build_query_as().fetch_all(pool).await?)
+}
+
+// Regions by id. Like the totals below, they are batched per request, so a page of
+// orders looks its regions up in one query rather than one per order.
+struct RegionLoader(PgPool);
+
+impl Loader<i64> for RegionLoader {
+    type Value = Region;
+    type Error = Arc<sqlx::Error>;
+
+    async fn load(&self, ids: &[i64]) -> Result<HashMap<i64, Region>, Self::Error> {
+        let rows = sqlx::query("SELECT id::BIGINT, name FROM regions WHERE id = ANY($1)")
+            .bind(ids)
+            .fetch_all(&self.0)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                let region = Region {
+                    id: row.get(0),
+                    name: row.get(1),
+                };
+                (region.id, region)
+            })
+            .collect())
+    }
+}
+
+// Quantity over all of a region's orders, keyed by region id
+struct RegionTotalLoader(PgPool);
+
+impl Loader<i64> for RegionTotalLoader {
+    type Value = i64;
+    type Error = Arc<sqlx::Error>;
+
+    async fn load(&self, ids: &[i64]) -> Result<HashMap<i64, i64>, Self::Error> {
+        let rows = sqlx::query(
+            "SELECT region_id::BIGINT, SUM(quantity)::BIGINT FROM order_totals
+            WHERE region_id = ANY($1)
+            GROUP BY region_id",
+        )
+        .bind(ids)
+        .fetch_all(&self.0)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect())
+    }
+}
+
+#[Object]
+impl Order {
+    async fn id(&self) -> i64 {
+        self.id
+    }
+
+    async fn region_id(&self) -> i64 {
+        self.region_id
+    }
+
+    async fn gift_name(&self) -> &str {
+        &self.gift_name
+    }
+
+    async fn quantity(&self) -> i64 {
+        self.quantity
+    }
+
+    // RFC 3339, in UTC
+    async fn created_at(&self) -> Option<String> {
+        self.created_at
+            .map(|time| time.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+    }
+
+    async fn region(&self, ctx: &Context<'_>) -> Result<Option<Region>> {
+        let regions = ctx.data::<DataLoader<RegionLoader>>()?;
+        Ok(regions.load_one(self.region_id).await?)
+    }
+}
+
+#[Object]
+impl Region {
+    async fn id(&self) -> i64 {
+        self.id
+    }
+
+    async fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Quantity over all of the region's orders
+    async fn total(&self, ctx: &Context<'_>) -> Result<i64> {
+        let totals = ctx.data::<DataLoader<RegionTotalLoader>>()?;
+        Ok(totals.load_one(self.id).await?.unwrap_or(0))
+    }
+
+    #[graphql(complexity = "page(first) as usize * child_complexity")]
+    async fn orders(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 20)] first: i32,
+        #[graphql(default)] offset: i32,
+    ) -> Result<Vec<Order>> {
+        let filter = OrderFilter {
+            region_id: Some(self.id),
+            ..Default::default()
+        };
+        fetch_orders(pool(ctx)?, &filter, first, offset).await
+    }
+
+    // Ranked from the same summary as /18/regions/top_list, ties broken by name
+    #[graphql(complexity = "page(limit) as usize * child_complexity")]
+    async fn top_gifts(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 3)] limit: i32,
+    ) -> Result<Vec<GiftRanking>> {
+        let gifts = sqlx::query_as::<_, GiftRanking>(
+            "SELECT $1::BIGINT AS region_id, gift_name, SUM(quantity)::BIGINT AS total_quantity,
+                ROW_NUMBER() OVER (ORDER BY SUM(quantity) DESC, gift_name) AS rank
+            FROM order_totals
+            WHERE region_id = $1 AND gift_name IS NOT NULL
+            GROUP BY gift_name
+            ORDER BY rank
+            LIMIT $2",
+        )
+        .bind(self.id)
+        .bind(page(limit))
+        .fetch_all(pool(ctx)?)
+        .await?;
+        Ok(gifts)
+    }
+}
+
+#[derive(SimpleObject, sqlx::FromRow)]
+#[graphql(complex)]
+struct GiftRanking {
+    #[graphql(skip)]
+    region_id: i64,
+    gift_name: String,
+    total_quantity: i64,
+    rank: i64,
+}
+
+#[ComplexObject]
+impl GiftRanking {
+    // The region's orders for this gift
+    #[graphql(complexity = "page(first) as usize * child_complexity")]
+    async fn orders(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 20)] first: i32,
+        #[graphql(default)] offset: i32,
+    ) -> Result<Vec<Order>> {
+        let filter = OrderFilter {
+            region_id: Some(self.region_id),
+            gift_name: Some(self.gift_name.clone()),
+            ..Default::default()
+        };
+        fetch_orders(pool(ctx)?, &filter, first, offset).await
+    }
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    #[graphql(complexity = "page(first) as usize * child_complexity")]
+    async fn orders(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<OrderFilter>,
+        #[graphql(default = 20)] first: i32,
+        #[graphql(default)] offset: i32,
+    ) -> Result<Vec<Order>> {
+        fetch_orders(pool(ctx)?, &filter.unwrap_or_default(), first, offset).await
+    }
+
+    async fn order(&self, ctx: &Context<'_>, id: i64) -> Result<Option<Order>> {
+        let order = sqlx::query_as(&format!("{} WHERE id = $1", ORDER_COLUMNS))
+            .bind(id)
+            .fetch_optional(pool(ctx)?)
+            .await?;
+        Ok(order)
+    }
+
+    #[graphql(complexity = "page(first) as usize * child_complexity")]
+    async fn regions(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 20)] first: i32,
+        #[graphql(default)] offset: i32,
+    ) -> Result<Vec<Region>> {
+        let rows =
+            sqlx::query("SELECT id::BIGINT, name FROM regions ORDER BY name LIMIT $1 OFFSET $2")
+                .bind(page(first))
+                .bind(offset.max(0) as i64)
+                .fetch_all(pool(ctx)?)
+                .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| Region {
+                id: row.get(0),
+                name: row.get(1),
+            })
+            .collect())
+    }
+
+    async fn region(&self, ctx: &Context<'_>, id: i64) -> Result<Option<Region>> {
+        let regions = ctx.data::<DataLoader<RegionLoader>>()?;
+        Ok(regions.load_one(id).await?)
+    }
+
+    // Same figure as /13/orders/total, in the same half-open range
+    async fn total_orders(
+        &self,
+        ctx: &Context<'_>,
+        from: Option<String>,
+        to: Option<String>,
+    ) -> Result<i64> {
+        let mut query = totals::query(
+            "SELECT COALESCE(SUM(quantity), 0)::BIGINT",
+            &time_range(from, to)?,
+        );
+        Ok(query.build().fetch_one(pool(ctx)?).await?.get(0))
+    }
+
+    async fn popular_gift(
+        &self,
+        ctx: &Context<'_>,
+        from: Option<String>,
+        to: Option<String>,
+    ) -> Result<Option<String>> {
+        let mut query = totals::query("SELECT gift_name", &time_range(from, to)?);
+        query.push(" GROUP BY gift_name ORDER BY SUM(quantity) DESC LIMIT 1");
+        let row = query.build().fetch_optional(pool(ctx)?).await?;
+        Ok(row.map(|row| row.get(0)))
+    }
+}
+
+fn time_range(from: Option<String>, to: Option<String>) -> Result<TimeRange> {
+    let range = TimeRange {
+        from: parse_time(from)?,
+        to: parse_time(to)?,
+    };
+    range.validate()?;
+    Ok(range)
+}
+
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+#[graphql(remote = "ConflictStrategy")]
+enum OnConflict {
+    Error,
+    Skip,
+    Upsert,
+    Replace,
+}
+
+#[derive(InputObject)]
+struct OrderInput {
+    id: i64,
+    region_id: i64,
+    gift_name: String,
+    quantity: i64,
+    // RFC 3339; the time of the insert when left out
+    created_at: Option<String>,
+}
+
+#[derive(InputObject)]
+struct RegionInput {
+    id: i64,
+    name: String,
+}
+
+#[derive(SimpleObject)]
+struct BatchError {
+    id: i64,
+    error: String,
+}
+
+// Mirrors the REST bulk summary; nothing was kept unless `committed` is set
+#[derive(SimpleObject)]
+struct BatchResult {
+    committed: bool,
+    inserted: Vec<i64>,
+    updated: Vec<i64>,
+    skipped: Vec<i64>,
+    errors: Vec<BatchError>,
+}
+
+impl BatchResult {
+    fn new(summary: BulkSummary, strategy: ConflictStrategy) -> Self {
+        BatchResult {
+            committed: summary.status(strategy).is_success(),
+            inserted: summary.inserted,
+            updated: summary.updated,
+            skipped: summary.skipped,
+            errors: summary
+                .errors
+                .into_iter()
+                .map(|RowError { id, error }| BatchError { id, error })
+                .collect(),
+        }
+    }
+}
+
+pub struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    async fn add_orders(
+        &self,
+        ctx: &Context<'_>,
+        orders: Vec<OrderInput>,
+        #[graphql(default_with = "OnConflict::Error")] on_conflict: OnConflict,
+    ) -> Result<BatchResult> {
+        let tenant = ctx.data::<Tenant>()?;
+        let orders = orders
+            .into_iter()
+            .map(|order| {
+                Ok(Order {
+                    id: order.id,
+                    region_id: order.region_id,
+                    gift_name: order.gift_name,
+                    quantity: order.quantity,
+                    created_at: parse_time(order.created_at)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let strategy = on_conflict.into();
+        let result = BatchResult::new(
+            bulk::insert_batch(&tenant.pool, strategy, orders).await?,
+            strategy,
+        );
+        if result.committed {
+            let written = [result.inserted.as_slice(), result.updated.as_slice()].concat();
+            feed::publish(tenant, Change::Written(written));
+        }
+        Ok(result)
+    }
+
+    async fn add_regions(
+        &self,
+        ctx: &Context<'_>,
+        regions: Vec<RegionInput>,
+        #[graphql(default_with = "OnConflict::Error")] on_conflict: OnConflict,
+    ) -> Result<BatchResult> {
+        let tenant = ctx.data::<Tenant>()?;
+        let regions = regions
+            .into_iter()
+            .map(|region| Region {
+                id: region.id,
+                name: region.name,
+            })
+            .collect();
+        let strategy = on_conflict.into();
+        let result = BatchResult::new(
+            bulk::insert_batch(&tenant.pool, strategy, regions).await?,
+            strategy,
+        );
+        if result.committed && !(result.inserted.is_empty() && result.updated.is_empty()) {
+            feed::publish(tenant, Change::Imported);
+        }
+        Ok(result)
+    }
+
+    // False when there was no such order
+    async fn delete_order(&self, ctx: &Context<'_>, id: i64) -> Result<bool> {
+        let tenant = ctx.data::<Tenant>()?;
+        let deleted = sqlx::query("DELETE FROM orders WHERE id = $1")
+            .bind(id)
+            .execute(&tenant.pool)
+            .await?
+            .rows_affected();
+        if deleted > 0 {
+            feed::publish(tenant, Change::Deleted(id));
+        }
+        Ok(deleted > 0)
+    }
+
+    // Fails while orders still point at the region
+    async fn delete_region(&self, ctx: &Context<'_>, id: i64) -> Result<bool> {
+        let tenant = ctx.data::<Tenant>()?;
+        let deleted = sqlx::query("DELETE FROM regions WHERE id = $1")
+            .bind(id)
+            .execute(&tenant.pool)
+            .await
+            .map_err(|e| match e.as_database_error() {
+                Some(e) => bulk::describe(e),
+                None => e.to_string(),
+            })?
+            .rows_affected();
+        if deleted > 0 {
+            feed::publish(tenant, Change::Imported);
+        }
+        Ok(deleted > 0)
+    }
+}
+
+#[post("/graphql")]
+async fn graphql(
+    state: web::Data<AppState>,
+    tenant: Tenant,
+    request: web::Json<async_graphql::Request>,
+) -> HttpResponse {
+    let request = request
+        .into_inner()
+        .data(DataLoader::new(
+            RegionLoader(tenant.pool.clone()),
+            actix_web::rt::spawn,
+        ))
+        .data(DataLoader::new(
+            RegionTotalLoader(tenant.pool.clone()),
+            actix_web::rt::spawn,
+        ))
+        .data(tenant);
+    HttpResponse::Ok().json(state.graphql.execute(request).await)
+}
@@ -0,0 +1,134 @@
+use serde::Deserialize;
+use sqlx::{Postgres, QueryBuilder};
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Ties {
+    // Every gift gets its own rank, ties broken by name
+    #[default]
+    RowNumber,
+    // Tied gifts share a rank and the next rank is skipped, so more than `number` may be listed
+    Rank,
+    // Tied gifts share a rank and the next rank follows on
+    DenseRank,
+}
+
+impl Ties {
+    fn window(&self) -> &'static str {
+        match self {
+            Ties::RowNumber => {
+                "ROW_NUMBER() OVER (PARTITION BY region_id ORDER BY quantity DESC, gift_name)"
+            }
+            Ties::Rank => "RANK() OVER (PARTITION BY region_id ORDER BY quantity DESC)",
+            Ties::DenseRank => "DENSE_RANK() OVER (PARTITION BY region_id ORDER BY quantity DESC)",
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    #[default]
+    Region,
+    // One ranking over the orders of every region
+    Global,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct TopListQuery {
+    // Lists quantities, share and rank instead of bare names
+    #[serde(default)]
+    pub details: bool,
+    #[serde(default)]
+    pub ties: Ties,
+    #[serde(default)]
+    pub scope: Scope,
+    pub region_id: Option<i64>,
+    // Only this gift is listed, still at its rank among all gifts
+    pub gift: Option<String>,
+    // Gifts ordered fewer times than this in the region are left out
+    pub min_quantity: Option<i64>,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+pub struct RankedGift {
+    pub region_id: Option<i64>,
+    pub region: Option<String>,
+    pub gift_name: Option<String>,
+    pub quantity: Option<i64>,
+    // Percentage of the region's quantity, or of all regions' for a global ranking
+    pub share: Option<f64>,
+    pub rank: Option<i64>,
+}
+
+impl TopListQuery {
+    // Ranks and shares are taken over all gifts, before the gift and quantity filters
+    pub fn build(&self, number: i32) -> QueryBuilder<'_, Postgres> {
+        let region = match self.scope {
+            Scope::Region => "regions.id::BIGINT",
+            Scope::Global => "NULL::BIGINT",
+        };
+        let mut query = QueryBuilder::new(format!(
+            "WITH totals AS (
+                SELECT {} AS region_id, order_totals.gift_name,
+                    SUM(order_totals.quantity)::BIGINT AS quantity
+                FROM order_totals
+                INNER JOIN regions ON regions.id = order_totals.region_id
+                WHERE order_totals.gift_name IS NOT NULL",
+            region
+        ));
+        if let Some(region_id) = self.region_id {
+            query.push(" AND regions.id = ").push_bind(region_id);
+        }
+        query.push(format!(
+            " GROUP BY 1, order_totals.gift_name
+            ),
+            ranked AS (
+                SELECT region_id, gift_name, quantity,
+                    {} AS rank,
+                    ROUND(100.0 * quantity / NULLIF(SUM(quantity) OVER (PARTITION BY region_id), 0), 2)::FLOAT8 AS share
+                FROM totals
+            )",
+            self.ties.window()
+        ));
+
+        // The filters go in the join, so regions without a listed gift still show up
+        match self.scope {
+            Scope::Region => {
+                query.push(
+                    " SELECT regions.id::BIGINT AS region_id, regions.name AS region,
+                        ranked.gift_name, ranked.quantity, ranked.share, ranked.rank
+                    FROM regions
+                    LEFT JOIN ranked ON ranked.region_id = regions.id AND ranked.rank <= ",
+                );
+            }
+            Scope::Global => {
+                query.push(
+                    " SELECT NULL::BIGINT AS region_id, NULL::TEXT AS region,
+                        gift_name, quantity, share, rank
+                    FROM ranked
+                    WHERE rank <= ",
+                );
+            }
+        }
+        query.push_bind(number as i64);
+        if let Some(gift) = &self.gift {
+            query.push(" AND gift_name = ").push_bind(gift.as_str());
+        }
+        if let Some(min_quantity) = self.min_quantity {
+            query.push(" AND quantity >= ").push_bind(min_quantity);
+        }
+        match self.scope {
+            Scope::Region => {
+                if let Some(region_id) = self.region_id {
+                    query.push(" WHERE regions.id = ").push_bind(region_id);
+                }
+                query.push(" ORDER BY regions.name, regions.id, rank, gift_name");
+            }
+            Scope::Global => {
+                query.push(" ORDER BY rank, gift_name");
+            }
+        }
+        query
+    }
+}
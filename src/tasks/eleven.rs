@@ -1,60 +1,491 @@
+pub(crate) mod analysis;
+pub(crate) mod limits;
+pub(crate) mod store;
+pub(crate) mod transform;
+
+use crate::tasks::eleven::analysis::Predicate;
+use crate::tasks::eleven::limits::{DecodeError, ImageLimits};
+use crate::tasks::eleven::store::AssetError;
+use crate::tasks::eleven::transform::{Transform, TransformError, TransformQuery};
+use crate::AppState;
 use actix_files::NamedFile;
-use actix_multipart::Multipart;
-use actix_web::{error, get, post, web, Error, HttpResponse, Responder};
+use actix_multipart::{Field, Multipart};
+use actix_web::http::{header, StatusCode};
+use actix_web::{delete, error, get, post, web, Error, HttpRequest, HttpResponse, Responder};
+use futures::stream::FuturesOrdered;
 use futures::{StreamExt, TryStreamExt};
-use image::io::Reader as ImageReader;
-use image::{GenericImageView, Pixel};
-use std::io::Cursor;
+use image::{DynamicImage, GenericImageView, ImageFormat, Pixel};
+use serde::{Deserialize, Serialize};
+
+fn asset_error(e: AssetError) -> Error {
+    match e {
+        AssetError::InvalidName => error::ErrorBadRequest("Invalid asset name"),
+        AssetError::NotFound => error::ErrorNotFound("Asset not found"),
+        AssetError::AlreadyExists => error::ErrorConflict("Asset already exists"),
+        AssetError::TooLarge => error::ErrorPayloadTooLarge("Asset is too large"),
+        AssetError::UnsupportedType(mime) => {
+            error::ErrorUnsupportedMediaType(format!("Unsupported asset type: {}", mime))
+        }
+        AssetError::Io(e) => error::ErrorInternalServerError(e),
+    }
+}
+
+fn transform_error(e: TransformError) -> Error {
+    match e {
+        TransformError::Invalid(message) => error::ErrorBadRequest(message),
+        TransformError::UnsupportedFormat(format) => {
+            error::ErrorUnsupportedMediaType(format!("Unsupported output format: {}", format))
+        }
+        TransformError::Image(message) => error::ErrorInternalServerError(message),
+    }
+}
+
+fn etag_matches(req: &HttpRequest, etag: &str) -> bool {
+    req.headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.split(',').any(|tag| tag.trim() == etag))
+}
 
 #[get("/11/assets/{file_name}")]
-async fn assets(file_name: web::Path<String>) -> impl Responder {
-    NamedFile::open_async(format!("static/{}", *file_name)).await
+async fn assets(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    file_name: web::Path<String>,
+    query: web::Query<TransformQuery>,
+) -> Result<HttpResponse, Error> {
+    let transform = Transform::from_query(&query).map_err(transform_error)?;
+    if transform.is_identity() {
+        let path = state.assets.path(&file_name).map_err(asset_error)?;
+        return Ok(NamedFile::open_async(path)
+            .await?
+            .into_response(&req)
+            .map_into_boxed_body());
+    }
+
+    let lookup_state = state.clone();
+    let name = file_name.into_inner();
+    let source = web::block(move || lookup_state.assets.asset(&name))
+        .await?
+        .map_err(asset_error)?;
+    let format = transform.output_format(&source.mime);
+    let key = transform.cache_key(&source.sha256, format);
+    let etag = format!("\"{}\"", key);
+
+    if etag_matches(&req, &etag) {
+        return Ok(HttpResponse::NotModified()
+            .insert_header((header::ETAG, etag))
+            .finish());
+    }
+
+    // Derivatives are rendered once per source hash and parameter set, then served from disk
+    let path = match state.assets.derived(&key, format.extension()) {
+        Some(path) => path,
+        None => {
+            let source_path = state.assets.path(&source.name).map_err(asset_error)?;
+            let data = web::block(move || std::fs::read(source_path)).await??;
+            let decode_state = state.clone();
+            let img = web::block(move || decode_state.image_limits.decode(&data))
+                .await?
+                .map_err(decode_error)?;
+            let encoded = web::block(move || transform::encode(&transform.apply(img)?, format))
+                .await?
+                .map_err(transform_error)?;
+
+            let state = state.clone();
+            web::block(move || {
+                state
+                    .assets
+                    .store_derived(&key, format.extension(), &encoded)
+            })
+            .await?
+            .map_err(asset_error)?
+        }
+    };
+
+    let file = NamedFile::open_async(path)
+        .await?
+        .use_etag(false)
+        .set_content_type(format.mime().parse().unwrap());
+    Ok(file
+        .customize()
+        .insert_header((header::ETAG, etag))
+        .respond_to(&req)
+        .map_into_boxed_body())
 }
 
-#[post("/11/red_pixels")]
-async fn count_red_pixels(mut payload: Multipart) -> Result<HttpResponse, Error> {
-    while let Ok(Some(mut field)) = payload.try_next().await {
-        let content_disposition = field.content_disposition();
-        let name = content_disposition.get_name().unwrap_or("");
-
-        if name == "image" {
-            let mut data = Vec::new();
-            while let Some(chunk) = field.next().await {
-                let chunk = chunk?;
-                data.extend_from_slice(&chunk);
+#[get("/11/assets")]
+async fn list_assets(state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+    let listed = web::block(move || state.assets.list())
+        .await?
+        .map_err(asset_error)?;
+    Ok(HttpResponse::Ok().json(listed))
+}
+
+#[post("/11/assets")]
+async fn upload_assets(
+    state: web::Data<AppState>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, Error> {
+    let max_bytes = state.assets.max_upload_bytes();
+    let mut uploaded = Vec::new();
+
+    while let Some(mut field) = payload.try_next().await? {
+        let Some(file_name) = field.content_disposition().get_filename().map(String::from) else {
+            continue;
+        };
+        store::validate_name(&file_name).map_err(asset_error)?;
+
+        let mut data = Vec::new();
+        while let Some(chunk) = field.next().await {
+            let chunk = chunk?;
+            // Stop reading as soon as the limit is crossed rather than buffering the rest
+            if data.len() + chunk.len() > max_bytes {
+                return Err(asset_error(AssetError::TooLarge));
             }
+            data.extend_from_slice(&chunk);
+        }
 
-            let img = ImageReader::new(Cursor::new(data))
-                .with_guessed_format()?
-                .decode()
-                .map_err(|e| error::ErrorInternalServerError(e))?;
-
-            let red_pixels = img
-                .pixels()
-                .filter(|(_, _, rgba)| {
-                    let channels = rgba.channels();
-                    // r > (g + b)
-                    channels[0] as u16 > (channels[1] as u16 + channels[2] as u16)
-                })
-                .count();
-
-            return Ok(HttpResponse::Ok().body(red_pixels.to_string()));
+        let state = state.clone();
+        let asset = web::block(move || state.assets.store(&file_name, &data))
+            .await?
+            .map_err(asset_error)?;
+        uploaded.push(asset);
+    }
+
+    if uploaded.is_empty() {
+        return Ok(HttpResponse::BadRequest().body("No files in the request"));
+    }
+    Ok(HttpResponse::Created().json(uploaded))
+}
+
+#[delete("/11/assets/{file_name}")]
+async fn delete_asset(
+    state: web::Data<AppState>,
+    file_name: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    web::block(move || state.assets.delete(&file_name))
+        .await?
+        .map_err(asset_error)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/11/assets/by-hash/{sha256}")]
+async fn asset_by_hash(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    sha256: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let sha256 = sha256.into_inner().to_lowercase();
+    let etag = format!("\"{}\"", sha256);
+    let cache_control = "public, max-age=31536000, immutable";
+
+    // The URL names the content, so a matching ETag can skip the lookup entirely
+    let if_none_match = req
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok());
+    if if_none_match.is_some_and(|value| value.split(',').any(|tag| tag.trim() == etag)) {
+        return Ok(HttpResponse::NotModified()
+            .insert_header((header::ETAG, etag))
+            .insert_header((header::CACHE_CONTROL, cache_control))
+            .finish());
+    }
+
+    let lookup_state = state.clone();
+    let asset = web::block(move || lookup_state.assets.find_by_hash(&sha256))
+        .await?
+        .map_err(asset_error)?;
+    let path = state.assets.path(&asset.name).map_err(asset_error)?;
+    let file = NamedFile::open_async(path)
+        .await?
+        .use_etag(false)
+        .use_last_modified(false);
+
+    Ok(file
+        .customize()
+        .insert_header((header::ETAG, etag))
+        .insert_header((header::CACHE_CONTROL, cache_control))
+        .respond_to(&req)
+        .map_into_boxed_body())
+}
+
+fn decode_error(e: DecodeError) -> Error {
+    let message = e.to_string();
+    match e {
+        DecodeError::TooLarge(_) => error::ErrorPayloadTooLarge(message),
+        DecodeError::UnsupportedFormat(_) => error::ErrorUnsupportedMediaType(message),
+        DecodeError::Invalid(_) => error::ErrorBadRequest(message),
+    }
+}
+
+async fn read_field(field: &mut Field, max_bytes: usize) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+    while let Some(chunk) = field.next().await {
+        let chunk = chunk?;
+        if data.len() + chunk.len() > max_bytes {
+            return Err(error::ErrorPayloadTooLarge(format!(
+                "Images are limited to {} bytes",
+                max_bytes
+            )));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+// Reads the first multipart field named `image` and decodes it within the configured limits
+async fn image_from_payload(
+    payload: &mut Multipart,
+    limits: &ImageLimits,
+) -> Result<Option<DynamicImage>, Error> {
+    while let Ok(Some(mut field)) = payload.try_next().await {
+        if field.content_disposition().get_name() == Some("image") {
+            let data = read_field(&mut field, limits.max_input_bytes).await?;
+            let limits = limits.clone();
+            let img = web::block(move || limits.decode(&data))
+                .await?
+                .map_err(decode_error)?;
+            return Ok(Some(img));
         }
     }
+    Ok(None)
+}
 
-    Ok(HttpResponse::BadRequest().body("No image field in the request"))
+fn red_pixel_count(img: &DynamicImage) -> usize {
+    img.pixels()
+        .filter(|(_, _, rgba)| {
+            let channels = rgba.channels();
+            // r > (g + b)
+            channels[0] as u16 > (channels[1] as u16 + channels[2] as u16)
+        })
+        .count()
+}
+
+#[derive(Serialize)]
+struct RedPixelResult {
+    field: String,
+    filename: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    red_pixels: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl RedPixelResult {
+    fn new(
+        field: String,
+        filename: Option<String>,
+        outcome: Result<((u32, u32), ImageFormat, usize), Error>,
+    ) -> Self {
+        match outcome {
+            Ok(((width, height), format, count)) => RedPixelResult {
+                field,
+                filename,
+                width: Some(width),
+                height: Some(height),
+                format: Some(format.extensions_str()[0].to_string()),
+                red_pixels: Some(count),
+                error: None,
+            },
+            Err(e) => RedPixelResult {
+                field,
+                filename,
+                width: None,
+                height: None,
+                format: None,
+                red_pixels: None,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+fn wants_json(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains("application/json"))
+}
+
+// Every part carrying a file, or named `image`, is decoded and counted on the blocking
+// pool as soon as it has been read, with at most `max_concurrent_decodes` in flight
+#[post("/11/red_pixels")]
+async fn count_red_pixels(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, Error> {
+    let limits = &state.image_limits;
+    let mut parts = Vec::new();
+    let mut pending = FuturesOrdered::new();
+    let mut results = Vec::new();
+
+    while let Some(mut field) = payload.try_next().await? {
+        let disposition = field.content_disposition();
+        let name = disposition.get_name().unwrap_or_default().to_string();
+        let filename = disposition.get_filename().map(String::from);
+        if filename.is_none() && name != "image" {
+            continue;
+        }
+        if parts.len() == limits.max_batch_images {
+            return Err(error::ErrorPayloadTooLarge(format!(
+                "At most {} images can be sent at once",
+                limits.max_batch_images
+            )));
+        }
+
+        let outcome = match read_field(&mut field, limits.max_input_bytes).await {
+            Ok(data) => {
+                let limits = limits.clone();
+                Ok(web::block(move || {
+                    limits.decode_with_format(&data).map(|(img, format)| {
+                        let count = red_pixel_count(&img);
+                        (img.dimensions(), format, count)
+                    })
+                }))
+            }
+            // An oversized file only fails its own entry, anything else breaks the whole stream
+            Err(e) if e.as_response_error().status_code() == StatusCode::PAYLOAD_TOO_LARGE => {
+                Err(e)
+            }
+            Err(e) => return Err(e),
+        };
+        parts.push((name, filename));
+        pending.push_back(async move {
+            match outcome {
+                Ok(task) => task.await.map_err(Error::from)?.map_err(decode_error),
+                Err(e) => Err(e),
+            }
+        });
+        // The next part is only read once a decode slot is free
+        if pending.len() >= limits.max_concurrent_decodes.max(1) {
+            results.extend(pending.next().await);
+        }
+    }
+
+    if parts.is_empty() {
+        return Ok(HttpResponse::BadRequest().body("No image field in the request"));
+    }
+    results.extend(pending.collect::<Vec<_>>().await);
+
+    // A lone `image` part keeps the plain-text answer the original challenge expects
+    if parts.len() == 1 && parts[0].0 == "image" && !wants_json(&req) {
+        let (_, _, count) = results.pop().unwrap()?;
+        return Ok(HttpResponse::Ok().body(count.to_string()));
+    }
+
+    let results: Vec<RedPixelResult> = parts
+        .into_iter()
+        .zip(results)
+        .map(|((field, filename), outcome)| RedPixelResult::new(field, filename, outcome))
+        .collect();
+    Ok(HttpResponse::Ok().json(results))
+}
+
+const DEFAULT_DOMINANT_COLORS: usize = 5;
+const MAX_DOMINANT_COLORS: usize = 64;
+
+#[derive(Deserialize)]
+struct AnalysisQuery {
+    predicate: Option<String>,
+    colors: Option<usize>,
+}
+
+#[post("/11/analyze")]
+async fn analyze_image(
+    state: web::Data<AppState>,
+    query: web::Query<AnalysisQuery>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, Error> {
+    let query = query.into_inner();
+    let predicate = match &query.predicate {
+        Some(expression) => Some(Predicate::parse(expression).map_err(error::ErrorBadRequest)?),
+        None => None,
+    };
+    let colors = query.colors.unwrap_or(DEFAULT_DOMINANT_COLORS);
+    if colors > MAX_DOMINANT_COLORS {
+        return Ok(HttpResponse::BadRequest().body(format!(
+            "At most {} dominant colours can be requested",
+            MAX_DOMINANT_COLORS
+        )));
+    }
+
+    let Some(img) = image_from_payload(&mut payload, &state.image_limits).await? else {
+        return Ok(HttpResponse::BadRequest().body("No image field in the request"));
+    };
+
+    let result = web::block(move || {
+        let predicate = query.predicate.as_deref().zip(predicate.as_ref());
+        analysis::analyze(&img, colors, predicate)
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok().json(result))
 }
 
 #[cfg(test)]
 mod test {
+    use crate::config::AppConfig;
+    use crate::tasks::eleven::store::AssetStoreConfig;
     use actix_web::{http, test, App};
     use http::header::CONTENT_TYPE;
+    use sqlx::postgres::PgPoolOptions;
+    use std::path::Path;
 
     use super::*;
 
+    fn set_up_state(assets_dir: &Path) -> web::Data<AppState> {
+        set_up_state_with(assets_dir, ImageLimits::default())
+    }
+
+    fn set_up_state_with(assets_dir: &Path, images: ImageLimits) -> web::Data<AppState> {
+        let pool = PgPoolOptions::new()
+            .connect_lazy("postgres://localhost/postgres")
+            .unwrap();
+        let config = AppConfig {
+            assets: AssetStoreConfig {
+                dir: assets_dir.to_path_buf(),
+                derived_dir: assets_dir.join(".derived"),
+                ..Default::default()
+            },
+            images,
+            ..Default::default()
+        };
+        web::Data::new(AppState::new(pool, config))
+    }
+
+    fn multipart_body(boundary: &str, parts: &[(&str, &str, &[u8])]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (name, file_name, data) in parts {
+            body.extend_from_slice(
+                format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n\r\n",
+                    boundary, name, file_name
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(data);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+        body
+    }
+
     #[actix_web::test]
     async fn test_assets() {
-        let app = test::init_service(App::new().service(assets)).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(set_up_state(Path::new("static")))
+                .service(assets),
+        )
+        .await;
 
         let req = test::TestRequest::get()
             .uri("/11/assets/decoration.png")
@@ -74,7 +505,12 @@ mod test {
 
     #[actix_web::test]
     async fn test_count_red_pixels() {
-        let mut app = test::init_service(App::new().service(count_red_pixels)).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(set_up_state(Path::new("static")))
+                .service(count_red_pixels),
+        )
+        .await;
 
         let file_path = "static/decoration.png";
         let file = std::fs::read(file_path).expect("Unable to read file");
@@ -101,10 +537,316 @@ mod test {
             .set_payload(request_body)
             .to_request();
 
-        let res = test::call_service(&mut app, req).await;
+        let res = test::call_service(&app, req).await;
         assert!(res.status().is_success());
 
         let res_body = test::read_body(res).await;
         assert_eq!(res_body, "73034");
     }
+
+    #[actix_web::test]
+    async fn test_asset_transformations() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::copy("static/decoration.png", dir.path().join("tree.png")).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(set_up_state(dir.path()))
+                .service(assets),
+        )
+        .await;
+
+        let uri = "/11/assets/tree.png?w=200&h=200&fit=cover&format=jpeg&grayscale=1";
+        let req = test::TestRequest::get().uri(uri).to_request();
+        let res = test::call_service(&app, req).await;
+        assert!(res.status().is_success());
+        assert_eq!(res.headers().get(CONTENT_TYPE).unwrap(), "image/jpeg");
+        let etag = res.headers().get(header::ETAG).unwrap().clone();
+        let body = test::read_body(res).await;
+        let img = image::load_from_memory(&body).unwrap();
+        assert_eq!(img.dimensions(), (200, 200));
+        assert_eq!(
+            std::fs::read_dir(dir.path().join(".derived"))
+                .unwrap()
+                .count(),
+            1
+        );
+
+        let req = test::TestRequest::get()
+            .uri(uri)
+            .insert_header((header::IF_NONE_MATCH, etag.clone()))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 304);
+
+        // Served from the cached derivative the second time round
+        let req = test::TestRequest::get().uri(uri).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.headers().get(header::ETAG).unwrap(), etag);
+        assert_eq!(test::read_body(res).await, body);
+
+        let req = test::TestRequest::get()
+            .uri("/11/assets/tree.png?w=200&h=200&fit=cover&format=webp&grayscale=1")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert!(res.status().is_success());
+        assert_eq!(res.headers().get(CONTENT_TYPE).unwrap(), "image/webp");
+        let webp = image::load_from_memory(&test::read_body(res).await).unwrap();
+        assert_eq!(webp.dimensions(), (200, 200));
+
+        for (uri, status) in [
+            ("/11/assets/tree.png?w=0", 400),
+            ("/11/assets/tree.png?rotate=45", 400),
+            ("/11/assets/tree.png?crop=0,0,5000,10", 400),
+            ("/11/assets/tree.png?format=avif", 415),
+            ("/11/assets/missing.png?w=10", 404),
+        ] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), status, "{}", uri);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_count_red_pixels_batch() {
+        // Fewer decode slots than parts, so the batch has to wait for them in order
+        let limits = ImageLimits {
+            max_concurrent_decodes: 2,
+            ..Default::default()
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(set_up_state_with(Path::new("static"), limits))
+                .service(count_red_pixels),
+        )
+        .await;
+
+        let png = std::fs::read("static/decoration.png").unwrap();
+        let boundary = "boundary";
+        let req = test::TestRequest::post()
+            .uri("/11/red_pixels")
+            .insert_header((
+                CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", boundary),
+            ))
+            .set_payload(multipart_body(
+                boundary,
+                &[
+                    ("frames", "one.png", &png),
+                    ("frames", "notes.txt", b"Ho ho ho"),
+                    ("frames", "two.png", &png),
+                ],
+            ))
+            .to_request();
+        let results: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+        assert_eq!(results.as_array().unwrap().len(), 3);
+        assert_eq!(results[0]["field"], "frames");
+        assert_eq!(results[0]["filename"], "one.png");
+        assert_eq!(results[0]["format"], "png");
+        assert!(results[0]["width"].as_u64().is_some());
+        assert_eq!(results[0]["red_pixels"], 73034);
+        assert_eq!(results[1]["filename"], "notes.txt");
+        assert!(results[1]["error"].is_string());
+        assert!(results[1].get("red_pixels").is_none());
+        assert_eq!(results[2]["red_pixels"], 73034);
+
+        // A single image can still be asked for as JSON
+        let req = test::TestRequest::post()
+            .uri("/11/red_pixels")
+            .insert_header((
+                CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", boundary),
+            ))
+            .insert_header((header::ACCEPT, "application/json"))
+            .set_payload(multipart_body(boundary, &[("image", "one.png", &png)]))
+            .to_request();
+        let results: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(results[0]["red_pixels"], 73034);
+    }
+
+    #[actix_web::test]
+    async fn test_image_limits() {
+        let app = test::init_service(
+            App::new()
+                .app_data(set_up_state(Path::new("static")))
+                .service(count_red_pixels),
+        )
+        .await;
+
+        // Compresses to a few hundred bytes but is taller than the default limit
+        let mut tall = Vec::new();
+        DynamicImage::new_rgb8(1, 9000)
+            .write_to(
+                &mut std::io::Cursor::new(&mut tall),
+                image::ImageOutputFormat::Png,
+            )
+            .unwrap();
+
+        let boundary = "boundary";
+        for (file_name, data, status) in [
+            ("tall.png", &tall[..], 413),
+            ("notes.txt", &b"Ho ho ho"[..], 415),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/11/red_pixels")
+                .insert_header((
+                    CONTENT_TYPE,
+                    format!("multipart/form-data; boundary={}", boundary),
+                ))
+                .set_payload(multipart_body(boundary, &[("image", file_name, data)]))
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), status, "{}", file_name);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_assets_reject_traversal() {
+        let app = test::init_service(
+            App::new()
+                .app_data(set_up_state(Path::new("static")))
+                .service(assets),
+        )
+        .await;
+
+        for uri in ["/11/assets/..%2FCargo.toml", "/11/assets/.gitignore"] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), 400, "{}", uri);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_asset_lifecycle() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(set_up_state(dir.path()))
+                .service(list_assets)
+                .service(upload_assets)
+                .service(delete_asset)
+                .service(asset_by_hash)
+                .service(assets),
+        )
+        .await;
+
+        let boundary = "boundary";
+        let png = std::fs::read("static/decoration.png").unwrap();
+        let req = test::TestRequest::post()
+            .uri("/11/assets")
+            .insert_header((
+                CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", boundary),
+            ))
+            .set_payload(multipart_body(
+                boundary,
+                &[
+                    ("file", "tree.png", &png),
+                    ("file", "notes.txt", b"Ho ho ho"),
+                ],
+            ))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 201);
+
+        let req = test::TestRequest::get().uri("/11/assets").to_request();
+        let listed: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(listed[0]["name"], "notes.txt");
+        assert_eq!(listed[0]["mime"], "text/plain");
+        assert_eq!(listed[1]["name"], "tree.png");
+        assert_eq!(listed[1]["size"], 787297);
+
+        let url = listed[1]["url"].as_str().unwrap().to_string();
+        let req = test::TestRequest::get().uri(&url).to_request();
+        let res = test::call_service(&app, req).await;
+        assert!(res.status().is_success());
+        assert_eq!(
+            res.headers().get(header::CACHE_CONTROL).unwrap(),
+            "public, max-age=31536000, immutable"
+        );
+        let etag = res.headers().get(header::ETAG).unwrap().clone();
+
+        let req = test::TestRequest::get()
+            .uri(&url)
+            .insert_header((header::IF_NONE_MATCH, etag))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 304);
+
+        let req = test::TestRequest::delete()
+            .uri("/11/assets/tree.png")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 204);
+
+        let req = test::TestRequest::get()
+            .uri("/11/assets/tree.png")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 404);
+
+        // The files the service ships with are not managed by the store
+        let req = test::TestRequest::delete()
+            .uri("/11/assets/decoration.png")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 404);
+        assert!(Path::new("static/decoration.png").is_file());
+    }
+
+    #[actix_web::test]
+    async fn test_analyze_image() {
+        let app = test::init_service(
+            App::new()
+                .app_data(set_up_state(Path::new("static")))
+                .service(analyze_image),
+        )
+        .await;
+
+        let boundary = "boundary";
+        let png = std::fs::read("static/decoration.png").unwrap();
+        let req = test::TestRequest::post()
+            .uri("/11/analyze?predicate=r%20%3E%20g%20%2B%20b&colors=3")
+            .insert_header((
+                CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", boundary),
+            ))
+            .set_payload(multipart_body(
+                boundary,
+                &[("image", "decoration.png", &png)],
+            ))
+            .to_request();
+        let res: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+        // Same predicate as /11/red_pixels, so the counts have to agree
+        assert_eq!(res["predicate"]["count"], 73034);
+        assert_eq!(res["histogram"]["r"].as_array().unwrap().len(), 256);
+        let pixels: u64 = res["histogram"]["a"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|count| count.as_u64().unwrap())
+            .sum();
+        assert_eq!(
+            pixels,
+            res["width"].as_u64().unwrap() * res["height"].as_u64().unwrap()
+        );
+        assert_eq!(res["dominant_colors"].as_array().unwrap().len(), 3);
+        let brightness = res["average_brightness"].as_f64().unwrap();
+        assert!(brightness > 0.0 && brightness < 1.0);
+
+        let req = test::TestRequest::post()
+            .uri("/11/analyze?predicate=hue%20between")
+            .insert_header((
+                CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", boundary),
+            ))
+            .set_payload(multipart_body(
+                boundary,
+                &[("image", "decoration.png", &png)],
+            ))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 400);
+    }
 }
@@ -0,0 +1,569 @@
+use image::{DynamicImage, GenericImageView, Pixel};
+use serde::Serialize;
+
+// Channel values a predicate can refer to, computed once per pixel
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PixelValues {
+    pub r: f64,
+    pub g: f64,
+    pub b: f64,
+    pub a: f64,
+    // Degrees in [0, 360)
+    pub h: f64,
+    // Saturation and value in [0, 1]
+    pub s: f64,
+    pub v: f64,
+    // Rec. 601 luma in [0, 1]
+    pub brightness: f64,
+}
+
+impl PixelValues {
+    pub fn from_rgba(rgba: [u8; 4]) -> Self {
+        let [r, g, b, a] = rgba.map(f64::from);
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        let delta = max - min;
+
+        let h = if delta == 0.0 {
+            0.0
+        } else if max == r {
+            60.0 * ((g - b) / delta).rem_euclid(6.0)
+        } else if max == g {
+            60.0 * ((b - r) / delta + 2.0)
+        } else {
+            60.0 * ((r - g) / delta + 4.0)
+        };
+
+        PixelValues {
+            r,
+            g,
+            b,
+            a,
+            h,
+            s: if max == 0.0 { 0.0 } else { delta / max },
+            v: max / 255.0,
+            brightness: (0.299 * r + 0.587 * g + 0.114 * b) / 255.0,
+        }
+    }
+
+    fn get(&self, channel: Channel) -> f64 {
+        match channel {
+            Channel::R => self.r,
+            Channel::G => self.g,
+            Channel::B => self.b,
+            Channel::A => self.a,
+            Channel::H => self.h,
+            Channel::S => self.s,
+            Channel::V => self.v,
+            Channel::Brightness => self.brightness,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Channel {
+    R,
+    G,
+    B,
+    A,
+    H,
+    S,
+    V,
+    Brightness,
+}
+
+impl Channel {
+    fn from_name(name: &str) -> Option<Channel> {
+        Some(match name {
+            "r" | "red" => Channel::R,
+            "g" | "green" => Channel::G,
+            "b" | "blue" => Channel::B,
+            "a" | "alpha" => Channel::A,
+            "h" | "hue" => Channel::H,
+            "s" | "saturation" => Channel::S,
+            "v" | "value" => Channel::V,
+            "l" | "brightness" => Channel::Brightness,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    And,
+    Or,
+}
+
+// Booleans are represented as 1.0 and 0.0 so arithmetic and logic share one tree
+#[derive(Clone, Debug, PartialEq)]
+enum Expr {
+    Number(f64),
+    Channel(Channel),
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    Between(Box<Expr>, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn eval(&self, pixel: &PixelValues) -> f64 {
+        let truth = |b: bool| if b { 1.0 } else { 0.0 };
+        match self {
+            Expr::Number(n) => *n,
+            Expr::Channel(c) => pixel.get(*c),
+            Expr::Neg(e) => -e.eval(pixel),
+            Expr::Not(e) => truth(e.eval(pixel) == 0.0),
+            Expr::Between(e, lo, hi) => {
+                let value = e.eval(pixel);
+                truth(lo.eval(pixel) <= value && value <= hi.eval(pixel))
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let (l, r) = (lhs.eval(pixel), rhs.eval(pixel));
+                match op {
+                    BinOp::Add => l + r,
+                    BinOp::Sub => l - r,
+                    BinOp::Mul => l * r,
+                    BinOp::Div => l / r,
+                    BinOp::Lt => truth(l < r),
+                    BinOp::Le => truth(l <= r),
+                    BinOp::Gt => truth(l > r),
+                    BinOp::Ge => truth(l >= r),
+                    BinOp::Eq => truth(l == r),
+                    BinOp::Ne => truth(l != r),
+                    BinOp::And => truth(l != 0.0 && r != 0.0),
+                    BinOp::Or => truth(l != 0.0 || r != 0.0),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Symbol(&'static str),
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    // Longer symbols come first so `<=` is not read as `<` followed by `=`
+    const SYMBOLS: [&str; 15] = [
+        "<=", ">=", "==", "!=", "&&", "||", "<", ">", "=", "+", "-", "*", "/", "(", ")",
+    ];
+    let mut tokens = Vec::new();
+    let mut rest = input.trim_start();
+
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap();
+        if c.is_ascii_digit() || c == '.' {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                .unwrap_or(rest.len());
+            let number = rest[..end]
+                .parse()
+                .map_err(|_| format!("Invalid number: {}", &rest[..end]))?;
+            tokens.push(Token::Number(number));
+            rest = &rest[end..];
+        } else if c.is_ascii_alphabetic() {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..end].to_lowercase()));
+            rest = &rest[end..];
+        } else if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        } else {
+            return Err(format!("Unexpected character: {}", c));
+        }
+        rest = rest.trim_start();
+    }
+
+    Ok(tokens)
+}
+
+// Deep enough for any sensible predicate, shallow enough to keep the parser off the end of
+// the stack
+const MAX_DEPTH: usize = 64;
+const MAX_PREDICATE_LEN: usize = 1024;
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    // Brackets, `not` and unary minus entered so far
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat_symbol(&mut self, symbols: &[&str]) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Symbol(s)) if symbols.contains(s) => {
+                let s = *s;
+                self.position += 1;
+                Some(s)
+            }
+            _ => None,
+        }
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Ident(ident)) if ident == keyword) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn nested(&mut self, parse: fn(&mut Parser) -> Result<Expr, String>) -> Result<Expr, String> {
+        if self.depth >= MAX_DEPTH {
+            return Err(format!(
+                "Predicate is nested more than {} levels deep",
+                MAX_DEPTH
+            ));
+        }
+        self.depth += 1;
+        let expr = parse(self);
+        self.depth -= 1;
+        expr
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.eat_keyword("or") || self.eat_symbol(&["||"]).is_some() {
+            expr = Expr::Binary(BinOp::Or, Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.not()?;
+        while self.eat_keyword("and") || self.eat_symbol(&["&&"]).is_some() {
+            expr = Expr::Binary(BinOp::And, Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr, String> {
+        if self.eat_keyword("not") {
+            return Ok(Expr::Not(Box::new(self.nested(Parser::not)?)));
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let lhs = self.sum()?;
+        if self.eat_keyword("between") {
+            let lo = self.sum()?;
+            if !self.eat_keyword("and") {
+                return Err("Expected 'and' in 'between' clause".to_string());
+            }
+            let hi = self.sum()?;
+            return Ok(Expr::Between(Box::new(lhs), Box::new(lo), Box::new(hi)));
+        }
+
+        let op = match self.eat_symbol(&["<", "<=", ">", ">=", "=", "==", "!="]) {
+            Some("<") => BinOp::Lt,
+            Some("<=") => BinOp::Le,
+            Some(">") => BinOp::Gt,
+            Some(">=") => BinOp::Ge,
+            Some("=") | Some("==") => BinOp::Eq,
+            Some("!=") => BinOp::Ne,
+            _ => return Ok(lhs),
+        };
+        Ok(Expr::Binary(op, Box::new(lhs), Box::new(self.sum()?)))
+    }
+
+    fn sum(&mut self) -> Result<Expr, String> {
+        let mut expr = self.product()?;
+        while let Some(symbol) = self.eat_symbol(&["+", "-"]) {
+            let op = if symbol == "+" {
+                BinOp::Add
+            } else {
+                BinOp::Sub
+            };
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.product()?));
+        }
+        Ok(expr)
+    }
+
+    fn product(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        while let Some(symbol) = self.eat_symbol(&["*", "/"]) {
+            let op = if symbol == "*" {
+                BinOp::Mul
+            } else {
+                BinOp::Div
+            };
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat_symbol(&["-"]).is_some() {
+            return Ok(Expr::Neg(Box::new(self.nested(Parser::unary)?)));
+        }
+
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::Ident(name)) => Channel::from_name(&name)
+                .map(Expr::Channel)
+                .ok_or_else(|| format!("Unknown channel: {}", name)),
+            Some(Token::Symbol("(")) => {
+                let expr = self.nested(Parser::or)?;
+                match self.next() {
+                    Some(Token::Symbol(")")) => Ok(expr),
+                    _ => Err("Expected ')'".to_string()),
+                }
+            }
+            Some(token) => Err(format!("Unexpected token: {:?}", token)),
+            None => Err("Unexpected end of predicate".to_string()),
+        }
+    }
+}
+
+// A boolean expression over a pixel's RGBA and HSV values,
+// e.g. `hue between 0 and 20 and saturation > 0.5` or `r > g + b`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Predicate(Expr);
+
+impl Predicate {
+    pub fn parse(input: &str) -> Result<Predicate, String> {
+        if input.len() > MAX_PREDICATE_LEN {
+            return Err(format!(
+                "Predicate is longer than {} bytes",
+                MAX_PREDICATE_LEN
+            ));
+        }
+        let mut parser = Parser {
+            tokens: tokenize(input)?,
+            position: 0,
+            depth: 0,
+        };
+        let expr = parser.or()?;
+        match parser.peek() {
+            None => Ok(Predicate(expr)),
+            Some(token) => Err(format!("Unexpected token: {:?}", token)),
+        }
+    }
+
+    pub fn matches(&self, pixel: &PixelValues) -> bool {
+        self.0.eval(pixel) != 0.0
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Histogram {
+    pub r: Vec<u64>,
+    pub g: Vec<u64>,
+    pub b: Vec<u64>,
+    pub a: Vec<u64>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct DominantColor {
+    pub hex: String,
+    pub rgb: [u8; 3],
+    // Fraction of the opaque pixels represented by this colour
+    pub share: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PredicateCount {
+    pub expression: String,
+    pub count: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Analysis {
+    pub width: u32,
+    pub height: u32,
+    pub histogram: Histogram,
+    pub average_brightness: f64,
+    pub dominant_colors: Vec<DominantColor>,
+    pub predicate: Option<PredicateCount>,
+}
+
+pub fn analyze(
+    img: &DynamicImage,
+    colors: usize,
+    predicate: Option<(&str, &Predicate)>,
+) -> Analysis {
+    let mut histogram = Histogram {
+        r: vec![0; 256],
+        g: vec![0; 256],
+        b: vec![0; 256],
+        a: vec![0; 256],
+    };
+    let mut brightness_sum = 0_f64;
+    let mut matching = 0_u64;
+    let mut opaque = Vec::new();
+
+    for (_, _, rgba) in img.pixels() {
+        let channels: [u8; 4] = rgba.channels().try_into().unwrap();
+        histogram.r[channels[0] as usize] += 1;
+        histogram.g[channels[1] as usize] += 1;
+        histogram.b[channels[2] as usize] += 1;
+        histogram.a[channels[3] as usize] += 1;
+
+        let values = PixelValues::from_rgba(channels);
+        brightness_sum += values.brightness;
+        if predicate.is_some_and(|(_, p)| p.matches(&values)) {
+            matching += 1;
+        }
+        if channels[3] > 0 {
+            opaque.push([channels[0], channels[1], channels[2]]);
+        }
+    }
+
+    let pixel_count = img.width() as u64 * img.height() as u64;
+    Analysis {
+        width: img.width(),
+        height: img.height(),
+        histogram,
+        average_brightness: if pixel_count == 0 {
+            0.0
+        } else {
+            brightness_sum / pixel_count as f64
+        },
+        dominant_colors: median_cut(opaque, colors),
+        predicate: predicate.map(|(expression, _)| PredicateCount {
+            expression: expression.to_string(),
+            count: matching,
+        }),
+    }
+}
+
+// Repeatedly splits the box with the widest channel range at its median
+pub fn median_cut(pixels: Vec<[u8; 3]>, colors: usize) -> Vec<DominantColor> {
+    let total = pixels.len();
+    if total == 0 || colors == 0 {
+        return Vec::new();
+    }
+
+    let range = |bucket: &[[u8; 3]]| {
+        (0..3)
+            .map(|c| {
+                let (min, max) = bucket.iter().fold((u8::MAX, u8::MIN), |(lo, hi), p| {
+                    (lo.min(p[c]), hi.max(p[c]))
+                });
+                (max - min, c)
+            })
+            .max()
+            .unwrap()
+    };
+
+    let mut buckets = vec![pixels];
+    while buckets.len() < colors {
+        let Some((index, (spread, channel))) = buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, bucket)| bucket.len() > 1)
+            .map(|(i, bucket)| (i, range(bucket)))
+            .max_by_key(|(_, (spread, _))| *spread)
+        else {
+            break;
+        };
+        if spread == 0 {
+            break;
+        }
+
+        let mut bucket = buckets.swap_remove(index);
+        bucket.sort_by_key(|p| p[channel]);
+        let upper = bucket.split_off(bucket.len() / 2);
+        buckets.push(bucket);
+        buckets.push(upper);
+    }
+
+    let mut dominant: Vec<DominantColor> = buckets
+        .into_iter()
+        .map(|bucket| {
+            let mut sums = [0_u64; 3];
+            for p in &bucket {
+                for c in 0..3 {
+                    sums[c] += p[c] as u64;
+                }
+            }
+            let rgb = sums.map(|sum| (sum as f64 / bucket.len() as f64).round() as u8);
+            DominantColor {
+                hex: format!("#{:02x}{:02x}{:02x}", rgb[0], rgb[1], rgb[2]),
+                rgb,
+                share: bucket.len() as f64 / total as f64,
+            }
+        })
+        .collect();
+    dominant.sort_by(|a, b| b.share.total_cmp(&a.share));
+    dominant
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_predicate_evaluation() {
+        let red = PixelValues::from_rgba([200, 20, 10, 255]);
+        let grey = PixelValues::from_rgba([100, 100, 100, 255]);
+
+        let reddish = Predicate::parse("hue between 0 and 20 and saturation > 0.5").unwrap();
+        assert!(reddish.matches(&red));
+        assert!(!reddish.matches(&grey));
+
+        let classic = Predicate::parse("r > g + b").unwrap();
+        assert!(classic.matches(&red));
+        assert!(!classic.matches(&grey));
+
+        let either = Predicate::parse("not (r >= 150) || (a == 255 && v < 0.5)").unwrap();
+        assert!(either.matches(&grey));
+        assert!(!either.matches(&red));
+    }
+
+    #[test]
+    fn test_predicate_errors() {
+        for input in ["", "r >", "hue between 1 20", "x > 1", "r > 1)", "r $ 2"] {
+            assert!(Predicate::parse(input).is_err(), "{}", input);
+        }
+
+        let nested = |depth: usize| format!("{}r > 1{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(Predicate::parse(&nested(MAX_DEPTH)).is_ok());
+        assert!(Predicate::parse(&nested(MAX_DEPTH + 1)).is_err());
+        assert!(Predicate::parse(&format!("{}r > 1", "not ".repeat(MAX_DEPTH + 1))).is_err());
+        assert!(Predicate::parse(&format!("{}r", "-".repeat(MAX_DEPTH + 1))).is_err());
+        assert!(Predicate::parse(&"(".repeat(100_000)).is_err());
+    }
+
+    #[test]
+    fn test_median_cut() {
+        let mut pixels = vec![[0, 0, 255]; 20];
+        pixels.extend(vec![[255, 0, 0]; 20]);
+
+        let colors = median_cut(pixels, 2);
+        let hexes: Vec<&str> = colors.iter().map(|c| c.hex.as_str()).collect();
+        assert_eq!(hexes, vec!["#ff0000", "#0000ff"]);
+        assert_eq!(colors[0].share, 0.5);
+
+        // A single colour cannot be split any further
+        assert_eq!(median_cut(vec![[1, 2, 3]; 5], 4).len(), 1);
+    }
+}
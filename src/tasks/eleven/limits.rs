@@ -0,0 +1,231 @@
+use crate::config::env_or;
+use image::error::LimitErrorKind;
+use image::io::{Limits, Reader as ImageReader};
+use image::{DynamicImage, ImageError, ImageFormat};
+use std::fmt;
+use std::io::Cursor;
+
+#[derive(Clone, Debug)]
+pub struct ImageLimits {
+    // Size of the encoded upload, enforced while the multipart field is read
+    pub max_input_bytes: usize,
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_pixels: u64,
+    // Memory the decoder may allocate for the pixel buffer
+    pub max_decoded_bytes: u64,
+    pub allowed_formats: Vec<ImageFormat>,
+    // Image parts accepted in a single batch request
+    pub max_batch_images: usize,
+    // Images of one batch decoded at the same time, which bounds the pixel buffers it holds
+    pub max_concurrent_decodes: usize,
+}
+
+impl Default for ImageLimits {
+    fn default() -> Self {
+        ImageLimits {
+            max_input_bytes: 10 * 1024 * 1024,
+            max_width: 8192,
+            max_height: 8192,
+            max_pixels: 32 * 1024 * 1024,
+            max_decoded_bytes: 256 * 1024 * 1024,
+            allowed_formats: vec![
+                ImageFormat::Png,
+                ImageFormat::Jpeg,
+                ImageFormat::Gif,
+                ImageFormat::WebP,
+                ImageFormat::Bmp,
+            ],
+            max_batch_images: 100,
+            max_concurrent_decodes: 4,
+        }
+    }
+}
+
+impl ImageLimits {
+    pub fn from_env() -> Self {
+        let default = ImageLimits::default();
+        ImageLimits {
+            max_input_bytes: env_or("IMAGE_MAX_INPUT_BYTES", default.max_input_bytes),
+            max_width: env_or("IMAGE_MAX_WIDTH", default.max_width),
+            max_height: env_or("IMAGE_MAX_HEIGHT", default.max_height),
+            max_pixels: env_or("IMAGE_MAX_PIXELS", default.max_pixels),
+            max_decoded_bytes: env_or("IMAGE_MAX_DECODED_BYTES", default.max_decoded_bytes),
+            allowed_formats: std::env::var("IMAGE_ALLOWED_FORMATS")
+                .map(|formats| {
+                    formats
+                        .split(',')
+                        .filter_map(|format| ImageFormat::from_extension(format.trim()))
+                        .collect()
+                })
+                .unwrap_or(default.allowed_formats),
+            max_batch_images: env_or("IMAGE_MAX_BATCH", default.max_batch_images),
+            max_concurrent_decodes: env_or(
+                "IMAGE_MAX_CONCURRENT_DECODES",
+                default.max_concurrent_decodes,
+            ),
+        }
+    }
+
+    // Format and dimensions come from the header alone, so oversized images are
+    // rejected before any pixel data is decoded
+    pub fn decode(&self, data: &[u8]) -> Result<DynamicImage, DecodeError> {
+        self.decode_with_format(data).map(|(img, _)| img)
+    }
+
+    pub fn decode_with_format(
+        &self,
+        data: &[u8],
+    ) -> Result<(DynamicImage, ImageFormat), DecodeError> {
+        let format = image::guess_format(data)
+            .map_err(|_| DecodeError::UnsupportedFormat("unknown".to_string()))?;
+        if !self.allowed_formats.contains(&format) {
+            return Err(DecodeError::UnsupportedFormat(
+                format.extensions_str()[0].to_string(),
+            ));
+        }
+
+        let (width, height) = ImageReader::with_format(Cursor::new(data), format)
+            .into_dimensions()
+            .map_err(DecodeError::from)?;
+        if width > self.max_width || height > self.max_height {
+            return Err(DecodeError::TooLarge(format!(
+                "Image is {}x{}, the limit is {}x{}",
+                width, height, self.max_width, self.max_height
+            )));
+        }
+        if width as u64 * height as u64 > self.max_pixels {
+            return Err(DecodeError::TooLarge(format!(
+                "Image has {} pixels, the limit is {}",
+                width as u64 * height as u64,
+                self.max_pixels
+            )));
+        }
+
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(self.max_width);
+        limits.max_image_height = Some(self.max_height);
+        limits.max_alloc = Some(self.max_decoded_bytes);
+
+        let mut reader = ImageReader::with_format(Cursor::new(data), format);
+        reader.limits(limits);
+        Ok((reader.decode().map_err(DecodeError::from)?, format))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    TooLarge(String),
+    UnsupportedFormat(String),
+    Invalid(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::TooLarge(message) | DecodeError::Invalid(message) => {
+                write!(f, "{}", message)
+            }
+            DecodeError::UnsupportedFormat(format) => {
+                write!(f, "Unsupported image format: {}", format)
+            }
+        }
+    }
+}
+
+impl From<ImageError> for DecodeError {
+    fn from(e: ImageError) -> Self {
+        match e {
+            ImageError::Limits(limit) => match limit.kind() {
+                LimitErrorKind::InsufficientMemory => {
+                    DecodeError::TooLarge("Decoded image would exceed the memory limit".to_string())
+                }
+                _ => DecodeError::TooLarge(limit.to_string()),
+            },
+            ImageError::Unsupported(e) => DecodeError::UnsupportedFormat(e.to_string()),
+            e => DecodeError::Invalid(e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // A valid PNG claiming the given size, with no pixel data at all
+    fn png_header(width: u32, height: u32) -> Vec<u8> {
+        let mut ihdr = width.to_be_bytes().to_vec();
+        ihdr.extend_from_slice(&height.to_be_bytes());
+        ihdr.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        for (kind, data) in [(b"IHDR", &ihdr[..]), (b"IDAT", &[]), (b"IEND", &[])] {
+            let mut chunk = kind.to_vec();
+            chunk.extend_from_slice(data);
+            png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            png.extend_from_slice(&chunk);
+            png.extend_from_slice(&crc32(&chunk).to_be_bytes());
+        }
+        png
+    }
+
+    fn crc32(data: &[u8]) -> u32 {
+        let mut crc = 0xffff_ffff_u32;
+        for byte in data {
+            crc ^= *byte as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ 0xedb8_8320
+                } else {
+                    crc >> 1
+                };
+            }
+        }
+        !crc
+    }
+
+    #[test]
+    fn test_rejects_bombs_before_decoding() {
+        let limits = ImageLimits::default();
+        assert!(matches!(
+            limits.decode(&png_header(100_000, 100_000)),
+            Err(DecodeError::TooLarge(_))
+        ));
+        assert!(matches!(
+            limits.decode(&png_header(8192, 8192)),
+            Err(DecodeError::TooLarge(_))
+        ));
+
+        let tight = ImageLimits {
+            max_decoded_bytes: 1024,
+            ..Default::default()
+        };
+        assert_eq!(
+            tight.decode(&png_header(100, 100)),
+            Err(DecodeError::TooLarge(
+                "Decoded image would exceed the memory limit".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_format_allow_list() {
+        let png_only = ImageLimits {
+            allowed_formats: vec![ImageFormat::Png],
+            ..Default::default()
+        };
+        let mut gif = Vec::new();
+        DynamicImage::new_rgba8(2, 2)
+            .write_to(&mut Cursor::new(&mut gif), image::ImageOutputFormat::Gif)
+            .unwrap();
+        assert_eq!(
+            png_only.decode(&gif),
+            Err(DecodeError::UnsupportedFormat("gif".to_string()))
+        );
+        assert!(ImageLimits::default().decode(&gif).is_ok());
+        assert!(matches!(
+            png_only.decode(b"plain text"),
+            Err(DecodeError::UnsupportedFormat(_))
+        ));
+    }
+}
@@ -0,0 +1,380 @@
+use crate::config::env_or;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+#[derive(Clone, Debug)]
+pub struct AssetStoreConfig {
+    // Uploads only, so listing and deleting never reach the files the service ships with
+    pub dir: PathBuf,
+    // Served read-only, and taking precedence over an upload of the same name
+    pub static_dir: PathBuf,
+    // Transformed images, kept out of listings by the leading dot
+    pub derived_dir: PathBuf,
+    pub max_upload_bytes: usize,
+    pub allowed_types: Vec<String>,
+}
+
+impl Default for AssetStoreConfig {
+    fn default() -> Self {
+        AssetStoreConfig {
+            dir: PathBuf::from("static/uploads"),
+            static_dir: PathBuf::from("static"),
+            derived_dir: PathBuf::from("static/.derived"),
+            max_upload_bytes: 10 * 1024 * 1024,
+            allowed_types: [
+                "image/png",
+                "image/jpeg",
+                "image/gif",
+                "image/webp",
+                "text/plain",
+            ]
+            .iter()
+            .map(|t| t.to_string())
+            .collect(),
+        }
+    }
+}
+
+impl AssetStoreConfig {
+    pub fn from_env() -> Self {
+        let default = AssetStoreConfig::default();
+        AssetStoreConfig {
+            dir: env_or("ASSETS_DIR", default.dir),
+            static_dir: env_or("ASSETS_STATIC_DIR", default.static_dir),
+            derived_dir: env_or("ASSETS_DERIVED_DIR", default.derived_dir),
+            max_upload_bytes: env_or("ASSETS_MAX_UPLOAD_BYTES", default.max_upload_bytes),
+            allowed_types: std::env::var("ASSETS_ALLOWED_TYPES")
+                .map(|types| types.split(',').map(|t| t.trim().to_string()).collect())
+                .unwrap_or(default.allowed_types),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Asset {
+    pub name: String,
+    pub size: u64,
+    pub mime: String,
+    pub sha256: String,
+    pub url: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AssetError {
+    InvalidName,
+    NotFound,
+    AlreadyExists,
+    TooLarge,
+    UnsupportedType(String),
+    Io(String),
+}
+
+impl From<io::Error> for AssetError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => AssetError::NotFound,
+            io::ErrorKind::AlreadyExists => AssetError::AlreadyExists,
+            _ => AssetError::Io(e.to_string()),
+        }
+    }
+}
+
+struct HashEntry {
+    modified: SystemTime,
+    size: u64,
+    sha256: String,
+}
+
+pub struct AssetStore {
+    config: AssetStoreConfig,
+    hashes: Mutex<HashMap<String, HashEntry>>,
+}
+
+// Only plain file names are accepted, so nothing can reach outside the storage directory
+pub fn validate_name(name: &str) -> Result<&str, AssetError> {
+    let valid = !name.is_empty()
+        && name.len() <= 255
+        && !name.starts_with('.')
+        && !name.contains("..")
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'));
+
+    if valid {
+        Ok(name)
+    } else {
+        Err(AssetError::InvalidName)
+    }
+}
+
+pub fn mime_for(name: &str) -> String {
+    mime_guess::from_path(name)
+        .first_or_octet_stream()
+        .essence_str()
+        .to_string()
+}
+
+pub fn hash_url(sha256: &str) -> String {
+    format!("/11/assets/by-hash/{}", sha256)
+}
+
+impl AssetStore {
+    pub fn new(config: AssetStoreConfig) -> Self {
+        AssetStore {
+            config,
+            hashes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn max_upload_bytes(&self) -> usize {
+        self.config.max_upload_bytes
+    }
+
+    pub fn path(&self, name: &str) -> Result<PathBuf, AssetError> {
+        let builtin = self.config.static_dir.join(validate_name(name)?);
+        if builtin.is_file() {
+            Ok(builtin)
+        } else {
+            self.upload_path(name)
+        }
+    }
+
+    fn upload_path(&self, name: &str) -> Result<PathBuf, AssetError> {
+        Ok(self.config.dir.join(validate_name(name)?))
+    }
+
+    // Hashes are cached per file and only recomputed once the file changes
+    pub fn list(&self) -> Result<Vec<Asset>, AssetError> {
+        let entries = match fs::read_dir(&self.config.dir) {
+            Ok(entries) => entries,
+            // Nothing has been uploaded yet
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut assets = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let Some(name) = entry.file_name().to_str().map(String::from) else {
+                continue;
+            };
+            if validate_name(&name).is_err() || !entry.file_type()?.is_file() {
+                continue;
+            }
+            assets.push(self.describe(&name, &entry.path())?);
+        }
+
+        assets.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(assets)
+    }
+
+    pub fn asset(&self, name: &str) -> Result<Asset, AssetError> {
+        let path = self.path(name)?;
+        if !fs::metadata(&path)?.is_file() {
+            return Err(AssetError::NotFound);
+        }
+        self.describe(name, &path)
+    }
+
+    pub fn find_by_hash(&self, sha256: &str) -> Result<Asset, AssetError> {
+        self.list()?
+            .into_iter()
+            .find(|asset| asset.sha256 == sha256)
+            .ok_or(AssetError::NotFound)
+    }
+
+    pub fn store(&self, name: &str, data: &[u8]) -> Result<Asset, AssetError> {
+        let path = self.upload_path(name)?;
+        if self.config.static_dir.join(name).exists() {
+            return Err(AssetError::AlreadyExists);
+        }
+        if data.len() > self.config.max_upload_bytes {
+            return Err(AssetError::TooLarge);
+        }
+
+        let mime = mime_for(name);
+        if !self.config.allowed_types.contains(&mime) {
+            return Err(AssetError::UnsupportedType(mime));
+        }
+        // The declared type of an image has to match what its bytes say it is
+        if mime.starts_with("image/") {
+            let detected = image::guess_format(data)
+                .map(|format| format.to_mime_type())
+                .unwrap_or("application/octet-stream");
+            if detected != mime {
+                return Err(AssetError::UnsupportedType(detected.to_string()));
+            }
+        }
+
+        fs::create_dir_all(&self.config.dir)?;
+        let mut file = tempfile::NamedTempFile::new_in(&self.config.dir)?;
+        file.write_all(data)?;
+        file.persist_noclobber(&path).map_err(|e| e.error)?;
+
+        self.describe(name, &path)
+    }
+
+    pub fn delete(&self, name: &str) -> Result<(), AssetError> {
+        fs::remove_file(self.upload_path(name)?)?;
+        self.hashes.lock().unwrap().remove(name);
+        Ok(())
+    }
+
+    // Derivatives are named by their cache key, so an existing file is always current
+    pub fn derived(&self, key: &str, extension: &str) -> Option<PathBuf> {
+        let path = self.derived_path(key, extension);
+        path.is_file().then_some(path)
+    }
+
+    pub fn store_derived(
+        &self,
+        key: &str,
+        extension: &str,
+        data: &[u8],
+    ) -> Result<PathBuf, AssetError> {
+        let path = self.derived_path(key, extension);
+        fs::create_dir_all(&self.config.derived_dir)?;
+        let mut file = tempfile::NamedTempFile::new_in(&self.config.derived_dir)?;
+        file.write_all(data)?;
+        // A concurrent request may have produced the same derivative first, which is fine
+        file.persist(&path).map_err(|e| e.error)?;
+        Ok(path)
+    }
+
+    fn derived_path(&self, key: &str, extension: &str) -> PathBuf {
+        self.config
+            .derived_dir
+            .join(format!("{}.{}", key, extension))
+    }
+
+    fn describe(&self, name: &str, path: &Path) -> Result<Asset, AssetError> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata.modified()?;
+        let size = metadata.len();
+
+        let cached = self
+            .hashes
+            .lock()
+            .unwrap()
+            .get(name)
+            .filter(|entry| entry.modified == modified && entry.size == size)
+            .map(|entry| entry.sha256.clone());
+        let sha256 = match cached {
+            Some(sha256) => sha256,
+            None => {
+                let sha256 = format!("{:x}", Sha256::digest(fs::read(path)?));
+                self.hashes.lock().unwrap().insert(
+                    name.to_string(),
+                    HashEntry {
+                        modified,
+                        size,
+                        sha256: sha256.clone(),
+                    },
+                );
+                sha256
+            }
+        };
+
+        Ok(Asset {
+            name: name.to_string(),
+            size,
+            mime: mime_for(name),
+            url: hash_url(&sha256),
+            sha256,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn store_in(dir: &Path) -> AssetStore {
+        AssetStore::new(AssetStoreConfig {
+            dir: dir.join("uploads"),
+            static_dir: dir.join("static"),
+            derived_dir: dir.join(".derived"),
+            max_upload_bytes: 16,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("decoration.png").is_ok());
+        for name in [
+            "",
+            "../Cargo.toml",
+            "a/b.png",
+            ".env",
+            "a\\b",
+            "..",
+            "x..png",
+            "%2e%2e",
+        ] {
+            assert_eq!(
+                validate_name(name),
+                Err(AssetError::InvalidName),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_store_list_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = store_in(dir.path());
+        assert!(store.list().unwrap().is_empty());
+
+        let asset = store.store("notes.txt", b"ho ho ho").unwrap();
+        assert_eq!(asset.mime, "text/plain");
+        assert_eq!(asset.sha256, format!("{:x}", Sha256::digest(b"ho ho ho")));
+        assert_eq!(store.list().unwrap(), vec![asset.clone()]);
+        assert_eq!(store.find_by_hash(&asset.sha256).unwrap(), asset);
+
+        assert_eq!(
+            store.store("notes.txt", b"again"),
+            Err(AssetError::AlreadyExists)
+        );
+        assert_eq!(
+            store.store("big.txt", &[b'a'; 17]),
+            Err(AssetError::TooLarge)
+        );
+        assert!(matches!(
+            store.store("fake.png", b"not a png"),
+            Err(AssetError::UnsupportedType(_))
+        ));
+        assert!(matches!(
+            store.store("run.sh", b"rm -rf"),
+            Err(AssetError::UnsupportedType(_))
+        ));
+
+        store.delete("notes.txt").unwrap();
+        assert_eq!(store.delete("notes.txt"), Err(AssetError::NotFound));
+        assert!(store.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_static_files_are_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = store_in(dir.path());
+        fs::create_dir_all(dir.path().join("static")).unwrap();
+        fs::write(dir.path().join("static/numbers.txt"), b"1 2 3").unwrap();
+
+        assert_eq!(store.asset("numbers.txt").unwrap().size, 5);
+        assert!(store.list().unwrap().is_empty());
+        assert_eq!(store.delete("numbers.txt"), Err(AssetError::NotFound));
+        assert_eq!(
+            store.store("numbers.txt", b"4 5 6"),
+            Err(AssetError::AlreadyExists)
+        );
+        assert!(dir.path().join("static/numbers.txt").is_file());
+    }
+}
@@ -0,0 +1,353 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageOutputFormat};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::io::Cursor;
+
+const MAX_DIMENSION: u32 = 4096;
+const JPEG_QUALITY: u8 = 85;
+
+#[derive(Deserialize, Default)]
+pub struct TransformQuery {
+    w: Option<u32>,
+    h: Option<u32>,
+    fit: Option<String>,
+    crop: Option<String>,
+    rotate: Option<u32>,
+    grayscale: Option<String>,
+    format: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fit {
+    // Scale to fit inside the box, keeping the aspect ratio
+    Contain,
+    // Scale to cover the box, then crop the overflow
+    Cover,
+    // Stretch to exactly the box
+    Fill,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Png,
+    Jpeg,
+    Gif,
+    Bmp,
+    Tiff,
+    WebP,
+}
+
+impl OutputFormat {
+    fn parse(value: &str) -> Result<OutputFormat, TransformError> {
+        match value.to_lowercase().as_str() {
+            "png" => Ok(OutputFormat::Png),
+            "jpg" | "jpeg" => Ok(OutputFormat::Jpeg),
+            "gif" => Ok(OutputFormat::Gif),
+            "bmp" => Ok(OutputFormat::Bmp),
+            "tif" | "tiff" => Ok(OutputFormat::Tiff),
+            "webp" => Ok(OutputFormat::WebP),
+            other => Err(TransformError::UnsupportedFormat(other.to_string())),
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Gif => "gif",
+            OutputFormat::Bmp => "bmp",
+            OutputFormat::Tiff => "tiff",
+            OutputFormat::WebP => "webp",
+        }
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "image/png",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Gif => "image/gif",
+            OutputFormat::Bmp => "image/bmp",
+            OutputFormat::Tiff => "image/tiff",
+            OutputFormat::WebP => "image/webp",
+        }
+    }
+
+    fn from_mime(mime: &str) -> Option<OutputFormat> {
+        match mime {
+            "image/png" => Some(OutputFormat::Png),
+            "image/jpeg" => Some(OutputFormat::Jpeg),
+            "image/gif" => Some(OutputFormat::Gif),
+            "image/bmp" => Some(OutputFormat::Bmp),
+            "image/tiff" => Some(OutputFormat::Tiff),
+            "image/webp" => Some(OutputFormat::WebP),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TransformError {
+    Invalid(String),
+    UnsupportedFormat(String),
+    Image(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transform {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: Fit,
+    // x, y, width, height in source pixels, applied before anything else
+    pub crop: Option<(u32, u32, u32, u32)>,
+    pub rotate: u32,
+    pub grayscale: bool,
+    pub format: Option<OutputFormat>,
+}
+
+impl Transform {
+    pub fn from_query(query: &TransformQuery) -> Result<Transform, TransformError> {
+        let invalid = |message: &str| TransformError::Invalid(message.to_string());
+
+        for dimension in [query.w, query.h].into_iter().flatten() {
+            if dimension == 0 || dimension > MAX_DIMENSION {
+                return Err(TransformError::Invalid(format!(
+                    "Width and height must be between 1 and {}",
+                    MAX_DIMENSION
+                )));
+            }
+        }
+
+        let fit = match query.fit.as_deref() {
+            None | Some("contain") => Fit::Contain,
+            Some("cover") => Fit::Cover,
+            Some("fill") => Fit::Fill,
+            Some(_) => return Err(invalid("fit must be one of contain, cover or fill")),
+        };
+
+        let crop = match &query.crop {
+            Some(crop) => {
+                let parts: Vec<u32> = crop
+                    .split(',')
+                    .map(|part| part.trim().parse())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| invalid("crop must be x,y,width,height"))?;
+                match parts[..] {
+                    [x, y, w, h] if w > 0 && h > 0 => Some((x, y, w, h)),
+                    _ => return Err(invalid("crop must be x,y,width,height")),
+                }
+            }
+            None => None,
+        };
+
+        let rotate = query.rotate.unwrap_or(0) % 360;
+        if !rotate.is_multiple_of(90) {
+            return Err(invalid("rotate must be a multiple of 90"));
+        }
+
+        let grayscale = match query.grayscale.as_deref() {
+            None | Some("0") | Some("false") => false,
+            Some("1") | Some("true") => true,
+            Some(_) => return Err(invalid("grayscale must be 0, 1, true or false")),
+        };
+
+        Ok(Transform {
+            width: query.w,
+            height: query.h,
+            fit,
+            crop,
+            rotate,
+            grayscale,
+            format: query
+                .format
+                .as_deref()
+                .map(OutputFormat::parse)
+                .transpose()?,
+        })
+    }
+
+    pub fn is_identity(&self) -> bool {
+        *self
+            == Transform {
+                width: None,
+                height: None,
+                fit: self.fit,
+                crop: None,
+                rotate: 0,
+                grayscale: false,
+                format: None,
+            }
+    }
+
+    // Output format, falling back to the source's own format where it can be encoded
+    pub fn output_format(&self, source_mime: &str) -> OutputFormat {
+        self.format
+            .or_else(|| OutputFormat::from_mime(source_mime))
+            .unwrap_or(OutputFormat::Png)
+    }
+
+    // Identifies a derivative by the source content and every parameter that shapes the output
+    pub fn cache_key(&self, source_sha256: &str, format: OutputFormat) -> String {
+        let canonical = format!(
+            "{}|w={:?}|h={:?}|fit={:?}|crop={:?}|rotate={}|grayscale={}|format={:?}",
+            source_sha256,
+            self.width,
+            self.height,
+            self.fit,
+            self.crop,
+            self.rotate,
+            self.grayscale,
+            format
+        );
+        format!("{:x}", Sha256::digest(canonical.as_bytes()))
+    }
+
+    pub fn apply(&self, mut img: DynamicImage) -> Result<DynamicImage, TransformError> {
+        if let Some((x, y, w, h)) = self.crop {
+            if x.saturating_add(w) > img.width() || y.saturating_add(h) > img.height() {
+                return Err(TransformError::Invalid(
+                    "crop lies outside the image".to_string(),
+                ));
+            }
+            img = img.crop_imm(x, y, w, h);
+        }
+
+        img = match self.rotate {
+            90 => img.rotate90(),
+            180 => img.rotate180(),
+            270 => img.rotate270(),
+            _ => img,
+        };
+
+        img = match (self.width, self.height) {
+            (None, None) => img,
+            (width, height) => {
+                // A single dimension scales the other one proportionally
+                let w = width.unwrap_or(u32::MAX);
+                let h = height.unwrap_or(u32::MAX);
+                match self.fit {
+                    Fit::Cover if width.is_some() && height.is_some() => {
+                        img.resize_to_fill(w, h, FilterType::Lanczos3)
+                    }
+                    Fit::Fill if width.is_some() && height.is_some() => {
+                        img.resize_exact(w, h, FilterType::Lanczos3)
+                    }
+                    _ => img.resize(w, h, FilterType::Lanczos3),
+                }
+            }
+        };
+
+        if self.grayscale {
+            img = img.grayscale();
+        }
+
+        Ok(img)
+    }
+}
+
+pub fn encode(img: &DynamicImage, format: OutputFormat) -> Result<Vec<u8>, TransformError> {
+    let mut bytes = Cursor::new(Vec::new());
+    let result = match format {
+        // JPEG has no alpha channel
+        OutputFormat::Jpeg => DynamicImage::ImageRgb8(img.to_rgb8())
+            .write_to(&mut bytes, ImageOutputFormat::Jpeg(JPEG_QUALITY)),
+        OutputFormat::Png => img.write_to(&mut bytes, ImageOutputFormat::Png),
+        OutputFormat::Gif => img.write_to(&mut bytes, ImageOutputFormat::Gif),
+        OutputFormat::Bmp => img.write_to(&mut bytes, ImageOutputFormat::Bmp),
+        OutputFormat::Tiff => img.write_to(&mut bytes, ImageOutputFormat::Tiff),
+        // image 0.24 only encodes WebP through libwebp, so the pure Rust encoder is used
+        // directly; it writes lossless files
+        OutputFormat::WebP => {
+            let rgba = img.to_rgba8();
+            image_webp::WebPEncoder::new(&mut bytes)
+                .encode(
+                    &rgba,
+                    rgba.width(),
+                    rgba.height(),
+                    image_webp::ColorType::Rgba8,
+                )
+                .map_err(|e| TransformError::Image(e.to_string()))?;
+            Ok(())
+        }
+    };
+    result.map_err(|e| TransformError::Image(e.to_string()))?;
+    Ok(bytes.into_inner())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::GenericImageView;
+
+    fn query(pairs: &str) -> Result<Transform, TransformError> {
+        let query = actix_web::web::Query::<TransformQuery>::from_query(pairs).unwrap();
+        Transform::from_query(&query)
+    }
+
+    #[test]
+    fn test_parse_transform() {
+        assert!(query("").unwrap().is_identity());
+        assert!(query("fit=cover").unwrap().is_identity());
+        assert!(!query("grayscale=1").unwrap().is_identity());
+
+        let transform = query("w=200&h=100&fit=cover&format=jpg&rotate=450&crop=1,2,3,4").unwrap();
+        assert_eq!(transform.fit, Fit::Cover);
+        assert_eq!(transform.rotate, 90);
+        assert_eq!(transform.crop, Some((1, 2, 3, 4)));
+        assert_eq!(transform.output_format("image/png"), OutputFormat::Jpeg);
+
+        assert!(query("w=0").is_err());
+        assert!(query("rotate=45").is_err());
+        assert!(query("crop=1,2,3").is_err());
+        assert_eq!(
+            query("format=webp").unwrap().format,
+            Some(OutputFormat::WebP)
+        );
+        assert_eq!(
+            query("format=avif"),
+            Err(TransformError::UnsupportedFormat("avif".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_apply_transform() {
+        let img = DynamicImage::new_rgba8(400, 200);
+
+        let contained = query("w=100&h=100").unwrap().apply(img.clone()).unwrap();
+        assert_eq!(contained.dimensions(), (100, 50));
+        let covered = query("w=100&h=100&fit=cover")
+            .unwrap()
+            .apply(img.clone())
+            .unwrap();
+        assert_eq!(covered.dimensions(), (100, 100));
+        let rotated = query("rotate=90&h=100")
+            .unwrap()
+            .apply(img.clone())
+            .unwrap();
+        assert_eq!(rotated.dimensions(), (50, 100));
+        let cropped = query("crop=10,10,50,20")
+            .unwrap()
+            .apply(img.clone())
+            .unwrap();
+        assert_eq!(cropped.dimensions(), (50, 20));
+        assert!(query("crop=390,0,20,20").unwrap().apply(img).is_err());
+    }
+
+    #[test]
+    fn test_cache_key_depends_on_parameters() {
+        let a = query("w=100").unwrap();
+        let b = query("w=101").unwrap();
+        assert_ne!(
+            a.cache_key("abc", OutputFormat::Png),
+            b.cache_key("abc", OutputFormat::Png)
+        );
+        assert_ne!(
+            a.cache_key("abc", OutputFormat::Png),
+            a.cache_key("abd", OutputFormat::Png)
+        );
+        assert_eq!(
+            a.cache_key("abc", OutputFormat::Png),
+            query("w=100").unwrap().cache_key("abc", OutputFormat::Png)
+        );
+    }
+}
@@ -1,64 +1,106 @@
-use actix_web::{post, web, HttpResponse, Responder};
-use askama::Template;
-use serde::Deserialize;
-
-#[derive(Template)]
-#[template(
-    source = "<html>\n  <head>\n    <title>CCH23 Day 14</title>\n  </head>\n  <body>\n    {{ content }}\n  </body>\n</html>",
-    ext = "html",
-    escape = "none"
-)]
-struct UnsafeTemplate {
-    content: String,
-}
+pub(crate) mod sanitize;
+pub(crate) mod templates;
 
-#[derive(Template)]
-#[template(
-    source = "<html>\n  <head>\n    <title>CCH23 Day 14</title>\n  </head>\n  <body>\n    {{ content }}\n  </body>\n</html>",
-    ext = "html",
-    escape = "html"
-)]
-struct SafeTemplate {
-    content: String,
-}
+use crate::tasks::fourteen::templates::RenderError;
+use crate::AppState;
+use actix_web::{error, post, web, Error, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 struct HtmlInput {
     content: String,
 }
 
-#[post("/14/unsafe")]
-async fn unsafe_endpoint(input: web::Json<HtmlInput>) -> impl Responder {
-    let template = UnsafeTemplate {
-        content: input.content.clone(),
-    };
+fn render<S: Serialize>(
+    state: &web::Data<AppState>,
+    name: &str,
+    context: S,
+) -> Result<HttpResponse, Error> {
+    let body = state.templates.render(name, context).map_err(|e| match e {
+        RenderError::NotFound => error::ErrorNotFound(format!("No template named {}", name)),
+        RenderError::Template(e) => error::ErrorInternalServerError(e),
+        RenderError::Context(e) => error::ErrorBadRequest(e),
+    })?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(mime_guess::from_path(name).first_or_text_plain())
+        .body(body))
+}
 
-    HttpResponse::Ok()
-        .content_type("text/html")
-        .body(template.render().unwrap())
+#[post("/14/unsafe")]
+async fn unsafe_endpoint(
+    state: web::Data<AppState>,
+    input: web::Json<HtmlInput>,
+) -> Result<HttpResponse, Error> {
+    render(&state, "unsafe.raw.html", input.into_inner())
 }
 
 #[post("/14/safe")]
-async fn safe_endpoint(input: web::Json<HtmlInput>) -> impl Responder {
-    let template = SafeTemplate {
-        content: input.content.clone(),
+async fn safe_endpoint(
+    state: web::Data<AppState>,
+    input: web::Json<HtmlInput>,
+) -> Result<HttpResponse, Error> {
+    render(&state, "safe.html", input.into_inner())
+}
+
+// Only the allow-listed markup survives, so the page template need not escape it
+#[post("/14/sanitized")]
+async fn sanitized_endpoint(
+    state: web::Data<AppState>,
+    input: web::Json<HtmlInput>,
+) -> Result<HttpResponse, Error> {
+    let input = HtmlInput {
+        content: state.sanitizer.clean(&input.content),
     };
+    render(&state, "sanitized.raw.html", input)
+}
 
-    HttpResponse::Ok()
-        .content_type("text/html")
-        .body(template.render().unwrap())
+// CommonMark with tables, sanitized like `/14/sanitized` and shown in the same page
+#[post("/14/markdown")]
+async fn markdown_endpoint(
+    state: web::Data<AppState>,
+    input: web::Json<HtmlInput>,
+) -> Result<HttpResponse, Error> {
+    let input = HtmlInput {
+        content: state.sanitizer.render_markdown(&input.content),
+    };
+    render(&state, "sanitized.raw.html", input)
+}
+
+// Any template under `templates/`, with the body as its context
+#[post("/14/render/{template}")]
+async fn render_template(
+    state: web::Data<AppState>,
+    template: web::Path<String>,
+    context: web::Json<Value>,
+) -> Result<HttpResponse, Error> {
+    if !context.is_object() {
+        return Err(error::ErrorBadRequest("The context must be a JSON object"));
+    }
+    render(&state, &template, context.into_inner())
 }
 
 #[cfg(test)]
 mod test {
-    use actix_web::{http::header, test, App};
+    use crate::config::AppConfig;
+    use actix_web::{http::header, http::StatusCode, test, App};
     use serde_json::json;
+    use sqlx::postgres::PgPoolOptions;
 
     use super::*;
 
+    fn set_up_state() -> web::Data<AppState> {
+        let pool = PgPoolOptions::new()
+            .connect_lazy("postgres://localhost/postgres")
+            .unwrap();
+        web::Data::new(AppState::new(pool, AppConfig::default()))
+    }
+
     #[actix_web::test]
     async fn test_unsafe_endpoint() {
-        let app = test::init_service(App::new().service(unsafe_endpoint)).await;
+        let app =
+            test::init_service(App::new().app_data(set_up_state()).service(unsafe_endpoint)).await;
 
         let input_data = json!({ "content": "<h1>Welcome to the North Pole!</h1>" });
         let req = test::TestRequest::post()
@@ -78,7 +120,8 @@ mod test {
 
     #[actix_web::test]
     async fn test_safe_endpoint() {
-        let app = test::init_service(App::new().service(safe_endpoint)).await;
+        let app =
+            test::init_service(App::new().app_data(set_up_state()).service(safe_endpoint)).await;
 
         let input_data = json!({ "content": "<script>alert(\"XSS Attack!\")</script>" });
         let req = test::TestRequest::post()
@@ -95,4 +138,116 @@ mod test {
 
         assert_eq!(body, expected_html);
     }
+
+    #[actix_web::test]
+    async fn test_sanitized_endpoint() {
+        let app = test::init_service(
+            App::new()
+                .app_data(set_up_state())
+                .service(sanitized_endpoint),
+        )
+        .await;
+
+        let content = concat!(
+            "<p onclick=\"steal()\"><b>Ho</b> <a href=\"https://shuttle.rs\" onmouseover=\"x()\">ho</a></p>",
+            "<script>alert(1)</script><a href=\"javascript:alert(1)\">click</a>",
+            "<ul><li>Sled</li></ul><img src=x onerror=alert(1)><marquee>hi</marquee>",
+        );
+        let req = test::TestRequest::post()
+            .uri("/14/sanitized")
+            .set_json(json!({ "content": content }))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert!(res.status().is_success());
+
+        let body = test::read_body(res).await;
+        let expected_html = concat!(
+            "<html>\n  <head>\n    <title>CCH23 Day 14</title>\n  </head>\n  <body>\n    ",
+            "<p><b>Ho</b> <a href=\"https://shuttle.rs\" rel=\"noopener noreferrer\">ho</a></p>",
+            "<a rel=\"noopener noreferrer\">click</a><ul><li>Sled</li></ul>hi",
+            "\n  </body>\n</html>",
+        );
+        assert_eq!(body, expected_html);
+    }
+
+    #[actix_web::test]
+    async fn test_markdown_endpoint() {
+        let app = test::init_service(
+            App::new()
+                .app_data(set_up_state())
+                .service(markdown_endpoint),
+        )
+        .await;
+
+        let content = concat!(
+            "# Sleigh *list*\n\n",
+            "| Gift | Qty |\n|------|-----|\n| Train | 3 |\n\n",
+            "```rust\nlet x = \"<b>\";\n```\n\n",
+            "<script>alert(1)</script>\n\n",
+            "[click](javascript:alert(1)) <span onclick=\"x()\">hi</span>\n",
+        );
+        let req = test::TestRequest::post()
+            .uri("/14/markdown")
+            .set_json(json!({ "content": content }))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert!(res.status().is_success());
+
+        let body = test::read_body(res).await;
+        let expected_html = concat!(
+            "<html>\n  <head>\n    <title>CCH23 Day 14</title>\n  </head>\n  <body>\n    ",
+            "<h1>Sleigh <em>list</em></h1>\n",
+            "<table><thead><tr><th>Gift</th><th>Qty</th></tr></thead><tbody>\n",
+            "<tr><td>Train</td><td>3</td></tr>\n</tbody></table>\n",
+            "<pre><code>let x = \"&lt;b&gt;\";\n</code></pre>\n\n",
+            "<p><a rel=\"noopener noreferrer\">click</a> hi</p>\n",
+            "\n  </body>\n</html>",
+        );
+        assert_eq!(body, expected_html);
+    }
+
+    #[actix_web::test]
+    async fn test_render_template() {
+        let app =
+            test::init_service(App::new().app_data(set_up_state()).service(render_template)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/14/render/gifts.html")
+            .set_json(json!({
+                "title": "Sleigh <1>",
+                "gifts": [{"name": "Toy Train", "quantity": 3}, {"name": "<b>Doll</b>"}]
+            }))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert!(res.status().is_success());
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/html"
+        );
+        let body = test::read_body(res).await;
+        assert_eq!(
+            body,
+            "<html>\n  <head>\n    <title>Sleigh &lt;1&gt;</title>\n  </head>\n  <body>\n    <ul>\n      <li>Toy Train &times; 3</li>\n      <li>&lt;b&gt;Doll&lt;/b&gt;</li>\n    </ul>\n  </body>\n</html>"
+        );
+
+        for (uri, body, status) in [
+            ("/14/render/missing.html", json!({}), StatusCode::NOT_FOUND),
+            (
+                "/14/render/gifts.html",
+                json!([1, 2]),
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                "/14/render/gifts.html",
+                json!({"gifts": 5}),
+                StatusCode::BAD_REQUEST,
+            ),
+        ] {
+            let req = test::TestRequest::post()
+                .uri(uri)
+                .set_json(body)
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), status);
+        }
+    }
 }
@@ -0,0 +1,328 @@
+use pulldown_cmark::{html, Event, Options, Parser};
+use std::collections::{HashMap, HashSet};
+
+// Their content is dropped along with them, so they can never be allowed
+const STRIPPED_TAGS: [&str; 2] = ["script", "style"];
+// URLs that can run script or smuggle in a document, whatever the operator allows
+const BLOCKED_SCHEMES: [&str; 3] = ["javascript", "vbscript", "data"];
+// Set on every link by the sanitizer itself
+const LINK_REL: &str = "noopener noreferrer";
+// Rendered Markdown is made of these, so they are allowed on top of the configured list
+const MARKDOWN_TAGS: [&str; 26] = [
+    "a",
+    "blockquote",
+    "br",
+    "code",
+    "del",
+    "em",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "img",
+    "li",
+    "ol",
+    "p",
+    "pre",
+    "strong",
+    "table",
+    "tbody",
+    "td",
+    "th",
+    "thead",
+    "tr",
+    "ul",
+];
+const MARKDOWN_ATTRIBUTES: [(&str, &str); 6] = [
+    ("a", "href"),
+    ("a", "title"),
+    ("img", "src"),
+    ("img", "alt"),
+    ("img", "title"),
+    ("ol", "start"),
+];
+// Mark where one raw HTML fragment of a Markdown source ends and the next begins
+const PLACEHOLDER_OPEN: char = '\u{E000}';
+const PLACEHOLDER_CLOSE: char = '\u{E001}';
+
+// The allow-list for `/14/sanitized`. Anything it does not name is removed, which covers
+// scripts, event handler attributes and `javascript:` URLs.
+#[derive(Clone, Debug)]
+pub struct SanitizerConfig {
+    pub tags: Vec<String>,
+    // Tag and attribute pairs, where the tag `*` allows the attribute everywhere
+    pub attributes: Vec<(String, String)>,
+    pub url_schemes: Vec<String>,
+}
+
+fn strings(values: &[&str]) -> Vec<String> {
+    values.iter().map(|value| value.to_string()).collect()
+}
+
+impl Default for SanitizerConfig {
+    fn default() -> Self {
+        SanitizerConfig {
+            tags: strings(&[
+                "a",
+                "b",
+                "blockquote",
+                "br",
+                "code",
+                "em",
+                "i",
+                "li",
+                "ol",
+                "p",
+                "pre",
+                "strong",
+                "u",
+                "ul",
+            ]),
+            attributes: vec![
+                ("a".to_string(), "href".to_string()),
+                ("*".to_string(), "title".to_string()),
+            ],
+            url_schemes: strings(&["http", "https", "mailto"]),
+        }
+    }
+}
+
+fn env_list(key: &str) -> Option<Vec<String>> {
+    std::env::var(key).ok().map(|list| {
+        list.split(',')
+            .map(|item| item.trim().to_ascii_lowercase())
+            .filter(|item| !item.is_empty())
+            .collect()
+    })
+}
+
+impl SanitizerConfig {
+    pub fn from_env() -> Self {
+        let default = SanitizerConfig::default();
+        SanitizerConfig {
+            tags: env_list("SANITIZE_TAGS").unwrap_or(default.tags),
+            attributes: env_list("SANITIZE_ATTRIBUTES")
+                .map(|pairs| {
+                    pairs
+                        .iter()
+                        .filter_map(|pair| pair.split_once(':'))
+                        .map(|(tag, attribute)| (tag.to_string(), attribute.to_string()))
+                        .collect()
+                })
+                .unwrap_or(default.attributes),
+            url_schemes: env_list("SANITIZE_URL_SCHEMES").unwrap_or(default.url_schemes),
+        }
+    }
+}
+
+pub struct Sanitizer {
+    config: SanitizerConfig,
+}
+
+impl Sanitizer {
+    // Entries the sanitizer manages itself are dropped, since ammonia refuses to run with them
+    pub fn new(mut config: SanitizerConfig) -> Self {
+        config
+            .tags
+            .retain(|tag| !STRIPPED_TAGS.contains(&tag.as_str()));
+        // Event handlers and inline styles can run script whatever the operator allows
+        config.attributes.retain(|(tag, attribute)| {
+            let attribute = attribute.to_ascii_lowercase();
+            !STRIPPED_TAGS.contains(&tag.as_str())
+                && attribute != "rel"
+                && attribute != "style"
+                && !attribute.starts_with("on")
+        });
+        config
+            .url_schemes
+            .retain(|scheme| !BLOCKED_SCHEMES.contains(&scheme.to_ascii_lowercase().as_str()));
+        Sanitizer { config }
+    }
+
+    pub fn clean(&self, html: &str) -> String {
+        self.clean_with(html, &[], &[])
+    }
+
+    // CommonMark with tables and strikethrough. Raw HTML in the source only gets the configured allow-list, so
+    // the Markdown tags are allowed just where Markdown syntax produced them.
+    pub fn render_markdown(&self, markdown: &str) -> String {
+        let mut events = Vec::new();
+        let mut raw: Vec<String> = Vec::new();
+        let mut after_raw = false;
+        for event in Parser::new_ext(
+            markdown,
+            Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH,
+        ) {
+            match event {
+                Event::Html(html) | Event::InlineHtml(html) => {
+                    // The lines of one HTML block are a single fragment
+                    match raw.last_mut() {
+                        Some(fragment) if after_raw => fragment.push_str(&html),
+                        _ => {
+                            raw.push(html.to_string());
+                            events.push(Event::Html("".into()));
+                        }
+                    }
+                    after_raw = true;
+                }
+                event => {
+                    after_raw = false;
+                    events.push(event);
+                }
+            }
+        }
+
+        let mut cleaned = self.clean_fragments(&raw).into_iter();
+        let events = events.into_iter().map(|event| match event {
+            Event::Html(_) => Event::Html(cleaned.next().unwrap_or_default().into()),
+            event => event,
+        });
+        let mut rendered = String::new();
+        html::push_html(&mut rendered, events);
+        self.clean_with(&rendered, &MARKDOWN_TAGS, &MARKDOWN_ATTRIBUTES)
+    }
+
+    // The fragments are cleaned as one document, so a tag opened in one and closed in another
+    // stays paired, then split apart again at numbered placeholders. A fragment whose
+    // placeholder did not survive cleaning is merged into the one before it.
+    fn clean_fragments(&self, fragments: &[String]) -> Vec<String> {
+        let mut joined = String::new();
+        for (index, fragment) in fragments.iter().enumerate() {
+            if index > 0 {
+                joined.push_str(&format!(
+                    "{}{}{}",
+                    PLACEHOLDER_OPEN, index, PLACEHOLDER_CLOSE
+                ));
+            }
+            joined.push_str(&fragment.replace([PLACEHOLDER_OPEN, PLACEHOLDER_CLOSE], "\u{FFFD}"));
+        }
+
+        let mut cleaned = vec![String::new(); fragments.len()];
+        let mut current = 0;
+        let output = self.clean(&joined);
+        let mut rest = output.as_str();
+        while let Some(start) = rest.find(PLACEHOLDER_OPEN) {
+            let after = &rest[start + PLACEHOLDER_OPEN.len_utf8()..];
+            let Some((index, tail)) = after
+                .split_once(PLACEHOLDER_CLOSE)
+                .and_then(|(index, tail)| Some((index.parse::<usize>().ok()?, tail)))
+            else {
+                break;
+            };
+            if let Some(fragment) = cleaned.get_mut(current) {
+                fragment.push_str(&rest[..start]);
+            }
+            current = current.max(index);
+            rest = tail;
+        }
+        if let Some(fragment) = cleaned.get_mut(current) {
+            fragment.push_str(rest);
+        }
+        cleaned
+    }
+
+    fn clean_with(&self, html: &str, tags: &[&str], attributes: &[(&str, &str)]) -> String {
+        let mut tag_attributes: HashMap<&str, HashSet<&str>> = HashMap::new();
+        let mut generic_attributes = HashSet::new();
+        for (tag, attribute) in &self.config.attributes {
+            if tag == "*" {
+                generic_attributes.insert(attribute.as_str());
+            } else {
+                tag_attributes
+                    .entry(tag.as_str())
+                    .or_default()
+                    .insert(attribute.as_str());
+            }
+        }
+        for (tag, attribute) in attributes {
+            tag_attributes.entry(tag).or_default().insert(attribute);
+        }
+
+        ammonia::Builder::default()
+            .tags(
+                self.config
+                    .tags
+                    .iter()
+                    .map(String::as_str)
+                    .chain(tags.iter().copied())
+                    .collect(),
+            )
+            .tag_attributes(tag_attributes)
+            .generic_attributes(generic_attributes)
+            .url_schemes(self.config.url_schemes.iter().map(String::as_str).collect())
+            .link_rel(Some(LINK_REL))
+            .clean(html)
+            .to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sanitizer_config() {
+        let sanitizer = Sanitizer::new(SanitizerConfig {
+            tags: strings(&["a", "i", "script", "img"]),
+            attributes: vec![
+                ("img".to_string(), "src".to_string()),
+                ("a".to_string(), "href".to_string()),
+                ("*".to_string(), "rel".to_string()),
+                ("*".to_string(), "onclick".to_string()),
+                ("img".to_string(), "OnError".to_string()),
+                ("i".to_string(), "style".to_string()),
+            ],
+            url_schemes: strings(&["https", "JavaScript", "data"]),
+        });
+        assert_eq!(
+            sanitizer
+                .clean("<a href=\"javascript:x()\">Elf</a><img src=\"data:image/png;base64,AA\">"),
+            "<a rel=\"noopener noreferrer\">Elf</a><img>"
+        );
+        assert_eq!(
+            sanitizer.clean(
+                "<i rel=\"x\" style=\"color:red\" onclick=\"x()\">Elf</i><b>Bold</b><script>x</script><img src=\"https://n.p/a.png\" onerror=\"x()\"><img src=\"http://n.p/b.png\">"
+            ),
+            "<i>Elf</i>Bold<img src=\"https://n.p/a.png\"><img>"
+        );
+    }
+
+    #[test]
+    fn test_markdown_raw_html() {
+        let sanitizer = Sanitizer::new(SanitizerConfig::default());
+        assert_eq!(
+            sanitizer.render_markdown(concat!(
+                "![Sled](https://n.p/sled.png) <img src=\"https://n.p/raw.png\">\n\n",
+                "<table><tr><td>Raw</td></tr></table>\n\n",
+                "| Gift |\n|------|\n| Kite |\n",
+            )),
+            concat!(
+                "<p><img src=\"https://n.p/sled.png\" alt=\"Sled\"> </p>\n",
+                "Raw\n",
+                "<table><thead><tr><th>Gift</th></tr></thead><tbody>\n",
+                "<tr><td>Kite</td></tr>\n</tbody></table>\n",
+            )
+        );
+        assert_eq!(
+            sanitizer.render_markdown("Some <b>bold</b> text"),
+            "<p>Some <b>bold</b> text</p>\n"
+        );
+        assert_eq!(
+            sanitizer.render_markdown("<ul>\n<li>raw <h1>x</h1></li>\n</ul>\n\n# Title"),
+            "<ul>\n<li>raw x</li>\n</ul>\n<h1>Title</h1>\n"
+        );
+
+        // Lists and strikethrough do not depend on the configured tags
+        let sanitizer = Sanitizer::new(SanitizerConfig {
+            tags: strings(&["p"]),
+            ..Default::default()
+        });
+        assert_eq!(
+            sanitizer.render_markdown("- one\n- ~~two~~\n\n<ul><li>raw</li></ul>"),
+            "<ul>\n<li>one</li>\n<li><del>two</del></li>\n</ul>\nraw"
+        );
+    }
+}
@@ -0,0 +1,98 @@
+use minijinja::value::Value;
+use minijinja::{
+    default_auto_escape_callback, escape_formatter, path_loader, AutoEscape, Environment, Error,
+    ErrorKind, Output, State,
+};
+use serde::Serialize;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+#[derive(Clone, Debug)]
+pub struct TemplateConfig {
+    pub dir: PathBuf,
+}
+
+impl Default for TemplateConfig {
+    fn default() -> Self {
+        TemplateConfig {
+            dir: PathBuf::from("templates"),
+        }
+    }
+}
+
+impl TemplateConfig {
+    pub fn from_env() -> Self {
+        TemplateConfig {
+            dir: std::env::var("TEMPLATES_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| TemplateConfig::default().dir),
+        }
+    }
+}
+
+// The escaping policy belongs to the template's file name: `.raw.html` leaves values as
+// given, and everything else follows minijinja, which escapes `.html` for HTML
+fn escape_policy(name: &str) -> AutoEscape {
+    if name.ends_with(".raw.html") {
+        AutoEscape::None
+    } else {
+        default_auto_escape_callback(name)
+    }
+}
+
+// askama's HTML escaping, which unlike minijinja's leaves `/` alone, so the /14 pages keep
+// the bytes they always had
+fn html_formatter(out: &mut Output, state: &State, value: &Value) -> Result<(), Error> {
+    match value.as_str() {
+        Some(text) if state.auto_escape() == AutoEscape::Html && !value.is_safe() => {
+            for c in text.chars() {
+                match c {
+                    '<' => out.write_str("&lt;")?,
+                    '>' => out.write_str("&gt;")?,
+                    '&' => out.write_str("&amp;")?,
+                    '"' => out.write_str("&quot;")?,
+                    '\'' => out.write_str("&#x27;")?,
+                    c => out.write_char(c)?,
+                }
+            }
+            Ok(())
+        }
+        _ => escape_formatter(out, state, value),
+    }
+}
+
+pub enum RenderError {
+    NotFound,
+    // The template itself is broken
+    Template(String),
+    // The context did not suit the template
+    Context(String),
+}
+
+// Templates are read from disk on first use and kept compiled afterwards
+pub struct Templates {
+    env: Environment<'static>,
+}
+
+impl Templates {
+    pub fn new(config: TemplateConfig) -> Self {
+        let mut env = Environment::new();
+        env.set_loader(path_loader(config.dir));
+        env.set_auto_escape_callback(escape_policy);
+        env.set_formatter(html_formatter);
+        Templates { env }
+    }
+
+    pub fn render<S: Serialize>(&self, name: &str, context: S) -> Result<String, RenderError> {
+        self.env
+            .get_template(name)
+            .and_then(|template| template.render(context))
+            .map_err(|e| match e.kind() {
+                ErrorKind::TemplateNotFound => RenderError::NotFound,
+                ErrorKind::SyntaxError | ErrorKind::BadInclude => {
+                    RenderError::Template(format!("{:#}", e))
+                }
+                _ => RenderError::Context(e.to_string()),
+            })
+    }
+}
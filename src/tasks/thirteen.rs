@@ -1,13 +1,28 @@
-use crate::AppState;
-use actix_web::{error, get, post, web, Error, HttpResponse, Responder};
-use serde::{Deserialize, Serialize};
+pub(crate) mod bulk;
+pub(crate) mod copy;
+pub(crate) mod feed;
+pub(crate) mod tenants;
+pub(crate) mod totals;
+pub(crate) mod trends;
+
+use crate::tasks::thirteen::bulk::{int_column, BulkQuery, BulkRow};
+use crate::tasks::thirteen::feed::Change;
+use crate::tasks::thirteen::tenants::{NewTenant, Tenant};
+use crate::tasks::thirteen::trends::{TimeRange, Trend, TrendQuery};
+use crate::tasks::twelve::analytics::parse_tz;
+use actix_web::{delete, error, get, post, put, web, Error, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::json;
-use sqlx::{Executor, Row};
+use sqlx::postgres::PgArguments;
+use sqlx::query::Query;
+use sqlx::{Executor, Postgres, QueryBuilder, Row};
 
 #[get("/13/sql")]
-async fn sql(state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+async fn sql(tenant: Tenant) -> Result<HttpResponse, Error> {
     let row = sqlx::query("SELECT 20231213;")
-        .fetch_one(&state.pool)
+        .fetch_one(&tenant.pool)
         .await
         .map_err(|e| error::ErrorBadRequest(e.to_string()))?;
 
@@ -15,73 +30,362 @@ async fn sql(state: web::Data<AppState>) -> Result<HttpResponse, Error> {
 }
 
 #[post("/13/reset")]
-async fn reset(state: web::Data<AppState>) -> impl Responder {
-    let res = state
-        .pool
-        .execute(
-            "DROP TABLE IF EXISTS orders;
+async fn reset(NewTenant(tenant): NewTenant) -> impl Responder {
+    let schema = format!(
+        "DROP TABLE IF EXISTS orders;
+        DROP TABLE IF EXISTS order_totals;
+        DROP TABLE IF EXISTS idempotency_keys;
         CREATE TABLE orders (
             id INT PRIMARY KEY,
             region_id INT,
             gift_name VARCHAR(50),
-            quantity INT
-        );",
-        )
-        .await;
+            quantity INT,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        );
+        {}",
+        totals::SCHEMA
+    );
+    let res = tenant.pool.execute(schema.as_str()).await;
 
-    if let Ok(_) = res {
+    if res.is_ok() {
         HttpResponse::Ok().body("Orders reset!")
     } else {
         HttpResponse::InternalServerError().body("Failed to reset orders!")
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, sqlx::FromRow)]
 pub struct Order {
-    id: i64,
-    region_id: i64,
-    gift_name: String,
-    quantity: i64,
+    pub(crate) id: i64,
+    pub(crate) region_id: i64,
+    pub(crate) gift_name: String,
+    pub(crate) quantity: i64,
+    // Orders sent without one are stamped by the server
+    #[serde(
+        default,
+        deserialize_with = "optional_timestamp",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) created_at: Option<DateTime<Utc>>,
+}
+
+// Accepts RFC 3339 as well as the form Postgres exports, and treats blank as missing
+fn optional_timestamp<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<DateTime<Utc>>, D::Error> {
+    let value = match Option::<String>::deserialize(deserializer)? {
+        Some(value) if !value.trim().is_empty() => value,
+        _ => return Ok(None),
+    };
+    DateTime::parse_from_rfc3339(value.trim())
+        .or_else(|_| DateTime::parse_from_str(value.trim(), "%Y-%m-%d %H:%M:%S%.f%#z"))
+        .map(|timestamp| Some(timestamp.with_timezone(&Utc)))
+        .map_err(|e| D::Error::custom(format!("Invalid created_at {}: {}", value, e)))
+}
+
+impl BulkRow for Order {
+    const TABLE: &'static str = "orders";
+    const COLUMNS: &'static [&'static str] =
+        &["id", "region_id", "gift_name", "quantity", "created_at"];
+    const DEFAULTED: &'static [&'static str] = &["created_at"];
+
+    fn id(&self) -> i64 {
+        self.id
+    }
+
+    fn bind<'q>(
+        &'q self,
+        query: Query<'q, Postgres, PgArguments>,
+    ) -> Query<'q, Postgres, PgArguments> {
+        query
+            .bind(self.id)
+            .bind(self.region_id)
+            .bind(&self.gift_name)
+            .bind(self.quantity)
+            .bind(self.created_at)
+    }
+
+    fn fill_defaults(&mut self) {
+        self.created_at.get_or_insert_with(Utc::now);
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        int_column("id", self.id)?;
+        int_column("region_id", self.region_id)?;
+        int_column("quantity", self.quantity)?;
+        if self.gift_name.chars().count() > MAX_GIFT_NAME_LEN {
+            return Err(format!(
+                "gift_name is limited to {} characters",
+                MAX_GIFT_NAME_LEN
+            ));
+        }
+        Ok(())
+    }
 }
 
 pub async fn add_orders(
-    state: web::Data<AppState>,
+    req: HttpRequest,
+    tenant: Tenant,
+    query: web::Query<BulkQuery>,
     orders: web::Json<Vec<Order>>,
 ) -> Result<HttpResponse, Error> {
-    let mut transaction = state
-        .pool
-        .begin()
+    let (response, written) =
+        bulk::bulk_insert(&tenant.pool, &req, query.on_conflict, orders.into_inner()).await?;
+    feed::publish(&tenant, Change::Written(written));
+    Ok(response)
+}
+
+// The columns are INT, so they are widened to match `Order`
+pub(crate) const ORDER_COLUMNS: &str = "SELECT id::BIGINT AS id, region_id::BIGINT AS region_id, gift_name, quantity::BIGINT AS quantity, created_at FROM orders";
+const DEFAULT_PAGE_SIZE: i64 = 20;
+const MAX_PAGE_SIZE: i64 = 100;
+const MAX_GIFT_NAME_LEN: usize = 50;
+
+fn order_id(id: web::Path<i64>) -> Result<i64, Error> {
+    let id = id.into_inner();
+    int_column("id", id).map_err(error::ErrorBadRequest)?;
+    Ok(id)
+}
+
+fn database_error(e: sqlx::Error) -> Error {
+    match &e {
+        sqlx::Error::Database(db) if db.is_unique_violation() => {
+            error::ErrorConflict("An order with this id already exists")
+        }
+        sqlx::Error::Database(db) if db.is_foreign_key_violation() => {
+            error::ErrorUnprocessableEntity("The order's region does not exist")
+        }
+        _ => error::ErrorInternalServerError(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct OrderListQuery {
+    region_id: Option<i64>,
+    gift_name: Option<String>,
+    min_quantity: Option<i64>,
+    max_quantity: Option<i64>,
+    // A column name, prefixed with `-` for descending order
+    sort: Option<String>,
+    page: Option<i64>,
+    per_page: Option<i64>,
+}
+
+impl OrderListQuery {
+    fn push_filters<'a>(&'a self, query: &mut QueryBuilder<'a, Postgres>) {
+        query.push(" WHERE TRUE");
+        if let Some(region_id) = self.region_id {
+            query.push(" AND region_id = ").push_bind(region_id);
+        }
+        if let Some(gift_name) = &self.gift_name {
+            query.push(" AND gift_name = ").push_bind(gift_name);
+        }
+        if let Some(min_quantity) = self.min_quantity {
+            query.push(" AND quantity >= ").push_bind(min_quantity);
+        }
+        if let Some(max_quantity) = self.max_quantity {
+            query.push(" AND quantity <= ").push_bind(max_quantity);
+        }
+    }
+
+    // Only known columns are accepted, so the result can be spliced into the SQL
+    fn order_by(&self) -> Result<String, Error> {
+        let sort = self.sort.as_deref().unwrap_or("id");
+        let (column, direction) = match sort.strip_prefix('-') {
+            Some(column) => (column, "DESC"),
+            None => (sort, "ASC"),
+        };
+        match column {
+            "id" => Ok(format!("id {}", direction)),
+            "region_id" | "gift_name" | "quantity" => {
+                Ok(format!("{} {}, id ASC", column, direction))
+            }
+            _ => Err(error::ErrorBadRequest(format!("Cannot sort by {}", column))),
+        }
+    }
+}
+
+#[get("/orders")]
+async fn list_orders(
+    tenant: Tenant,
+    query: web::Query<OrderListQuery>,
+) -> Result<HttpResponse, Error> {
+    let page = query.page.unwrap_or(1);
+    let per_page = query.per_page.unwrap_or(DEFAULT_PAGE_SIZE);
+    if page < 1 || !(1..=MAX_PAGE_SIZE).contains(&per_page) {
+        return Err(error::ErrorBadRequest(format!(
+            "page must be at least 1 and per_page between 1 and {}",
+            MAX_PAGE_SIZE
+        )));
+    }
+    let order_by = query.order_by()?;
+    let offset = (page - 1)
+        .checked_mul(per_page)
+        .ok_or_else(|| error::ErrorBadRequest("page is too large"))?;
+
+    let mut count = QueryBuilder::new("SELECT COUNT(*) FROM orders");
+    query.push_filters(&mut count);
+    let total: i64 = count
+        .build_query_scalar()
+        .fetch_one(&tenant.pool)
         .await
-        .map_err(|e| error::ErrorInternalServerError(e))?;
+        .map_err(database_error)?;
 
-    for order in orders.into_inner() {
-        sqlx::query(
-            "INSERT INTO orders (id, region_id, gift_name, quantity) VALUES ($1, $2, $3, $4)",
-        )
-        .bind(order.id)
-        .bind(order.region_id)
-        .bind(order.gift_name)
-        .bind(order.quantity)
-        .execute(&mut *transaction)
+    let mut select = QueryBuilder::new(ORDER_COLUMNS);
+    query.push_filters(&mut select);
+    select
+        .push(" ORDER BY ")
+        .push(order_by)
+        .push(" LIMIT ")
+        .push_bind(per_page)
+        .push(" OFFSET ")
+        .push_bind(offset);
+    let orders: Vec<Order> = select
+        .build_query_as()
+        .fetch_all(&tenant.pool)
         .await
-        .map_err(|e| error::ErrorInternalServerError(e))?;
+        .map_err(database_error)?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "orders": orders,
+        "page": page,
+        "per_page": per_page,
+        "total": total
+    })))
+}
+
+#[post("/orders")]
+async fn create_order(tenant: Tenant, order: web::Json<Order>) -> Result<HttpResponse, Error> {
+    let mut order = order.into_inner();
+    order.validate().map_err(error::ErrorBadRequest)?;
+    order.fill_defaults();
+    order
+        .bind(sqlx::query(
+            "INSERT INTO orders (id, region_id, gift_name, quantity, created_at)
+            VALUES ($1, $2, $3, $4, $5)",
+        ))
+        .execute(&tenant.pool)
+        .await
+        .map_err(database_error)?;
+
+    feed::publish(&tenant, Change::Written(vec![order.id]));
+    Ok(HttpResponse::Created().json(order))
+}
+
+#[get("/orders/{id}")]
+async fn get_order(tenant: Tenant, id: web::Path<i64>) -> Result<HttpResponse, Error> {
+    let id = order_id(id)?;
+    let order: Option<Order> = sqlx::query_as(&format!("{} WHERE id = $1", ORDER_COLUMNS))
+        .bind(id)
+        .fetch_optional(&tenant.pool)
+        .await
+        .map_err(database_error)?;
+
+    match order {
+        Some(order) => Ok(HttpResponse::Ok().json(order)),
+        None => Err(error::ErrorNotFound("Order not found")),
+    }
+}
+
+#[derive(Deserialize)]
+struct OrderUpdate {
+    // Optional, but has to match the path when given
+    id: Option<i64>,
+    region_id: i64,
+    gift_name: String,
+    quantity: i64,
+    // Left unchanged when missing
+    created_at: Option<DateTime<Utc>>,
+}
+
+#[put("/orders/{id}")]
+async fn update_order(
+    tenant: Tenant,
+    id: web::Path<i64>,
+    update: web::Json<OrderUpdate>,
+) -> Result<HttpResponse, Error> {
+    let id = order_id(id)?;
+    if update.id.is_some_and(|body_id| body_id != id) {
+        return Err(error::ErrorBadRequest(
+            "The id in the body does not match the path",
+        ));
+    }
+    int_column("region_id", update.region_id).map_err(error::ErrorBadRequest)?;
+    int_column("quantity", update.quantity).map_err(error::ErrorBadRequest)?;
+    if update.gift_name.chars().count() > MAX_GIFT_NAME_LEN {
+        return Err(error::ErrorBadRequest(format!(
+            "gift_name is limited to {} characters",
+            MAX_GIFT_NAME_LEN
+        )));
+    }
+
+    let order: Option<Order> = sqlx::query_as(
+        "UPDATE orders
+        SET region_id = $2, gift_name = $3, quantity = $4, created_at = COALESCE($5, created_at)
+        WHERE id = $1
+        RETURNING id::BIGINT AS id, region_id::BIGINT AS region_id, gift_name, quantity::BIGINT AS quantity, created_at",
+    )
+    .bind(id)
+    .bind(update.region_id)
+    .bind(&update.gift_name)
+    .bind(update.quantity)
+    .bind(update.created_at)
+    .fetch_optional(&tenant.pool)
+    .await
+    .map_err(database_error)?;
+
+    match order {
+        Some(order) => {
+            feed::publish(&tenant, Change::Written(vec![order.id]));
+            Ok(HttpResponse::Ok().json(order))
+        }
+        None => Err(error::ErrorNotFound("Order not found")),
     }
+}
 
-    transaction
-        .commit()
+#[delete("/orders/{id}")]
+async fn delete_order(tenant: Tenant, id: web::Path<i64>) -> Result<HttpResponse, Error> {
+    let id = order_id(id)?;
+    let deleted = sqlx::query("DELETE FROM orders WHERE id = $1")
+        .bind(id)
+        .execute(&tenant.pool)
         .await
-        .map_err(|e| error::ErrorInternalServerError(e))?;
+        .map_err(database_error)?
+        .rows_affected();
 
-    Ok(HttpResponse::Ok().body("Successfully added orders!"))
+    if deleted == 0 {
+        return Err(error::ErrorNotFound("Order not found"));
+    }
+    feed::publish(&tenant, Change::Deleted(id));
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[post("/13/orders/import")]
+async fn import_orders(
+    tenant: Tenant,
+    query: web::Query<BulkQuery>,
+    payload: web::Payload,
+) -> Result<HttpResponse, Error> {
+    let response = copy::import_csv::<Order>(&tenant.pool, payload, query.on_conflict).await?;
+    if response.status().is_success() {
+        feed::publish(&tenant, Change::Imported);
+    }
+    Ok(response)
+}
+
+#[get("/13/orders/export")]
+async fn export_orders(tenant: Tenant) -> Result<HttpResponse, Error> {
+    copy::export_csv::<Order>(&tenant.pool).await
 }
 
 #[get("/13/orders/total")]
-async fn total_orders(state: web::Data<AppState>) -> impl Responder {
-    if let Ok(row) = sqlx::query("SELECT SUM(quantity) FROM orders;")
-        .fetch_one(&state.pool)
-        .await
-    {
-        let count = row.get::<i64, _>(0);
+async fn total_orders(tenant: Tenant, range: web::Query<TimeRange>) -> impl Responder {
+    if let Err(e) = range.validate() {
+        return HttpResponse::BadRequest().body(e);
+    }
+    let mut query = totals::query("SELECT SUM(quantity)::BIGINT", &range);
+    if let Ok(row) = query.build().fetch_one(&tenant.pool).await {
+        let count = row.get::<Option<i64>, _>(0).unwrap_or(0);
         HttpResponse::Ok().json(json!({"total": count}))
     } else {
         HttpResponse::InternalServerError().body("Failed to get total orders")
@@ -89,13 +393,13 @@ async fn total_orders(state: web::Data<AppState>) -> impl Responder {
 }
 
 #[get("/13/orders/popular")]
-async fn most_popular_gift(state: web::Data<AppState>) -> impl Responder {
-    if let Ok(Some(row)) = sqlx::query(
-        "SELECT gift_name FROM orders GROUP BY gift_name ORDER BY SUM(quantity) DESC LIMIT 1;",
-    )
-    .fetch_optional(&state.pool)
-    .await
-    {
+async fn most_popular_gift(tenant: Tenant, range: web::Query<TimeRange>) -> impl Responder {
+    if let Err(e) = range.validate() {
+        return HttpResponse::BadRequest().body(e);
+    }
+    let mut query = totals::query("SELECT gift_name", &range);
+    query.push(" GROUP BY gift_name ORDER BY SUM(quantity) DESC LIMIT 1");
+    if let Ok(Some(row)) = query.build().fetch_optional(&tenant.pool).await {
         let gift_name = row.get::<String, _>(0);
         HttpResponse::Ok().json(json!({"popular": gift_name}))
     } else {
@@ -103,22 +407,43 @@ async fn most_popular_gift(state: web::Data<AppState>) -> impl Responder {
     }
 }
 
+#[get("/13/orders/trends")]
+async fn order_trends(
+    tenant: Tenant,
+    query: web::Query<TrendQuery>,
+) -> Result<HttpResponse, Error> {
+    let tz = parse_tz(query.tz.as_deref()).map_err(error::ErrorBadRequest)?;
+    let trends: Vec<Trend> = query
+        .build(tz.name())
+        .map_err(error::ErrorBadRequest)?
+        .build_query_as()
+        .fetch_all(&tenant.pool)
+        .await
+        .map_err(database_error)?;
+
+    Ok(HttpResponse::Ok().json(trends))
+}
+
 #[cfg(test)]
 mod test {
+    use crate::config::AppConfig;
+    use crate::tasks::thirteen::tenants::TenantConfig;
+    use crate::AppState;
+    use actix_web::http::StatusCode;
     use actix_web::{test, App};
     use serde_json::json;
     use serial_test::serial;
     use sqlx::postgres::PgPoolOptions;
-    use std::collections::HashMap;
-    use std::sync::Arc;
-    use std::sync::Mutex as SyncMutex;
     use tokio::fs;
-    use tokio::sync::Mutex;
     use toml::Table;
 
     use super::*;
 
     async fn set_up_sql() -> web::Data<AppState> {
+        set_up_sql_with(AppConfig::default()).await
+    }
+
+    async fn set_up_sql_with(config: AppConfig) -> web::Data<AppState> {
         let secrets_contents = fs::read_to_string("Secrets.dev.toml").await.unwrap();
         let secrets = secrets_contents.parse::<Table>().unwrap();
         let password = secrets
@@ -134,11 +459,7 @@ mod test {
             password, port
         );
         let pool = PgPoolOptions::new().connect(&database_url).await.unwrap();
-        web::Data::new(AppState {
-            pool,
-            rooms: Mutex::new(HashMap::new()),
-            view_count: Arc::new(SyncMutex::new(0_usize)),
-        })
+        web::Data::new(AppState::new(pool, config))
     }
 
     #[actix_web::test]
@@ -240,4 +561,601 @@ mod test {
         let body = test::read_body(res).await;
         assert_eq!(body, json!({"popular": "Toy Train"}).to_string());
     }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_order_resource() {
+        let state = set_up_sql().await;
+        let app = test::init_service(
+            App::new()
+                .app_data(state)
+                .service(reset)
+                .route("/13/orders", web::post().to(add_orders))
+                .service(list_orders)
+                .service(create_order)
+                .service(get_order)
+                .service(update_order)
+                .service(delete_order),
+        )
+        .await;
+
+        let req = test::TestRequest::post().uri("/13/reset").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        let req = test::TestRequest::post()
+            .uri("/13/orders")
+            .set_json(json!([
+                {"id":1,"region_id":2,"gift_name":"Toy Train","quantity":5},
+                {"id":2,"region_id":2,"gift_name":"Doll","quantity":8},
+                {"id":3,"region_id":3,"gift_name":"Action Figure","quantity":12},
+                {"id":4,"region_id":2,"gift_name":"Board Game","quantity":10,"created_at":"2023-12-13T08:00:00Z"}
+            ]))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        let req = test::TestRequest::get()
+            .uri("/orders?region_id=2&min_quantity=6&sort=-quantity")
+            .to_request();
+        let page: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(page["total"], 2);
+        assert_eq!(page["orders"][0]["gift_name"], "Board Game");
+        assert_eq!(page["orders"][1]["gift_name"], "Doll");
+
+        let req = test::TestRequest::get()
+            .uri("/orders?per_page=3&page=2")
+            .to_request();
+        let page: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(page["total"], 4);
+        assert_eq!(
+            page["orders"],
+            json!([{"id":4,"region_id":2,"gift_name":"Board Game","quantity":10,"created_at":"2023-12-13T08:00:00Z"}])
+        );
+
+        let req = test::TestRequest::put()
+            .uri("/orders/2")
+            .set_json(json!({"region_id":3,"gift_name":"Doll","quantity":9}))
+            .to_request();
+        let order: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(order["region_id"], 3);
+
+        let req = test::TestRequest::get().uri("/orders/2").to_request();
+        let order: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(order["quantity"], 9);
+
+        let req = test::TestRequest::post()
+            .uri("/orders")
+            .set_json(json!({"id":2,"region_id":1,"gift_name":"Kite","quantity":1}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 409);
+
+        let req = test::TestRequest::delete().uri("/orders/2").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 204);
+
+        for (req, status) in [
+            (test::TestRequest::get().uri("/orders/2"), 404),
+            (test::TestRequest::delete().uri("/orders/2"), 404),
+            (
+                test::TestRequest::put()
+                    .uri("/orders/2")
+                    .set_json(json!({"region_id":1,"gift_name":"Kite","quantity":1})),
+                404,
+            ),
+            (
+                test::TestRequest::put()
+                    .uri("/orders/1")
+                    .set_json(json!({"id":5,"region_id":1,"gift_name":"Kite","quantity":1})),
+                400,
+            ),
+            (test::TestRequest::get().uri("/orders?sort=name"), 400),
+            (test::TestRequest::get().uri("/orders?per_page=1000"), 400),
+            (
+                test::TestRequest::get().uri("/orders?per_page=100&page=9223372036854775807"),
+                400,
+            ),
+            (test::TestRequest::get().uri("/orders/4294967296"), 400),
+            (test::TestRequest::delete().uri("/orders/4294967296"), 400),
+            (
+                test::TestRequest::put()
+                    .uri("/orders/1")
+                    .set_json(json!({"region_id":4294967296_i64,"gift_name":"Kite","quantity":1})),
+                400,
+            ),
+            (
+                test::TestRequest::post().uri("/orders").set_json(
+                    json!({"id":4294967296_i64,"region_id":1,"gift_name":"Kite","quantity":1}),
+                ),
+                400,
+            ),
+        ] {
+            assert_eq!(
+                test::call_service(&app, req.to_request()).await.status(),
+                status
+            );
+        }
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_bulk_conflicts() {
+        let state = set_up_sql().await;
+        let app = test::init_service(
+            App::new()
+                .app_data(state)
+                .service(reset)
+                .route("/13/orders", web::post().to(add_orders))
+                .service(get_order),
+        )
+        .await;
+
+        let req = test::TestRequest::post().uri("/13/reset").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        let req = test::TestRequest::post()
+            .uri("/13/orders")
+            .set_json(json!([{"id":1,"region_id":2,"gift_name":"Toy Train","quantity":5,"created_at":"2023-12-01T08:00:00Z"}]))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        let batch = json!([
+            {"id":1,"region_id":2,"gift_name":"Doll","quantity":8},
+            {"id":2,"region_id":3,"gift_name":"Action Figure","quantity":12}
+        ]);
+
+        // The default rejects the batch and names the conflicting row
+        let req = test::TestRequest::post()
+            .uri("/13/orders")
+            .set_json(&batch)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let summary: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(summary["errors"][0]["id"], 1);
+        let req = test::TestRequest::get().uri("/orders/2").to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NOT_FOUND
+        );
+
+        let req = test::TestRequest::post()
+            .uri("/13/orders?on_conflict=skip")
+            .set_json(&batch)
+            .to_request();
+        let summary: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(summary["inserted"], json!([2]));
+        assert_eq!(summary["skipped"], json!([1]));
+
+        let req = test::TestRequest::post()
+            .uri("/13/orders?on_conflict=upsert")
+            .set_json(&batch)
+            .to_request();
+        let summary: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(summary["updated"], json!([1, 2]));
+        let req = test::TestRequest::get().uri("/orders/1").to_request();
+        let order: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(order["gift_name"], "Doll");
+        // Left out of the upsert, so the stored timestamp stays
+        assert_eq!(order["created_at"], "2023-12-01T08:00:00Z");
+
+        let req = test::TestRequest::post()
+            .uri("/13/orders?on_conflict=replace")
+            .set_json(json!([
+                {"id":2,"region_id":3,"gift_name":"Teddy Bear","quantity":1},
+                {"id":3,"region_id":3,"gift_name":"x".repeat(51),"quantity":1}
+            ]))
+            .to_request();
+        let summary: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(summary["updated"], json!([2]));
+        assert_eq!(summary["errors"][0]["id"], 3);
+
+        // A retry with the same key replays the first response without touching the table
+        let retry = json!([{"id":4,"region_id":1,"gift_name":"Drone","quantity":9}]);
+        let mut bodies = Vec::new();
+        for replayed in [false, true] {
+            let req = test::TestRequest::post()
+                .uri("/13/orders")
+                .insert_header(("Idempotency-Key", "upload-1"))
+                .set_json(&retry)
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert!(res.status().is_success());
+            assert_eq!(res.headers().contains_key("idempotent-replayed"), replayed);
+            bodies.push(test::read_body(res).await);
+        }
+        assert_eq!(bodies[0], bodies[1]);
+
+        let req = test::TestRequest::post()
+            .uri("/13/orders")
+            .insert_header(("Idempotency-Key", "upload-1"))
+            .set_json(&batch)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_csv_import_export() {
+        let state = set_up_sql().await;
+        let app = test::init_service(
+            App::new()
+                .app_data(state)
+                .service(reset)
+                .service(import_orders)
+                .service(export_orders),
+        )
+        .await;
+
+        let req = test::TestRequest::post().uri("/13/reset").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        let csv = "quantity,gift_name,id,region_id\n\
+            5,Toy Train,1,2\n\
+            8,\"Doll, Porcelain\",2,2\n\
+            lots,Drone,3,2\n\
+            3,Yarn Ball,1,4\n\
+            12,Action Figure,4\n";
+
+        // Strict by default, so the bad lines keep the good ones out too
+        let req = test::TestRequest::post()
+            .uri("/13/orders/import")
+            .set_payload(csv)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let report: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(report["inserted"], 0);
+        let lines: Vec<u64> = report["rejected"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|rejected| rejected["line"].as_u64().unwrap())
+            .collect();
+        assert_eq!(lines, vec![4, 5, 6]);
+
+        let req = test::TestRequest::post()
+            .uri("/13/orders/import?on_conflict=skip")
+            .set_payload(csv)
+            .to_request();
+        let report: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(report["inserted"], 2);
+        assert_eq!(report["rejected_total"], 3);
+
+        // Importing an export again only finds conflicts
+        let req = test::TestRequest::get()
+            .uri("/13/orders/export")
+            .to_request();
+        let exported = test::call_and_read_body(&app, req).await;
+        let text = std::str::from_utf8(&exported).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "id,region_id,gift_name,quantity,created_at");
+        assert!(lines[2].starts_with("2,2,\"Doll, Porcelain\",8,"));
+        let created_at = lines[1].rsplit_once(',').unwrap().1.to_string();
+        let req = test::TestRequest::post()
+            .uri("/13/orders/import?on_conflict=upsert")
+            .set_payload(exported)
+            .to_request();
+        let report: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(report["inserted"], 0);
+        assert_eq!(report["updated"], 2);
+
+        // An upsert without created_at keeps the timestamps of existing orders
+        let req = test::TestRequest::post()
+            .uri("/13/orders/import?on_conflict=upsert")
+            .set_payload("id,region_id,gift_name,quantity\n1,2,Toy Train,6\n")
+            .to_request();
+        let report: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(report["updated"], 1);
+        let req = test::TestRequest::get()
+            .uri("/13/orders/export")
+            .to_request();
+        let upserted = test::call_and_read_body(&app, req).await;
+        let upserted: Vec<&str> = std::str::from_utf8(&upserted).unwrap().lines().collect();
+        assert_eq!(
+            upserted[1].split_once(",6,"),
+            Some(("1,2,Toy Train", created_at.as_str()))
+        );
+
+        let req = test::TestRequest::post()
+            .uri("/13/orders/import")
+            .set_payload("id,gift_name\n1,Doll\n")
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_order_trends() {
+        let state = set_up_sql().await;
+        let app = test::init_service(
+            App::new()
+                .app_data(state)
+                .service(reset)
+                .route("/13/orders", web::post().to(add_orders))
+                .service(total_orders)
+                .service(order_trends),
+        )
+        .await;
+
+        let req = test::TestRequest::post().uri("/13/reset").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        let req = test::TestRequest::post()
+            .uri("/13/orders")
+            .set_json(json!([
+                {"id":1,"region_id":1,"gift_name":"Sled","quantity":2,"created_at":"2023-12-01T09:00:00Z"},
+                {"id":2,"region_id":1,"gift_name":"Doll","quantity":4,"created_at":"2023-12-01T23:30:00Z"},
+                {"id":3,"region_id":2,"gift_name":"Doll","quantity":6,"created_at":"2023-12-02T10:00:00Z"},
+                {"id":4,"region_id":2,"gift_name":"Sled","quantity":10,"created_at":"2023-12-04T10:00:00Z"}
+            ]))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        let req = test::TestRequest::get()
+            .uri("/13/orders/total?from=2023-12-02T00:00:00Z")
+            .to_request();
+        let total: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(total, json!({"total": 16}));
+
+        // The rolling figures cover a day and the one before it, even across the gap on the 3rd
+        let req = test::TestRequest::get()
+            .uri("/13/orders/trends?window=2")
+            .to_request();
+        let trends: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            trends[0],
+            json!({
+                "period": "2023-12-01", "orders": 2, "quantity": 6,
+                "p50": 3.0, "p90": 3.8, "p99": 3.98, "popular_gift": "Doll",
+                "rolling_orders": 2, "rolling_quantity": 6
+            })
+        );
+        assert_eq!(trends[1]["rolling_quantity"], 12);
+        assert_eq!(trends[2]["period"], "2023-12-04");
+        assert_eq!(trends[2]["rolling_quantity"], 10);
+
+        // Order 2 falls on the 2nd in Tokyo
+        let req = test::TestRequest::get()
+            .uri("/13/orders/trends?bucket=month&by_region=true&tz=Asia/Tokyo")
+            .to_request();
+        let trends: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(trends.as_array().unwrap().len(), 2);
+        assert_eq!(trends[0]["region_id"], 1);
+        assert_eq!(trends[1]["quantity"], 16);
+
+        let req = test::TestRequest::get()
+            .uri("/13/orders/trends?bucket=week&region_id=1&tz=Asia/Tokyo")
+            .to_request();
+        let trends: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(trends[0]["period"], "2023-11-27");
+        assert_eq!(trends[0]["orders"], 2);
+
+        for uri in [
+            "/13/orders/trends?bucket=year",
+            "/13/orders/trends?window=0",
+            "/13/orders/trends?tz=Mars/Olympus_Mons",
+            "/13/orders/total?from=2023-12-02T00:00:00Z&to=2023-12-01T00:00:00Z",
+        ] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            assert_eq!(
+                test::call_service(&app, req).await.status(),
+                StatusCode::BAD_REQUEST
+            );
+        }
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_order_totals_summary() {
+        let state = set_up_sql().await;
+        let pool = state.tenants.get("public", false).await.unwrap().pool;
+        let app = test::init_service(
+            App::new()
+                .app_data(state)
+                .service(reset)
+                .route("/13/orders", web::post().to(add_orders))
+                .service(update_order)
+                .service(delete_order)
+                .service(import_orders)
+                .service(total_orders)
+                .service(most_popular_gift),
+        )
+        .await;
+
+        // The summary must always agree with aggregating the orders from scratch, with a
+        // single row per key
+        let check = || async {
+            let expected: Vec<(Option<i32>, Option<String>, i64, i64)> = sqlx::query_as(
+                "SELECT region_id, gift_name, COUNT(*), COALESCE(SUM(quantity), 0)::BIGINT
+                FROM orders GROUP BY region_id, gift_name ORDER BY region_id, gift_name",
+            )
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+            let summary: Vec<(Option<i32>, Option<String>, i64, i64)> = sqlx::query_as(
+                "SELECT region_id, gift_name, orders, quantity
+                FROM order_totals ORDER BY region_id, gift_name",
+            )
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+            assert_eq!(summary, expected);
+        };
+
+        let req = test::TestRequest::post().uri("/13/reset").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        check().await;
+
+        let req = test::TestRequest::post()
+            .uri("/13/orders")
+            .set_json(json!([
+                {"id":1,"region_id":1,"gift_name":"Sled","quantity":3},
+                {"id":2,"region_id":1,"gift_name":"Sled","quantity":4},
+                {"id":3,"region_id":2,"gift_name":"Doll","quantity":5}
+            ]))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        check().await;
+
+        let req = test::TestRequest::post()
+            .uri("/13/orders?on_conflict=upsert")
+            .set_json(json!([{"id":3,"region_id":2,"gift_name":"Kite","quantity":1}]))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let req = test::TestRequest::put()
+            .uri("/orders/1")
+            .set_json(json!({"id":1,"region_id":3,"gift_name":"Sled","quantity":3}))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let req = test::TestRequest::delete().uri("/orders/2").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        check().await;
+
+        let req = test::TestRequest::post()
+            .uri("/13/orders/import?on_conflict=replace")
+            .set_payload("id,region_id,gift_name,quantity\n3,2,Doll,9\n4,2,Drone,2\n")
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        check().await;
+
+        // Orders missing their region or gift share a summary row
+        for id in [5, 6] {
+            sqlx::query("INSERT INTO orders (id, quantity) VALUES ($1, 0)")
+                .bind(id)
+                .execute(&pool)
+                .await
+                .unwrap();
+            check().await;
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/13/orders/total")
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, json!({"total": 14}));
+        let req = test::TestRequest::get()
+            .uri("/13/orders/popular")
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, json!({"popular": "Doll"}));
+
+        // A reset starts the summary over
+        let req = test::TestRequest::post().uri("/13/reset").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let req = test::TestRequest::get()
+            .uri("/13/orders/total")
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, json!({"total": 0}));
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_tenant_isolation() {
+        let state = set_up_sql_with(AppConfig {
+            tenants: TenantConfig {
+                admin_key: Some("admin".to_string()),
+                max_tenants: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .await;
+        let app = test::init_service(
+            App::new()
+                .app_data(state)
+                .service(reset)
+                .route("/13/orders", web::post().to(add_orders))
+                .service(total_orders)
+                .service(tenants::list_tenants),
+        )
+        .await;
+
+        for tenant in [None, Some("elves"), Some("reindeer")] {
+            let mut req = test::TestRequest::post().uri("/13/reset");
+            if let Some(tenant) = tenant {
+                req = req.insert_header(("X-Tenant", tenant));
+            }
+            assert!(test::call_service(&app, req.to_request())
+                .await
+                .status()
+                .is_success());
+        }
+
+        let req = test::TestRequest::post()
+            .uri("/13/orders")
+            .set_json(json!([{"id":1,"region_id":1,"gift_name":"Sled","quantity":3}]))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        // The same id again, which only clashes within a tenant
+        let req = test::TestRequest::post()
+            .uri("/13/orders")
+            .insert_header(("X-Tenant", "elves"))
+            .set_json(json!([
+                {"id":1,"region_id":1,"gift_name":"Doll","quantity":5},
+                {"id":2,"region_id":1,"gift_name":"Kite","quantity":4}
+            ]))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        for (tenant, total) in [("public", 3), ("elves", 9), ("reindeer", 0)] {
+            let req = test::TestRequest::get()
+                .uri("/13/orders/total")
+                .insert_header(("X-Tenant", tenant))
+                .to_request();
+            let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(body, json!({"total": total}), "{}", tenant);
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/13/orders/total")
+            .insert_header(("X-Tenant", "North Pole"))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+
+        // Only a reset creates a tenant, and only while there is room for it
+        let req = test::TestRequest::get()
+            .uri("/13/orders/total")
+            .insert_header(("X-Tenant", "gnomes"))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NOT_FOUND
+        );
+        let req = test::TestRequest::post()
+            .uri("/13/reset")
+            .insert_header(("X-Tenant", "gnomes"))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::FORBIDDEN
+        );
+
+        let req = test::TestRequest::get().uri("/tenants").to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        let req = test::TestRequest::get()
+            .uri("/tenants")
+            .insert_header(("X-Api-Key", "admin"))
+            .to_request();
+        let body: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        let rows = |tenant: &str| {
+            body.iter()
+                .find(|entry| entry["tenant"] == tenant)
+                .map(|entry| entry["rows"]["orders"].clone())
+        };
+        assert_eq!(rows("public"), Some(json!(1)));
+        assert_eq!(rows("elves"), Some(json!(2)));
+        assert_eq!(rows("reindeer"), Some(json!(0)));
+    }
 }
@@ -0,0 +1,437 @@
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::http::StatusCode;
+use actix_web::{error, Error, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::error::DatabaseError;
+use sqlx::postgres::{PgArguments, PgDatabaseError};
+use sqlx::query::Query;
+use sqlx::{Acquire, Executor, PgPool, Postgres, Row, Transaction};
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictStrategy {
+    // Any failing row rolls back the whole batch
+    #[default]
+    Error,
+    // Rows whose id exists are left untouched
+    Skip,
+    // Existing rows have the given columns updated in place
+    Upsert,
+    // Existing rows are overwritten in place, so unlisted columns get their defaults
+    Replace,
+}
+
+#[derive(Deserialize)]
+pub struct BulkQuery {
+    #[serde(default)]
+    pub on_conflict: ConflictStrategy,
+}
+
+pub trait BulkRow: Serialize {
+    const TABLE: &'static str;
+    // The first column is the primary key
+    const COLUMNS: &'static [&'static str];
+    // Columns that may be left out of an upload, because deserializing fills them in
+    const DEFAULTED: &'static [&'static str] = &[];
+
+    fn id(&self) -> i64;
+    fn bind<'q>(
+        &'q self,
+        query: Query<'q, Postgres, PgArguments>,
+    ) -> Query<'q, Postgres, PgArguments>;
+    // Runs after the request is hashed, so server-side defaults do not defeat idempotency
+    fn fill_defaults(&mut self) {}
+    fn validate(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// The tables use INT columns, so wider values are refused before Postgres sees them
+pub fn int_column(name: &str, value: i64) -> Result<(), String> {
+    i32::try_from(value)
+        .map(|_| ())
+        .map_err(|_| format!("{} must fit in a 32-bit integer", name))
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct RowError {
+    pub id: i64,
+    pub error: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct BulkSummary {
+    pub inserted: Vec<i64>,
+    pub updated: Vec<i64>,
+    pub skipped: Vec<i64>,
+    pub errors: Vec<RowError>,
+}
+
+impl BulkSummary {
+    pub fn status(&self, strategy: ConflictStrategy) -> StatusCode {
+        if strategy != ConflictStrategy::Error || self.errors.is_empty() {
+            StatusCode::OK
+        } else if self.errors.iter().any(|e| e.error == CONFLICT) {
+            StatusCode::CONFLICT
+        } else {
+            StatusCode::BAD_REQUEST
+        }
+    }
+}
+
+const CONFLICT: &str = "An entry with this id already exists";
+
+fn insert_sql<R: BulkRow>(strategy: ConflictStrategy, kept: &[&str]) -> String {
+    let placeholders: Vec<String> = (1..=R::COLUMNS.len()).map(|i| format!("${}", i)).collect();
+    let insert = format!(
+        "INSERT INTO {} ({}) VALUES ({})",
+        R::TABLE,
+        R::COLUMNS.join(", "),
+        placeholders.join(", ")
+    );
+    match strategy {
+        ConflictStrategy::Error => format!("{} RETURNING TRUE", insert),
+        ConflictStrategy::Skip => format!("{} ON CONFLICT (id) DO NOTHING RETURNING TRUE", insert),
+        // xmax is only set on rows that already existed, which tells updates from inserts
+        ConflictStrategy::Upsert => format!(
+            "{} ON CONFLICT (id) DO UPDATE SET {} RETURNING (xmax = 0)",
+            insert,
+            update_set::<R>(kept)
+        ),
+        // Updated rather than deleted, so rows referencing the existing one stay valid
+        ConflictStrategy::Replace => format!(
+            "{} ON CONFLICT (id) DO UPDATE SET {} RETURNING (xmax = 0)",
+            insert,
+            update_set::<R>(&[])
+        ),
+    }
+}
+
+// Columns in `kept` hold on to what the existing row has
+pub fn update_set<R: BulkRow>(kept: &[&str]) -> String {
+    R::COLUMNS[1..]
+        .iter()
+        .map(|column| {
+            if kept.contains(column) {
+                format!("{} = {}.{}", column, R::TABLE, column)
+            } else {
+                format!("{} = EXCLUDED.{}", column, column)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Fills in the defaults and returns the columns that needed one, so an upsert
+// does not overwrite those columns of an existing row with a default
+pub fn fill_omitted<R: BulkRow>(row: &mut R) -> Vec<&'static str> {
+    let given = serde_json::to_value(&*row).unwrap_or_default();
+    row.fill_defaults();
+    R::DEFAULTED
+        .iter()
+        .copied()
+        .filter(|column| given.get(column).is_none_or(|value| value.is_null()))
+        .collect()
+}
+
+// Postgres names the offending key in the detail, e.g. for foreign key violations
+pub fn describe(e: &dyn DatabaseError) -> String {
+    match e
+        .try_downcast_ref::<PgDatabaseError>()
+        .and_then(|e| e.detail())
+    {
+        Some(detail) => format!("{} ({})", e.message(), detail),
+        None => e.message().to_string(),
+    }
+}
+
+// Each row runs in its own savepoint, so one bad row does not poison the transaction
+async fn insert_rows<R: BulkRow>(
+    transaction: &mut Transaction<'_, Postgres>,
+    rows: &[R],
+    omitted: &[Vec<&str>],
+    strategy: ConflictStrategy,
+) -> Result<BulkSummary, sqlx::Error> {
+    let mut summary = BulkSummary::default();
+
+    for (row, omitted) in rows.iter().zip(omitted) {
+        if let Err(error) = row.validate() {
+            summary.errors.push(RowError {
+                id: row.id(),
+                error,
+            });
+            continue;
+        }
+
+        let mut savepoint = (&mut **transaction).begin().await?;
+        let sql = insert_sql::<R>(strategy, omitted);
+        match row
+            .bind(sqlx::query(&sql))
+            .fetch_optional(&mut *savepoint)
+            .await
+        {
+            Ok(Some(result)) => {
+                savepoint.commit().await?;
+                if !result.get::<bool, _>(0) {
+                    summary.updated.push(row.id());
+                } else {
+                    summary.inserted.push(row.id());
+                }
+            }
+            Ok(None) => {
+                savepoint.commit().await?;
+                summary.skipped.push(row.id());
+            }
+            Err(sqlx::Error::Database(e)) => {
+                savepoint.rollback().await?;
+                summary.errors.push(RowError {
+                    id: row.id(),
+                    error: if e.is_unique_violation() {
+                        CONFLICT.to_string()
+                    } else {
+                        describe(e.as_ref())
+                    },
+                });
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(summary)
+}
+
+struct StoredResponse {
+    request_hash: String,
+    status: u16,
+    body: String,
+}
+
+fn request_hash<R: BulkRow>(strategy: ConflictStrategy, rows: &[R]) -> String {
+    let canonical = serde_json::to_vec(&(strategy, rows)).unwrap_or_default();
+    format!("{:x}", Sha256::digest(canonical))
+}
+
+async fn ensure_idempotency_table(pool: &PgPool) -> Result<(), sqlx::Error> {
+    pool.execute(
+        "CREATE TABLE IF NOT EXISTS idempotency_keys (
+            scope VARCHAR(50),
+            key VARCHAR(255),
+            request_hash CHAR(64) NOT NULL,
+            status INT NOT NULL,
+            body TEXT NOT NULL,
+            PRIMARY KEY (scope, key)
+        );",
+    )
+    .await
+    .map(|_| ())
+}
+
+async fn stored_response(
+    pool: &PgPool,
+    scope: &str,
+    key: &str,
+) -> Result<Option<StoredResponse>, sqlx::Error> {
+    ensure_idempotency_table(pool).await?;
+    let row = sqlx::query(
+        "SELECT request_hash, status, body FROM idempotency_keys WHERE scope = $1 AND key = $2",
+    )
+    .bind(scope)
+    .bind(key)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.map(|row| StoredResponse {
+        request_hash: row.get(0),
+        status: row.get::<i32, _>(1) as u16,
+        body: row.get(2),
+    }))
+}
+
+// Stored in the same transaction as the rows, so a replay never sees a half-applied batch
+async fn store_response(
+    transaction: &mut Transaction<'_, Postgres>,
+    scope: &str,
+    key: &str,
+    response: &StoredResponse,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO idempotency_keys (scope, key, request_hash, status, body)
+        VALUES ($1, $2, $3, $4, $5)",
+    )
+    .bind(scope)
+    .bind(key)
+    .bind(&response.request_hash)
+    .bind(response.status as i32)
+    .bind(&response.body)
+    .execute(&mut **transaction)
+    .await
+    .map(|_| ())
+}
+
+const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+fn json_response(status: StatusCode, body: String) -> HttpResponse {
+    HttpResponse::build(status)
+        .insert_header((CONTENT_TYPE, "application/json"))
+        .body(body)
+}
+
+// The same batch insert for callers that have no HTTP request, so no idempotency.
+// Nothing is committed when the summary's status is not a success.
+pub async fn insert_batch<R: BulkRow>(
+    pool: &PgPool,
+    strategy: ConflictStrategy,
+    mut rows: Vec<R>,
+) -> Result<BulkSummary, sqlx::Error> {
+    let omitted: Vec<_> = rows.iter_mut().map(fill_omitted).collect();
+    let mut transaction = pool.begin().await?;
+    let summary = insert_rows(&mut transaction, &rows, &omitted, strategy).await?;
+    if summary.status(strategy).is_success() {
+        transaction.commit().await?;
+    } else {
+        transaction.rollback().await?;
+    }
+    Ok(summary)
+}
+
+// Inserts a batch with the chosen strategy. Successful batches sent with an
+// `Idempotency-Key` are remembered, and retries get the original response back.
+// Also returns the ids that were committed, which replays and failures have none of.
+pub async fn bulk_insert<R: BulkRow>(
+    pool: &PgPool,
+    req: &HttpRequest,
+    strategy: ConflictStrategy,
+    mut rows: Vec<R>,
+) -> Result<(HttpResponse, Vec<i64>), Error> {
+    let key = match req.headers().get(IDEMPOTENCY_KEY) {
+        Some(value) => match value.to_str() {
+            Ok(key) if !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LEN => Some(key),
+            _ => return Err(error::ErrorBadRequest("Invalid Idempotency-Key")),
+        },
+        None => None,
+    };
+    let hash = request_hash(strategy, &rows);
+
+    if let Some(key) = key {
+        if let Some(stored) = stored_response(pool, R::TABLE, key)
+            .await
+            .map_err(error::ErrorInternalServerError)?
+        {
+            if stored.request_hash != hash {
+                return Err(error::ErrorUnprocessableEntity(
+                    "Idempotency-Key was already used for a different request",
+                ));
+            }
+            let status = StatusCode::from_u16(stored.status).unwrap_or(StatusCode::OK);
+            let response = HttpResponse::build(status)
+                .insert_header((CONTENT_TYPE, "application/json"))
+                .insert_header(("Idempotent-Replayed", "true"))
+                .body(stored.body);
+            return Ok((response, Vec::new()));
+        }
+    }
+
+    let omitted: Vec<_> = rows.iter_mut().map(fill_omitted).collect();
+    let mut transaction = pool
+        .begin()
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    let summary = insert_rows(&mut transaction, &rows, &omitted, strategy)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    let status = summary.status(strategy);
+    let body = serde_json::to_string(&summary).map_err(error::ErrorInternalServerError)?;
+
+    if !status.is_success() {
+        transaction
+            .rollback()
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+        return Ok((json_response(status, body), Vec::new()));
+    }
+
+    if let Some(key) = key {
+        let stored = StoredResponse {
+            request_hash: hash,
+            status: status.as_u16(),
+            body: body.clone(),
+        };
+        store_response(&mut transaction, R::TABLE, key, &stored)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::Database(db) if db.is_unique_violation() => error::ErrorConflict(
+                    "A request with this Idempotency-Key is already being processed",
+                ),
+                e => error::ErrorInternalServerError(e),
+            })?;
+    }
+    transaction
+        .commit()
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let written = summary
+        .inserted
+        .into_iter()
+        .chain(summary.updated)
+        .collect();
+    Ok((json_response(status, body), written))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Gift {
+        id: i64,
+        name: String,
+    }
+
+    impl BulkRow for Gift {
+        const TABLE: &'static str = "gifts";
+        const COLUMNS: &'static [&'static str] = &["id", "name"];
+
+        fn id(&self) -> i64 {
+            self.id
+        }
+
+        fn bind<'q>(
+            &'q self,
+            query: Query<'q, Postgres, PgArguments>,
+        ) -> Query<'q, Postgres, PgArguments> {
+            query.bind(self.id).bind(&self.name)
+        }
+    }
+
+    #[test]
+    fn test_insert_sql() {
+        assert_eq!(
+            insert_sql::<Gift>(ConflictStrategy::Skip, &[]),
+            "INSERT INTO gifts (id, name) VALUES ($1, $2) ON CONFLICT (id) DO NOTHING RETURNING TRUE"
+        );
+        assert_eq!(
+            insert_sql::<Gift>(ConflictStrategy::Upsert, &[]),
+            "INSERT INTO gifts (id, name) VALUES ($1, $2) ON CONFLICT (id) DO UPDATE SET name = EXCLUDED.name RETURNING (xmax = 0)"
+        );
+        assert_eq!(update_set::<Gift>(&["name"]), "name = gifts.name");
+    }
+
+    #[test]
+    fn test_request_hash() {
+        let gifts = [Gift {
+            id: 1,
+            name: "Sled".to_string(),
+        }];
+        assert_eq!(
+            request_hash(ConflictStrategy::Skip, &gifts),
+            request_hash(ConflictStrategy::Skip, &gifts)
+        );
+        assert_ne!(
+            request_hash(ConflictStrategy::Skip, &gifts),
+            request_hash(ConflictStrategy::Upsert, &gifts)
+        );
+    }
+}
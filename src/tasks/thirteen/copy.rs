@@ -0,0 +1,458 @@
+use crate::tasks::thirteen::bulk::{describe, fill_omitted, update_set, BulkRow, ConflictStrategy};
+use actix_web::http::header::CONTENT_DISPOSITION;
+use actix_web::http::StatusCode;
+use actix_web::{error, web, Error, HttpResponse};
+use csv::{QuoteStyle, StringRecord, WriterBuilder};
+use csv_core::ReadRecordResult;
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt, TryStreamExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{Executor, PgPool, Row};
+
+// Keeps the report readable when a whole file is malformed
+const MAX_REPORTED_REJECTIONS: usize = 1000;
+const CONFLICT: &str = "An entry with this id already exists";
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct RejectedLine {
+    pub line: u64,
+    pub error: String,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct ImportReport {
+    pub inserted: u64,
+    pub updated: u64,
+    // Valid lines a trigger set aside, such as orders quarantined for an unknown region
+    pub skipped: u64,
+    pub rejected_total: usize,
+    pub rejected: Vec<RejectedLine>,
+}
+
+impl ImportReport {
+    fn reject(&mut self, line: u64, error: String) {
+        self.rejected_total += 1;
+        if self.rejected.len() < MAX_REPORTED_REJECTIONS {
+            self.rejected.push(RejectedLine { line, error });
+        }
+    }
+
+    fn status(&self, strategy: ConflictStrategy) -> StatusCode {
+        if strategy != ConflictStrategy::Error || self.rejected_total == 0 {
+            StatusCode::OK
+        } else if self.rejected.iter().any(|r| r.error == CONFLICT) {
+            StatusCode::CONFLICT
+        } else {
+            StatusCode::BAD_REQUEST
+        }
+    }
+}
+
+// Splits CSV records out of a body that arrives in arbitrary chunks
+struct RecordSplitter {
+    reader: csv_core::Reader,
+    output: Vec<u8>,
+    ends: Vec<usize>,
+    nout: usize,
+    nend: usize,
+    start_line: u64,
+}
+
+impl RecordSplitter {
+    fn new() -> Self {
+        RecordSplitter {
+            reader: csv_core::Reader::new(),
+            output: vec![0; 4096],
+            ends: vec![0; 16],
+            nout: 0,
+            nend: 0,
+            start_line: 1,
+        }
+    }
+
+    // An empty chunk marks the end of the body and flushes a final unterminated record
+    fn feed(&mut self, mut input: &[u8]) -> Vec<(u64, Result<StringRecord, String>)> {
+        let done = input.is_empty();
+        let mut records = Vec::new();
+        loop {
+            let (result, nin, nout, nend) = self.reader.read_record(
+                input,
+                &mut self.output[self.nout..],
+                &mut self.ends[self.nend..],
+            );
+            input = &input[nin..];
+            self.nout += nout;
+            self.nend += nend;
+
+            match result {
+                ReadRecordResult::InputEmpty if !done => return records,
+                ReadRecordResult::InputEmpty => {}
+                ReadRecordResult::OutputFull => {
+                    let len = self.output.len();
+                    self.output.resize(len * 2, 0);
+                }
+                ReadRecordResult::OutputEndsFull => {
+                    let len = self.ends.len();
+                    self.ends.resize(len * 2, 0);
+                }
+                ReadRecordResult::Record => {
+                    records.push((self.start_line, self.record()));
+                    self.nout = 0;
+                    self.nend = 0;
+                    self.start_line = self.reader.line();
+                }
+                ReadRecordResult::End => return records,
+            }
+        }
+    }
+
+    fn record(&self) -> Result<StringRecord, String> {
+        let mut start = 0;
+        let mut fields = Vec::with_capacity(self.nend);
+        for &end in &self.ends[..self.nend] {
+            let field = std::str::from_utf8(&self.output[start..end])
+                .map_err(|_| "Line is not valid UTF-8".to_string())?;
+            fields.push(field);
+            start = end;
+        }
+        let mut record = StringRecord::from(fields);
+        record.trim();
+        Ok(record)
+    }
+}
+
+fn check_header<R: BulkRow>(header: &StringRecord) -> Result<(), String> {
+    match R::COLUMNS
+        .iter()
+        .filter(|column| !R::DEFAULTED.contains(column))
+        .find(|column| !header.iter().any(|name| name == **column))
+    {
+        Some(column) => Err(format!("Missing column: {}", column)),
+        None => Ok(()),
+    }
+}
+
+fn check_record<R: BulkRow + DeserializeOwned>(
+    header: &StringRecord,
+    record: &StringRecord,
+) -> Result<(R, Vec<&'static str>), String> {
+    if record.len() != header.len() {
+        return Err(format!(
+            "Expected {} fields, found {}",
+            header.len(),
+            record.len()
+        ));
+    }
+    let mut row = record
+        .deserialize::<R>(Some(header))
+        .map_err(|e| match e.kind() {
+            csv::ErrorKind::Deserialize { err, .. } => err.to_string(),
+            _ => e.to_string(),
+        })?;
+    row.validate()?;
+    let omitted = fill_omitted(&mut row);
+    Ok((row, omitted))
+}
+
+// The staging line is written from the parsed row, so defaults and normalisation carry over.
+// The columns that got a default go last, as an array literal.
+fn staging_fields<R: BulkRow>(
+    line: u64,
+    (row, omitted): &(R, Vec<&str>),
+) -> Result<Vec<String>, String> {
+    let value = serde_json::to_value(row).map_err(|e| e.to_string())?;
+    Ok(std::iter::once(line.to_string())
+        .chain(R::COLUMNS.iter().map(|column| match &value[*column] {
+            Value::String(s) => s.clone(),
+            Value::Null => String::new(),
+            other => other.to_string(),
+        }))
+        .chain(std::iter::once(format!("{{{}}}", omitted.join(","))))
+        .collect())
+}
+
+// Constraint violations are caused by the uploaded data, not by the server
+fn database_error(e: sqlx::Error) -> Error {
+    match e {
+        sqlx::Error::Database(e) => error::ErrorBadRequest(describe(e.as_ref())),
+        e => error::ErrorInternalServerError(e),
+    }
+}
+
+// Valid lines are streamed through COPY into a staging table, which is then
+// checked for duplicates and merged into the real one with the chosen strategy
+pub async fn import_csv<R: BulkRow + DeserializeOwned>(
+    pool: &PgPool,
+    mut payload: web::Payload,
+    strategy: ConflictStrategy,
+) -> Result<HttpResponse, Error> {
+    let staging = format!("{}_import", R::TABLE);
+    let columns = R::COLUMNS.join(", ");
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    transaction
+        .execute(
+            format!(
+                "CREATE TEMP TABLE {} ON COMMIT DROP AS
+                SELECT 0::BIGINT AS line, {}, '{{}}'::TEXT[] AS omitted FROM {} WITH NO DATA",
+                staging,
+                columns,
+                R::TABLE
+            )
+            .as_str(),
+        )
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let mut report = ImportReport::default();
+    let mut splitter = RecordSplitter::new();
+    let mut header: Option<StringRecord> = None;
+    let mut copy = transaction
+        .copy_in_raw(&format!(
+            "COPY {} (line, {}, omitted) FROM STDIN WITH (FORMAT csv)",
+            staging, columns
+        ))
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    let mut finished = false;
+    while !finished {
+        let chunk = match payload.next().await {
+            // An empty chunk would read as the end of the body
+            Some(Ok(chunk)) if chunk.is_empty() => continue,
+            Some(Ok(chunk)) => chunk,
+            Some(Err(e)) => {
+                copy.abort("Upload failed")
+                    .await
+                    .map_err(error::ErrorInternalServerError)?;
+                return Err(error::ErrorBadRequest(e));
+            }
+            None => {
+                finished = true;
+                web::Bytes::new()
+            }
+        };
+
+        let mut writer = WriterBuilder::new()
+            .quote_style(QuoteStyle::NonNumeric)
+            .from_writer(Vec::new());
+        for (line, record) in splitter.feed(&chunk) {
+            let record = match record {
+                Ok(record) => record,
+                Err(e) => {
+                    report.reject(line, e);
+                    continue;
+                }
+            };
+            let Some(names) = &header else {
+                match check_header::<R>(&record) {
+                    Ok(()) => {
+                        header = Some(record);
+                        continue;
+                    }
+                    Err(e) => {
+                        copy.abort(e.as_str())
+                            .await
+                            .map_err(error::ErrorInternalServerError)?;
+                        return Err(error::ErrorBadRequest(e));
+                    }
+                }
+            };
+            match check_record::<R>(names, &record).and_then(|row| staging_fields(line, &row)) {
+                Ok(fields) => writer
+                    .write_record(fields)
+                    .map_err(error::ErrorInternalServerError)?,
+                Err(e) => report.reject(line, e),
+            }
+        }
+
+        let data = writer
+            .into_inner()
+            .map_err(|e| error::ErrorInternalServerError(e.to_string()))?;
+        if !data.is_empty() {
+            copy.send(data)
+                .await
+                .map_err(error::ErrorInternalServerError)?;
+        }
+    }
+
+    if header.is_none() {
+        copy.abort("Empty upload")
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+        return Err(error::ErrorBadRequest("The file has no header line"));
+    }
+    copy.finish().await.map_err(database_error)?;
+
+    // Only the first line with a given id is kept
+    let duplicates = sqlx::query(&format!(
+        "DELETE FROM {0} s WHERE EXISTS (SELECT 1 FROM {0} t WHERE t.id = s.id AND t.line < s.line)
+        RETURNING line, id::BIGINT",
+        staging
+    ))
+    .fetch_all(&mut *transaction)
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+    for row in duplicates {
+        report.reject(
+            row.get::<i64, _>(0) as u64,
+            format!("Duplicate id {} in the file", row.get::<i64, _>(1)),
+        );
+    }
+
+    let insert = format!(
+        "INSERT INTO {0} ({1}) SELECT {1} FROM {2} ORDER BY line",
+        R::TABLE,
+        columns,
+        staging
+    );
+    match strategy {
+        ConflictStrategy::Error | ConflictStrategy::Skip => {
+            let conflicts = sqlx::query(&format!(
+                "DELETE FROM {} s USING {} t WHERE s.id = t.id RETURNING s.line",
+                staging,
+                R::TABLE
+            ))
+            .fetch_all(&mut *transaction)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+            for row in conflicts {
+                report.reject(row.get::<i64, _>(0) as u64, CONFLICT.to_string());
+            }
+        }
+        // Existing rows keep their values where the file left a column to its default
+        ConflictStrategy::Upsert if !R::DEFAULTED.is_empty() => {
+            let kept: Vec<String> = R::DEFAULTED
+                .iter()
+                .map(|column| {
+                    format!(
+                        "{0} = CASE WHEN '{0}' = ANY(s.omitted) THEN t.{0} ELSE s.{0} END",
+                        column
+                    )
+                })
+                .collect();
+            sqlx::query(&format!(
+                "UPDATE {} s SET {} FROM {} t WHERE s.id = t.id",
+                staging,
+                kept.join(", "),
+                R::TABLE
+            ))
+            .execute(&mut *transaction)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+        }
+        ConflictStrategy::Upsert | ConflictStrategy::Replace => {}
+    }
+
+    let staged: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {}", staging))
+        .fetch_one(&mut *transaction)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    // Replacing updates in place too, so rows referencing the existing one stay valid
+    let (inserted, updated) = if matches!(
+        strategy,
+        ConflictStrategy::Upsert | ConflictStrategy::Replace
+    ) {
+        let row = sqlx::query(&format!(
+            "WITH upserted AS ({} ON CONFLICT (id) DO UPDATE SET {} RETURNING (xmax = 0) AS inserted)
+            SELECT COUNT(*) FILTER (WHERE inserted), COUNT(*) FILTER (WHERE NOT inserted) FROM upserted",
+            insert,
+            update_set::<R>(&[])
+        ))
+        .fetch_one(&mut *transaction)
+        .await
+        .map_err(database_error)?;
+        (row.get::<i64, _>(0) as u64, row.get::<i64, _>(1) as u64)
+    } else {
+        let affected = sqlx::query(&insert)
+            .execute(&mut *transaction)
+            .await
+            .map_err(database_error)?
+            .rows_affected();
+        (affected, 0)
+    };
+    report.inserted = inserted;
+    report.updated = updated;
+    report.skipped = (staged as u64).saturating_sub(inserted + updated);
+    report.rejected.sort_by_key(|rejected| rejected.line);
+
+    let status = report.status(strategy);
+    if status.is_success() {
+        transaction
+            .commit()
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+    } else {
+        report.inserted = 0;
+        report.updated = 0;
+        report.skipped = 0;
+        transaction
+            .rollback()
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+    }
+    Ok(HttpResponse::build(status).json(report))
+}
+
+// Streams straight from Postgres, with a header line that `import_csv` accepts
+pub async fn export_csv<R: BulkRow>(pool: &PgPool) -> Result<HttpResponse, Error> {
+    let statement = format!(
+        "COPY (SELECT {} FROM {} ORDER BY id) TO STDOUT WITH (FORMAT csv, HEADER)",
+        R::COLUMNS.join(", "),
+        R::TABLE
+    );
+    // The COPY stream borrows its connection, so a task owns both and forwards the chunks
+    let mut connection = pool
+        .acquire()
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    let (mut sender, receiver) = mpsc::channel(16);
+    actix_web::rt::spawn(async move {
+        let mut stream = match connection.copy_out_raw(&statement).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                let _ = sender.send(Err(e)).await;
+                return;
+            }
+        };
+        while let Some(chunk) = stream.next().await {
+            let failed = chunk.is_err();
+            if sender.send(chunk).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv")
+        .insert_header((
+            CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}.csv\"", R::TABLE),
+        ))
+        .streaming(receiver.map_err(error::ErrorInternalServerError)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_records_split_across_chunks() {
+        let mut splitter = RecordSplitter::new();
+        let mut records = splitter.feed(b"id,name\n1,\"North");
+        records.extend(splitter.feed(b"\nPole\"\n2, Europe"));
+        records.extend(splitter.feed(b""));
+
+        let lines: Vec<u64> = records.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, vec![1, 2, 4]);
+        let records: Vec<StringRecord> = records.into_iter().map(|(_, r)| r.unwrap()).collect();
+        assert_eq!(&records[1][1], "North\nPole");
+        assert_eq!(&records[2][1], "Europe");
+    }
+}
@@ -0,0 +1,281 @@
+use crate::tasks::thirteen::tenants::Tenant;
+use crate::tasks::thirteen::{Order, ORDER_COLUMNS};
+use actix::{
+    Actor, ActorFutureExt, Addr, AsyncContext, Context, Handler, Message, StreamHandler, WrapFuture,
+};
+use actix_web::{get, web, Error, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use serde::Serialize;
+use sqlx::PgPool;
+use std::collections::HashSet;
+
+#[derive(Serialize, sqlx::FromRow, Clone, Debug, PartialEq)]
+pub struct RegionTotal {
+    region: String,
+    total: i64,
+    top_gift: Option<String>,
+}
+
+#[derive(Message, Serialize, Clone, Debug)]
+#[rtype(result = "()")]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum FeedEvent {
+    Order { order: Order },
+    OrderDeleted { id: i64 },
+    // Only the regions that changed, except for the snapshot sent on connect
+    Totals { regions: Vec<RegionTotal> },
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Subscribe {
+    addr: Addr<FeedConnection>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Unsubscribe {
+    addr: Addr<FeedConnection>,
+}
+
+// Asks the feed to recompute the region totals from the tenant's tables
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Refresh(PgPool);
+
+pub struct OrderFeed {
+    subscribers: HashSet<Addr<FeedConnection>>,
+    totals: Vec<RegionTotal>,
+}
+
+impl Actor for OrderFeed {
+    type Context = Context<Self>;
+}
+
+impl OrderFeed {
+    fn new() -> Self {
+        OrderFeed {
+            subscribers: HashSet::new(),
+            totals: Vec::new(),
+        }
+    }
+
+    fn broadcast(&self, event: FeedEvent) {
+        for addr in self.subscribers.iter() {
+            addr.do_send(event.clone());
+        }
+    }
+}
+
+impl Handler<Subscribe> for OrderFeed {
+    type Result = ();
+
+    fn handle(&mut self, msg: Subscribe, _: &mut Context<Self>) {
+        msg.addr.do_send(FeedEvent::Totals {
+            regions: self.totals.clone(),
+        });
+        self.subscribers.insert(msg.addr);
+    }
+}
+
+impl Handler<Unsubscribe> for OrderFeed {
+    type Result = ();
+
+    fn handle(&mut self, msg: Unsubscribe, _: &mut Context<Self>) {
+        self.subscribers.remove(&msg.addr);
+    }
+}
+
+impl Handler<FeedEvent> for OrderFeed {
+    type Result = ();
+
+    fn handle(&mut self, msg: FeedEvent, _: &mut Context<Self>) {
+        self.broadcast(msg);
+    }
+}
+
+// The feed waits for each refresh before handling anything else, so refreshes finish in
+// the order they were asked for and the totals never step back to an older state
+impl Handler<Refresh> for OrderFeed {
+    type Result = ();
+
+    fn handle(&mut self, msg: Refresh, ctx: &mut Context<Self>) {
+        let refresh = async move { region_totals(&msg.0).await }
+            .into_actor(self)
+            .map(|totals, feed, _| {
+                // Orders on their own, without a regions table, have no totals to report
+                let Ok(totals) = totals else {
+                    return;
+                };
+                let changes = changed_totals(&feed.totals, &totals);
+                feed.totals = totals;
+                if !changes.is_empty() {
+                    feed.broadcast(FeedEvent::Totals { regions: changes });
+                }
+            });
+        ctx.wait(refresh);
+    }
+}
+
+// Regions that disappeared are reported with a zero total
+fn changed_totals(old: &[RegionTotal], new: &[RegionTotal]) -> Vec<RegionTotal> {
+    let mut changes: Vec<RegionTotal> = new
+        .iter()
+        .filter(|total| !old.contains(total))
+        .cloned()
+        .collect();
+    changes.extend(
+        old.iter()
+            .filter(|total| !new.iter().any(|t| t.region == total.region))
+            .map(|total| RegionTotal {
+                region: total.region.clone(),
+                total: 0,
+                top_gift: None,
+            }),
+    );
+    changes
+}
+
+struct FeedConnection {
+    feed: Addr<OrderFeed>,
+}
+
+impl Actor for FeedConnection {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.feed.do_send(Subscribe {
+            addr: ctx.address(),
+        });
+    }
+
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        self.feed.do_send(Unsubscribe {
+            addr: ctx.address(),
+        });
+    }
+}
+
+impl Handler<FeedEvent> for FeedConnection {
+    type Result = ();
+
+    fn handle(&mut self, msg: FeedEvent, ctx: &mut ws::WebsocketContext<Self>) {
+        ctx.text(serde_json::to_string(&msg).unwrap_or_default());
+    }
+}
+
+// The feed only talks; anything but a close is ignored
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for FeedConnection {
+    fn handle(&mut self, item: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match item {
+            Ok(ws::Message::Ping(bytes)) => ctx.pong(&bytes),
+            Ok(ws::Message::Close(reason)) => ctx.close(reason),
+            _ => (),
+        }
+    }
+}
+
+async fn region_totals(pool: &PgPool) -> Result<Vec<RegionTotal>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT regions.name AS region, SUM(order_totals.quantity)::BIGINT AS total,
+            (SELECT gift_name FROM order_totals top
+                WHERE top.region_id = regions.id
+                ORDER BY quantity DESC, gift_name
+                LIMIT 1) AS top_gift
+        FROM regions
+        INNER JOIN order_totals ON regions.id = order_totals.region_id
+        GROUP BY regions.id, regions.name
+        ORDER BY regions.name",
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub enum Change {
+    Written(Vec<i64>),
+    Deleted(i64),
+    // Too many rows to send one by one, so only the totals go out
+    Imported,
+}
+
+// Runs in the background once the response is on its way. Nothing is queried
+// until somebody has subscribed to the tenant's feed.
+pub fn publish(tenant: &Tenant, change: Change) {
+    let Some(feed) = tenant.order_feed.get().cloned() else {
+        return;
+    };
+    let pool = tenant.pool.clone();
+    actix_web::rt::spawn(async move {
+        match change {
+            Change::Written(ids) if !ids.is_empty() => {
+                let orders: Vec<Order> =
+                    sqlx::query_as(&format!("{} WHERE id = ANY($1) ORDER BY id", ORDER_COLUMNS))
+                        .bind(ids)
+                        .fetch_all(&pool)
+                        .await
+                        .unwrap_or_default();
+                for order in orders {
+                    feed.do_send(FeedEvent::Order { order });
+                }
+            }
+            Change::Deleted(id) => feed.do_send(FeedEvent::OrderDeleted { id }),
+            _ => (),
+        }
+        feed.do_send(Refresh(pool));
+    });
+}
+
+#[get("/18/ws/orders")]
+async fn order_feed(
+    req: HttpRequest,
+    stream: web::Payload,
+    tenant: Tenant,
+) -> Result<HttpResponse, Error> {
+    let feed = tenant
+        .order_feed
+        .get_or_init(|| OrderFeed::new().start())
+        .clone();
+    // Refreshed first, so the snapshot the new subscriber gets is current
+    feed.do_send(Refresh(tenant.pool.clone()));
+    ws::start(FeedConnection { feed }, &req, stream)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn total(region: &str, total: i64, top_gift: &str) -> RegionTotal {
+        RegionTotal {
+            region: region.to_string(),
+            total,
+            top_gift: Some(top_gift.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_changed_totals() {
+        let old = vec![
+            total("Europe", 5, "Doll"),
+            total("Oceania", 3, "Kite"),
+            total("Asia", 9, "Drone"),
+        ];
+        let new = vec![
+            total("Africa", 1, "Sled"),
+            total("Europe", 5, "Doll"),
+            total("Oceania", 4, "Kite"),
+        ];
+        assert_eq!(
+            changed_totals(&old, &new),
+            vec![
+                total("Africa", 1, "Sled"),
+                total("Oceania", 4, "Kite"),
+                RegionTotal {
+                    region: "Asia".to_string(),
+                    total: 0,
+                    top_gift: None,
+                },
+            ]
+        );
+        assert!(changed_totals(&new, &new).is_empty());
+    }
+}
@@ -0,0 +1,393 @@
+use crate::config::env_or;
+use crate::tasks::thirteen::feed::OrderFeed;
+use crate::AppState;
+use actix::Addr;
+use actix_web::dev::Payload;
+use actix_web::{error, get, web, Error, FromRequest, HttpRequest, HttpResponse};
+use futures::future::LocalBoxFuture;
+use serde_json::{json, Map, Value};
+use sqlx::postgres::PgPoolOptions;
+use sqlx::{PgPool, Row};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+const TENANT_HEADER: &str = "X-Tenant";
+const API_KEY_HEADER: &str = "X-Api-Key";
+// Callers that name no tenant keep using the tables in `public`
+pub const DEFAULT_TENANT: &str = "public";
+const MAX_TENANT_LEN: usize = 40;
+// Tables counted by the admin listing
+const TENANT_TABLES: [&str; 3] = ["orders", "regions", "orders_quarantine"];
+
+#[derive(Clone, Debug)]
+pub struct TenantConfig {
+    // API key to tenant name
+    pub api_keys: HashMap<String, String>,
+    // Only an API key may then pick a tenant; `X-Tenant` on its own is refused
+    pub require_api_key: bool,
+    // Key for `GET /tenants`, which is refused when it is unset
+    pub admin_key: Option<String>,
+    // Connections each tenant's pool may hold
+    pub pool_size: u32,
+    // Tenant schemas that may exist, and tenant pools that may be open, besides `public`
+    pub max_tenants: usize,
+    // Idle connections of a tenant's pool are closed after this long
+    pub idle_timeout: Duration,
+}
+
+impl Default for TenantConfig {
+    fn default() -> Self {
+        TenantConfig {
+            api_keys: HashMap::new(),
+            require_api_key: false,
+            admin_key: None,
+            pool_size: 5,
+            max_tenants: 50,
+            idle_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+impl TenantConfig {
+    pub fn from_env() -> Self {
+        let default = TenantConfig::default();
+        TenantConfig {
+            // `key=tenant` pairs, comma-separated; pairs naming an invalid tenant are dropped
+            api_keys: std::env::var("TENANT_API_KEYS")
+                .map(|pairs| {
+                    pairs
+                        .split(',')
+                        .filter_map(|pair| pair.split_once('='))
+                        .map(|(key, tenant)| (key.trim().to_string(), tenant.trim().to_string()))
+                        .filter(|(key, tenant)| !key.is_empty() && valid_name(tenant))
+                        .collect()
+                })
+                .unwrap_or(default.api_keys),
+            require_api_key: env_or("TENANT_REQUIRE_API_KEY", default.require_api_key),
+            admin_key: std::env::var("TENANT_ADMIN_KEY")
+                .ok()
+                .filter(|key| !key.is_empty()),
+            pool_size: env_or("TENANT_POOL_SIZE", default.pool_size),
+            max_tenants: env_or("TENANT_MAX", default.max_tenants),
+            idle_timeout: Duration::from_secs(env_or(
+                "TENANT_IDLE_TIMEOUT_SECS",
+                default.idle_timeout.as_secs(),
+            )),
+        }
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    (1..=MAX_TENANT_LEN).contains(&name.len())
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_')
+}
+
+// Names are checked before they get here, so the schema is safe to splice into SQL
+fn schema_name(tenant: &str) -> String {
+    if tenant == DEFAULT_TENANT {
+        DEFAULT_TENANT.to_string()
+    } else {
+        format!("tenant_{}", tenant)
+    }
+}
+
+// The orders tables of one tenant. Its pool only sees the tenant's schema, so the
+// handlers need not know which tenant they serve.
+#[derive(Clone)]
+pub struct Tenant {
+    pub pool: PgPool,
+    // Started by the tenant's first subscriber, since actors need the running system
+    pub(crate) order_feed: Arc<OnceLock<Addr<OrderFeed>>>,
+}
+
+impl Tenant {
+    fn new(pool: PgPool) -> Self {
+        Tenant {
+            pool,
+            order_feed: Arc::new(OnceLock::new()),
+        }
+    }
+}
+
+pub struct Tenants {
+    config: TenantConfig,
+    default: Tenant,
+    tenants: Mutex<HashMap<String, Tenant>>,
+}
+
+impl Tenants {
+    pub fn new(pool: PgPool, config: TenantConfig) -> Self {
+        Tenants {
+            config,
+            default: Tenant::new(pool),
+            tenants: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // The tenant named by the request's headers, where a key outranks the plain header
+    fn resolve(&self, req: &HttpRequest) -> Result<String, Error> {
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .map(|value| {
+                    value
+                        .to_str()
+                        .map_err(|_| error::ErrorBadRequest("Invalid header"))
+                })
+                .transpose()
+        };
+        let requested = header(TENANT_HEADER)?;
+
+        if let Some(key) = header(API_KEY_HEADER)? {
+            let Some(tenant) = self.config.api_keys.get(key) else {
+                return Err(error::ErrorUnauthorized("Unknown API key"));
+            };
+            return match requested {
+                Some(requested) if requested != tenant => Err(error::ErrorForbidden(
+                    "The API key does not belong to this tenant",
+                )),
+                _ => Ok(tenant.clone()),
+            };
+        }
+
+        match requested {
+            None => Ok(DEFAULT_TENANT.to_string()),
+            Some(_) if self.config.require_api_key => Err(error::ErrorUnauthorized(
+                "An API key is required to choose a tenant",
+            )),
+            // A tenant with a key is only reachable with it
+            Some(tenant) if self.config.api_keys.values().any(|keyed| keyed == tenant) => Err(
+                error::ErrorUnauthorized("An API key is required for this tenant"),
+            ),
+            Some(tenant) if valid_name(tenant) => Ok(tenant.to_string()),
+            Some(_) => Err(error::ErrorBadRequest(format!(
+                "Tenant names are 1 to {} lowercase letters, digits or underscores",
+                MAX_TENANT_LEN
+            ))),
+        }
+    }
+
+    // A tenant's schema is created by a reset, or on first use for a tenant with an API
+    // key; its tables come from the reset endpoints. Other tenants must already exist.
+    pub async fn get(&self, name: &str, create: bool) -> Result<Tenant, Error> {
+        if name == DEFAULT_TENANT {
+            return Ok(self.default.clone());
+        }
+        if let Some(tenant) = self.tenants.lock().unwrap().get(name) {
+            return Ok(tenant.clone());
+        }
+
+        let schema = schema_name(name);
+        let pool = &self.default.pool;
+        let exists: bool =
+            sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM pg_namespace WHERE nspname = $1)")
+                .bind(&schema)
+                .fetch_one(pool)
+                .await
+                .map_err(error::ErrorInternalServerError)?;
+        if !exists {
+            if !create && !self.config.api_keys.values().any(|keyed| keyed == name) {
+                return Err(error::ErrorNotFound(format!(
+                    "No tenant named {}; a reset creates it",
+                    name
+                )));
+            }
+            let count: i64 = sqlx::query_scalar(
+                "SELECT COUNT(*) FROM pg_namespace WHERE nspname LIKE 'tenant\\_%'",
+            )
+            .fetch_one(pool)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+            if count as usize >= self.config.max_tenants {
+                return Err(error::ErrorForbidden("No more tenants can be created"));
+            }
+            sqlx::query(&format!("CREATE SCHEMA IF NOT EXISTS {}", schema))
+                .execute(pool)
+                .await
+                .map_err(error::ErrorInternalServerError)?;
+        }
+
+        let options = (*pool.connect_options())
+            .clone()
+            .options([("search_path", schema.as_str())]);
+        let pool = PgPoolOptions::new()
+            .max_connections(self.config.pool_size)
+            .idle_timeout(self.config.idle_timeout)
+            .connect_lazy_with(options);
+
+        // Another request may have got here first, in which case its pool is kept
+        let mut tenants = self.tenants.lock().unwrap();
+        if !tenants.contains_key(name) && tenants.len() >= self.config.max_tenants {
+            return Err(error::ErrorServiceUnavailable("Too many tenants are open"));
+        }
+        Ok(tenants
+            .entry(name.to_string())
+            .or_insert_with(|| Tenant::new(pool))
+            .clone())
+    }
+}
+
+fn extract(req: &HttpRequest, create: bool) -> LocalBoxFuture<'static, Result<Tenant, Error>> {
+    let req = req.clone();
+    Box::pin(async move {
+        let state = req
+            .app_data::<web::Data<AppState>>()
+            .ok_or_else(|| error::ErrorInternalServerError("App state is not configured"))?;
+        let name = state.tenants.resolve(&req)?;
+        state.tenants.get(&name, create).await
+    })
+}
+
+impl FromRequest for Tenant {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        extract(req, false)
+    }
+}
+
+// The tenant of a reset endpoint, the only place a tenant's schema is created
+pub struct NewTenant(pub Tenant);
+
+impl FromRequest for NewTenant {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let tenant = extract(req, true);
+        Box::pin(async move { tenant.await.map(NewTenant) })
+    }
+}
+
+async fn row_counts(pool: &PgPool, schema: &str) -> Result<Map<String, Value>, sqlx::Error> {
+    let tables: Vec<String> = sqlx::query(
+        "SELECT table_name::TEXT FROM information_schema.tables
+        WHERE table_schema = $1 AND table_name = ANY($2)
+        ORDER BY table_name",
+    )
+    .bind(schema)
+    .bind(&TENANT_TABLES[..])
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| row.get(0))
+    .collect();
+
+    let mut counts = Map::new();
+    for table in tables {
+        let count: i64 = sqlx::query(&format!("SELECT COUNT(*) FROM {}.{}", schema, table))
+            .fetch_one(pool)
+            .await?
+            .get(0);
+        counts.insert(table, count.into());
+    }
+    Ok(counts)
+}
+
+// Every tenant with a schema, including ones only earlier runs have seen
+#[get("/tenants")]
+async fn list_tenants(req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+    let Some(admin_key) = &state.tenants.config.admin_key else {
+        return Err(error::ErrorForbidden(
+            "The tenant listing is disabled without an admin key",
+        ));
+    };
+    let key = req
+        .headers()
+        .get(API_KEY_HEADER)
+        .and_then(|key| key.to_str().ok());
+    if key != Some(admin_key.as_str()) {
+        return Err(error::ErrorUnauthorized("The admin key is required"));
+    }
+
+    let pool = &state.tenants.default.pool;
+    let mut names = vec![DEFAULT_TENANT.to_string()];
+    names.extend(
+        sqlx::query(
+            "SELECT substr(nspname, 8) FROM pg_namespace
+            WHERE nspname LIKE 'tenant\\_%'
+            ORDER BY nspname",
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(error::ErrorInternalServerError)?
+        .into_iter()
+        .map(|row| row.get::<String, _>(0))
+        .filter(|name| valid_name(name)),
+    );
+
+    let mut tenants = Vec::new();
+    for name in names {
+        let schema = schema_name(&name);
+        let rows = row_counts(pool, &schema)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+        tenants.push(json!({"tenant": name, "schema": schema, "rows": rows}));
+    }
+    Ok(HttpResponse::Ok().json(tenants))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test;
+
+    fn tenants(require_api_key: bool) -> Tenants {
+        let pool = PgPool::connect_lazy("postgres://localhost/postgres").unwrap();
+        Tenants::new(
+            pool,
+            TenantConfig {
+                api_keys: HashMap::from([("elf-key".to_string(), "elves".to_string())]),
+                require_api_key,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn resolve(tenants: &Tenants, headers: &[(&str, &str)]) -> Result<String, u16> {
+        let mut req = test::TestRequest::get();
+        for &header in headers {
+            req = req.insert_header(header);
+        }
+        tenants
+            .resolve(&req.to_http_request())
+            .map_err(|e| e.as_response_error().status_code().as_u16())
+    }
+
+    #[actix_web::test]
+    async fn test_resolve_tenant() {
+        let open = tenants(false);
+        assert_eq!(resolve(&open, &[]), Ok("public".to_string()));
+        assert_eq!(
+            resolve(&open, &[("X-Tenant", "reindeer")]),
+            Ok("reindeer".to_string())
+        );
+        assert_eq!(resolve(&open, &[("X-Tenant", "Rein deer")]), Err(400));
+        assert_eq!(
+            resolve(&open, &[("X-Api-Key", "elf-key")]),
+            Ok("elves".to_string())
+        );
+        assert_eq!(
+            resolve(&open, &[("X-Api-Key", "elf-key"), ("X-Tenant", "elves")]),
+            Ok("elves".to_string())
+        );
+        assert_eq!(
+            resolve(&open, &[("X-Api-Key", "elf-key"), ("X-Tenant", "reindeer")]),
+            Err(403)
+        );
+        assert_eq!(resolve(&open, &[("X-Api-Key", "wrong")]), Err(401));
+        assert_eq!(resolve(&open, &[("X-Tenant", "elves")]), Err(401));
+
+        let strict = tenants(true);
+        assert_eq!(resolve(&strict, &[]), Ok("public".to_string()));
+        assert_eq!(resolve(&strict, &[("X-Tenant", "reindeer")]), Err(401));
+        assert_eq!(
+            resolve(&strict, &[("X-Api-Key", "elf-key")]),
+            Ok("elves".to_string())
+        );
+    }
+}
@@ -0,0 +1,69 @@
+use crate::tasks::thirteen::trends::TimeRange;
+use sqlx::{Postgres, QueryBuilder};
+
+// Part of both resets, after `orders` is created. Statement triggers fold each write's
+// rows into `order_totals`, so every path that writes orders keeps it current, the
+// CSV import and quarantined orphans included. NULL keys are grouped through an
+// expression index, since `UNIQUE NULLS NOT DISTINCT` needs Postgres 15.
+pub const SCHEMA: &str = "
+    CREATE TABLE order_totals (
+      region_id INT,
+      gift_name VARCHAR(50),
+      orders BIGINT NOT NULL,
+      quantity BIGINT NOT NULL
+    );
+
+    CREATE UNIQUE INDEX order_totals_key ON order_totals (
+      (region_id IS NULL), (COALESCE(region_id, 0)), (gift_name IS NULL), (COALESCE(gift_name, ''))
+    );
+
+    CREATE OR REPLACE FUNCTION order_totals_apply() RETURNS trigger AS $$
+    BEGIN
+      IF TG_OP IN ('UPDATE', 'DELETE') THEN
+        INSERT INTO order_totals AS t (region_id, gift_name, orders, quantity)
+        SELECT region_id, gift_name, -COUNT(*), -COALESCE(SUM(quantity), 0)
+        FROM old_rows
+        GROUP BY region_id, gift_name
+        ON CONFLICT (
+          (region_id IS NULL), (COALESCE(region_id, 0)), (gift_name IS NULL), (COALESCE(gift_name, ''))
+        ) DO UPDATE
+        SET orders = t.orders + EXCLUDED.orders, quantity = t.quantity + EXCLUDED.quantity;
+      END IF;
+      IF TG_OP IN ('INSERT', 'UPDATE') THEN
+        INSERT INTO order_totals AS t (region_id, gift_name, orders, quantity)
+        SELECT region_id, gift_name, COUNT(*), COALESCE(SUM(quantity), 0)
+        FROM new_rows
+        GROUP BY region_id, gift_name
+        ON CONFLICT (
+          (region_id IS NULL), (COALESCE(region_id, 0)), (gift_name IS NULL), (COALESCE(gift_name, ''))
+        ) DO UPDATE
+        SET orders = t.orders + EXCLUDED.orders, quantity = t.quantity + EXCLUDED.quantity;
+      END IF;
+      DELETE FROM order_totals WHERE orders = 0;
+      RETURN NULL;
+    END
+    $$ LANGUAGE plpgsql;
+
+    CREATE TRIGGER order_totals_insert AFTER INSERT ON orders
+    REFERENCING NEW TABLE AS new_rows
+    FOR EACH STATEMENT EXECUTE FUNCTION order_totals_apply();
+
+    CREATE TRIGGER order_totals_update AFTER UPDATE ON orders
+    REFERENCING OLD TABLE AS old_rows NEW TABLE AS new_rows
+    FOR EACH STATEMENT EXECUTE FUNCTION order_totals_apply();
+
+    CREATE TRIGGER order_totals_delete AFTER DELETE ON orders
+    REFERENCING OLD TABLE AS old_rows
+    FOR EACH STATEMENT EXECUTE FUNCTION order_totals_apply();";
+
+// Unbounded ranges are answered from the summary. It has no timestamps, so a bounded
+// range still aggregates the orders themselves.
+pub fn query(select: &str, range: &TimeRange) -> QueryBuilder<'static, Postgres> {
+    if range.from.is_none() && range.to.is_none() {
+        QueryBuilder::new(format!("{} FROM order_totals", select))
+    } else {
+        let mut query = QueryBuilder::new(format!("{} FROM orders", select));
+        range.push_filters(&mut query);
+        query
+    }
+}
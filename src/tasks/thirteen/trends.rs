@@ -0,0 +1,175 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, QueryBuilder};
+
+// Longest rolling window, in buckets
+pub const MAX_WINDOW: u32 = 366;
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Bucket {
+    #[default]
+    Day,
+    // Weeks start on Monday
+    Week,
+    Month,
+}
+
+impl Bucket {
+    fn unit(&self) -> &'static str {
+        match self {
+            Bucket::Day => "day",
+            Bucket::Week => "week",
+            Bucket::Month => "month",
+        }
+    }
+}
+
+// Half-open, so consecutive ranges never count an order twice
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+pub struct TimeRange {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+impl TimeRange {
+    pub fn validate(&self) -> Result<(), String> {
+        match (self.from, self.to) {
+            (Some(from), Some(to)) if from >= to => Err("from must be before to".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn push_filters(&self, query: &mut QueryBuilder<'_, Postgres>) {
+        query.push(" WHERE TRUE");
+        if let Some(from) = self.from {
+            query.push(" AND created_at >= ").push_bind(from);
+        }
+        if let Some(to) = self.to {
+            query.push(" AND created_at < ").push_bind(to);
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TrendQuery {
+    #[serde(default)]
+    pub bucket: Bucket,
+    // Splits every bucket by region
+    #[serde(default)]
+    pub by_region: bool,
+    pub region_id: Option<i64>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    // Number of buckets, ending with the current one, that the rolling figures cover
+    pub window: Option<u32>,
+    // Buckets follow the local calendar of this zone
+    pub tz: Option<String>,
+}
+
+#[derive(Serialize, sqlx::FromRow, Debug, PartialEq)]
+pub struct Trend {
+    pub period: NaiveDate,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region_id: Option<i64>,
+    pub orders: i64,
+    pub quantity: i64,
+    pub p50: Option<f64>,
+    pub p90: Option<f64>,
+    pub p99: Option<f64>,
+    pub popular_gift: Option<String>,
+    pub rolling_orders: i64,
+    pub rolling_quantity: i64,
+}
+
+impl TrendQuery {
+    pub fn window(&self) -> Result<u32, String> {
+        match self.window.unwrap_or(1) {
+            window @ 1..=MAX_WINDOW => Ok(window),
+            _ => Err(format!("window must be between 1 and {}", MAX_WINDOW)),
+        }
+    }
+
+    // The bucket, window and zone are checked before they are spliced in; the rest is bound
+    pub fn build(&self, tz: &str) -> Result<QueryBuilder<'static, Postgres>, String> {
+        let range = TimeRange {
+            from: self.from,
+            to: self.to,
+        };
+        range.validate()?;
+        let window = self.window()?;
+        let unit = self.bucket.unit();
+        let region = if self.by_region {
+            "region_id::BIGINT"
+        } else {
+            "NULL::BIGINT"
+        };
+
+        let mut query = QueryBuilder::new(format!(
+            "WITH filtered AS (
+                SELECT *, date_trunc('{}', created_at AT TIME ZONE '{}')::DATE AS period, {} AS region
+                FROM orders",
+            unit, tz, region
+        ));
+        range.push_filters(&mut query);
+        if let Some(region_id) = self.region_id {
+            query.push(" AND region_id = ").push_bind(region_id);
+        }
+        query.push(format!(
+            "),
+            buckets AS (
+                SELECT period, region,
+                    COUNT(*) AS orders,
+                    COALESCE(SUM(quantity), 0)::BIGINT AS quantity,
+                    percentile_cont(0.5) WITHIN GROUP (ORDER BY quantity) AS p50,
+                    percentile_cont(0.9) WITHIN GROUP (ORDER BY quantity) AS p90,
+                    percentile_cont(0.99) WITHIN GROUP (ORDER BY quantity) AS p99
+                FROM filtered
+                GROUP BY period, region
+            ),
+            gifts AS (
+                SELECT DISTINCT ON (period, region) period, region, gift_name
+                FROM filtered
+                GROUP BY period, region, gift_name
+                ORDER BY period, region, SUM(quantity) DESC NULLS LAST, gift_name
+            )
+            SELECT b.period, b.region AS region_id, b.orders, b.quantity, b.p50, b.p90, b.p99,
+                g.gift_name AS popular_gift,
+                (SUM(b.orders) OVER w)::BIGINT AS rolling_orders,
+                (SUM(b.quantity) OVER w)::BIGINT AS rolling_quantity
+            FROM buckets b
+            LEFT JOIN gifts g ON g.period = b.period AND g.region IS NOT DISTINCT FROM b.region
+            WINDOW w AS (
+                PARTITION BY b.region ORDER BY b.period
+                RANGE BETWEEN INTERVAL '{} {}' PRECEDING AND CURRENT ROW
+            )
+            ORDER BY b.period, b.region",
+            window - 1,
+            unit
+        ));
+        Ok(query)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_query_validation() {
+        let query: TrendQuery = serde_json::from_str(r#"{"bucket": "week", "window": 4}"#).unwrap();
+        assert_eq!(query.window(), Ok(4));
+        let sql = query.build("UTC").unwrap().into_sql();
+        assert!(sql.contains("date_trunc('week'"));
+        assert!(sql.contains("INTERVAL '3 week' PRECEDING"));
+
+        let query: TrendQuery = serde_json::from_str(r#"{"window": 0}"#).unwrap();
+        assert!(query.build("UTC").is_err());
+        let query: TrendQuery = serde_json::from_str(
+            r#"{"from": "2023-12-02T00:00:00Z", "to": "2023-12-01T00:00:00Z"}"#,
+        )
+        .unwrap();
+        assert!(query.build("UTC").is_err());
+        assert!(serde_json::from_str::<TrendQuery>(r#"{"bucket": "year"}"#).is_err());
+    }
+}
@@ -1,66 +1,185 @@
-use actix_web::{get, post, web, HttpResponse, Responder};
-use chrono::{Datelike, NaiveDateTime, TimeZone, Utc, Weekday};
-use lazy_static::lazy_static;
+pub(crate) mod analytics;
+pub(crate) mod ids;
+pub(crate) mod store;
+
+use crate::tasks::twelve::analytics::DateFilter;
+use crate::AppState;
+use actix_web::{delete, get, post, web, HttpResponse, Responder};
+use chrono::{Datelike, Utc, Weekday};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::collections::HashMap;
-use std::sync::Mutex;
-use std::time::Instant;
-use ulid::Ulid;
-use uuid::Uuid;
+use std::time::Duration;
+use ulid::{Generator, Ulid};
 
-lazy_static! {
-    static ref STORE: Mutex<HashMap<String, Instant>> = Mutex::new(HashMap::new());
+#[derive(Deserialize)]
+struct SaveQuery {
+    ttl: Option<u64>,
 }
 
+// The body, when present, is kept as a JSON payload alongside the timestamp
 #[post("/12/save/{string}")]
-async fn save_string(string: web::Path<String>) -> impl Responder {
-    STORE
-        .lock()
-        .unwrap()
-        .insert(string.into_inner(), Instant::now());
-    HttpResponse::Ok().body("Saved!")
+async fn save_string(
+    state: web::Data<AppState>,
+    string: web::Path<String>,
+    query: web::Query<SaveQuery>,
+    body: web::Bytes,
+) -> impl Responder {
+    let value = if body.is_empty() {
+        None
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(value) => Some(value),
+            Err(e) => return HttpResponse::BadRequest().body(format!("Invalid JSON value: {}", e)),
+        }
+    };
+    match state
+        .timed_store
+        .save(&string, query.ttl.map(Duration::from_secs), value)
+    {
+        Ok(()) => HttpResponse::Ok().body("Saved!"),
+        Err(_) => HttpResponse::BadRequest().body("TTL is too large"),
+    }
 }
 
 #[get("/12/load/{string}")]
-async fn load_string(string: web::Path<String>) -> impl Responder {
-    if let Some(saved_at) = STORE.lock().unwrap().get(string.as_str()) {
-        let elapsed = Instant::now().duration_since(*saved_at);
-        return HttpResponse::Ok().body(elapsed.as_secs().to_string());
+async fn load_string(state: web::Data<AppState>, string: web::Path<String>) -> impl Responder {
+    match state.timed_store.get(&string) {
+        Some(entry) => HttpResponse::Ok().body(entry.age_secs.to_string()),
+        None => HttpResponse::BadRequest().body("Key not found in store!"),
     }
-    HttpResponse::BadRequest().body("Key not found in store!")
 }
 
-fn ulid_to_uuid(ulid_str: &str) -> Result<Uuid, &'static str> {
-    let ulid = Ulid::from_string(ulid_str).map_err(|_| "Invalid ULID")?;
-    let bytes = ulid.to_bytes();
+#[get("/12/keys")]
+async fn list_keys(state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(state.timed_store.list())
+}
+
+#[get("/12/keys/{key}")]
+async fn get_key(state: web::Data<AppState>, key: web::Path<String>) -> impl Responder {
+    match state.timed_store.get(&key) {
+        Some(entry) => HttpResponse::Ok().json(entry),
+        None => HttpResponse::NotFound().body("Key not found in store!"),
+    }
+}
 
-    // Split the ULID bytes to match the UUID field structure
-    let (fields, node) = bytes.split_at(8);
-    let (field1, field23) = fields.split_at(4);
-    let (field2, field3) = field23.split_at(2);
+#[delete("/12/keys/{key}")]
+async fn delete_key(state: web::Data<AppState>, key: web::Path<String>) -> impl Responder {
+    if state.timed_store.delete(&key) {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().body("Key not found in store!")
+    }
+}
 
-    let field1 = u32::from_be_bytes(field1.try_into().unwrap());
-    let field2 = u16::from_be_bytes(field2.try_into().unwrap());
-    let field3 = u16::from_be_bytes(field3.try_into().unwrap());
-    let node: [u8; 8] = node.try_into().unwrap();
+// Invalid items are reported in place instead of failing the whole batch
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Converted {
+    Id(String),
+    Invalid { input: String, error: String },
+}
 
-    Ok(Uuid::from_fields(field1, field2, field3, &node))
+fn converted<T: ToString>(input: String, result: Result<T, String>) -> Converted {
+    match result {
+        Ok(id) => Converted::Id(id.to_string()),
+        Err(error) => Converted::Invalid { input, error },
+    }
 }
 
 #[post("/12/ulids")]
 async fn convert_ulids_to_uuids(ulids: web::Json<Vec<String>>) -> impl Responder {
-    let mut uuids: Vec<String> = ulids
+    let mut uuids: Vec<Converted> = ulids
         .into_inner()
-        .iter()
-        .map(|ulid| ulid_to_uuid(ulid).unwrap().to_string())
+        .into_iter()
+        .map(|ulid| {
+            let uuid = ids::ulid_to_uuid(&ulid);
+            converted(ulid, uuid)
+        })
         .collect();
 
     uuids.reverse();
     HttpResponse::Ok().json(uuids)
 }
 
+#[post("/12/uuids")]
+async fn convert_uuids_to_ulids(uuids: web::Json<Vec<String>>) -> impl Responder {
+    let ulids: Vec<Converted> = uuids
+        .into_inner()
+        .into_iter()
+        .map(|uuid| {
+            let ulid = ids::uuid_to_ulid(&uuid);
+            converted(uuid, ulid)
+        })
+        .collect();
+    HttpResponse::Ok().json(ulids)
+}
+
+#[derive(Deserialize)]
+struct GenerateQuery {
+    count: Option<usize>,
+    timestamp: Option<String>,
+}
+
+#[post("/12/generate")]
+async fn generate_ulids(
+    state: web::Data<AppState>,
+    query: web::Query<GenerateQuery>,
+) -> impl Responder {
+    let count = query.count.unwrap_or(1);
+    if count == 0 || count > ids::MAX_GENERATE {
+        return HttpResponse::BadRequest()
+            .body(format!("count must be between 1 and {}", ids::MAX_GENERATE));
+    }
+    let at = match query.timestamp.as_deref().map(ids::parse_timestamp) {
+        Some(Ok(at)) => Some(at),
+        Some(Err(e)) => return HttpResponse::BadRequest().body(e),
+        None => None,
+    };
+
+    // An explicit timestamp gets its own sequence so earlier times are honoured
+    let generated = match at {
+        Some(at) => ids::generate(&mut Generator::new(), count, Some(at)),
+        None => ids::generate(&mut state.ulid_generator.lock().unwrap(), count, None),
+    };
+    match generated {
+        Ok(ulids) => HttpResponse::Ok().json(
+            ulids
+                .iter()
+                .map(|ulid| ulid.to_string())
+                .collect::<Vec<_>>(),
+        ),
+        Err(e) => HttpResponse::ServiceUnavailable().body(e),
+    }
+}
+
+#[get("/12/inspect/{id}")]
+async fn inspect_id(id: web::Path<String>) -> impl Responder {
+    match ids::inspect(&id) {
+        Ok(info) => HttpResponse::Ok().json(info),
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct TzQuery {
+    tz: Option<String>,
+}
+
 #[post("/12/ulids/{weekday}")]
-async fn analyze_ulids(weekday: web::Path<u8>, ulids: web::Json<Vec<String>>) -> impl Responder {
+async fn analyze_ulids(
+    weekday: web::Path<u8>,
+    query: web::Query<TzQuery>,
+    ulids: web::Json<Vec<String>>,
+) -> impl Responder {
+    let Ok(weekday) = Weekday::try_from(*weekday) else {
+        return HttpResponse::BadRequest()
+            .body("Weekday must be between 0 (Monday) and 6 (Sunday)");
+    };
+    let tz = match analytics::parse_tz(query.tz.as_deref()) {
+        Ok(tz) => tz,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+
     let mut christmas_eve_count = 0;
     let mut specified_weekday_count = 0;
     let mut future_count = 0;
@@ -68,18 +187,14 @@ async fn analyze_ulids(weekday: web::Path<u8>, ulids: web::Json<Vec<String>>) ->
 
     for ulid_str in ulids.into_inner() {
         if let Ok(ulid) = Ulid::from_string(&ulid_str) {
-            let timestamp = ulid.timestamp_ms();
-            if let Some(datetime) = NaiveDateTime::from_timestamp_opt((timestamp / 1000) as i64, 0)
-            {
-                let date = Utc.from_utc_datetime(&datetime);
-
+            if let Some(date) = analytics::local_datetime(&ulid, &tz) {
                 // Check for christmas eve
                 if date.month() == 12 && date.day() == 24 {
                     christmas_eve_count += 1;
                 }
 
                 // Check for specified weekday
-                if date.weekday() == Weekday::try_from(*weekday).unwrap() {
+                if date.weekday() == weekday {
                     specified_weekday_count += 1;
                 }
 
@@ -105,18 +220,63 @@ async fn analyze_ulids(weekday: web::Path<u8>, ulids: web::Json<Vec<String>>) ->
     }))
 }
 
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AnalyticsRequest {
+    ulids: Vec<String>,
+    tz: Option<String>,
+    #[serde(default)]
+    filter: DateFilter,
+}
+
+#[post("/12/analytics")]
+async fn ulid_analytics(request: web::Json<AnalyticsRequest>) -> impl Responder {
+    let tz = match analytics::parse_tz(request.tz.as_deref()) {
+        Ok(tz) => tz,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    if let Err(e) = request.filter.validate() {
+        return HttpResponse::BadRequest().body(e);
+    }
+
+    HttpResponse::Ok().json(analytics::analyze(
+        &request.ulids,
+        &tz,
+        &request.filter,
+        Utc::now(),
+    ))
+}
+
 #[cfg(test)]
 mod test {
+    use crate::config::AppConfig;
+    use crate::tasks::twelve::store::{ManualClock, TimedStore, TimedStoreConfig};
     use actix_web::{http::header, test, App};
     use serde_json::json;
-    use std::time::Duration;
-    use tokio::time::sleep;
+    use sqlx::postgres::PgPoolOptions;
+    use std::sync::Arc;
 
     use super::*;
 
+    fn set_up_state(clock: Arc<ManualClock>) -> web::Data<AppState> {
+        let pool = PgPoolOptions::new()
+            .connect_lazy("postgres://localhost/postgres")
+            .unwrap();
+        let mut state = AppState::new(pool, AppConfig::default());
+        state.timed_store = TimedStore::new(TimedStoreConfig::default(), clock);
+        web::Data::new(state)
+    }
+
     #[actix_web::test]
     async fn test_save_and_load_string() {
-        let app = test::init_service(App::new().service(save_string).service(load_string)).await;
+        let clock = Arc::new(ManualClock::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(set_up_state(clock.clone()))
+                .service(save_string)
+                .service(load_string),
+        )
+        .await;
 
         let req = test::TestRequest::post()
             .uri("/12/save/packet20231212")
@@ -124,8 +284,7 @@ mod test {
         let res = test::call_service(&app, req).await;
         assert!(res.status().is_success());
 
-        // Wait for 2 seconds
-        sleep(Duration::from_secs(2)).await;
+        clock.advance(Duration::from_secs(2));
 
         let req = test::TestRequest::get()
             .uri("/12/load/packet20231212")
@@ -135,8 +294,7 @@ mod test {
         let body = test::read_body(res).await;
         assert_eq!(&body, "2");
 
-        // Wait for another 2 seconds
-        sleep(Duration::from_secs(2)).await;
+        clock.advance(Duration::from_secs(2));
 
         let req = test::TestRequest::get()
             .uri("/12/load/packet20231212")
@@ -161,6 +319,61 @@ mod test {
         assert_eq!(&body, "0");
     }
 
+    #[actix_web::test]
+    async fn test_keys_with_ttl_and_value() {
+        let clock = Arc::new(ManualClock::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(set_up_state(clock.clone()))
+                .service(save_string)
+                .service(load_string)
+                .service(list_keys)
+                .service(get_key)
+                .service(delete_key),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/12/save/sled?ttl=10")
+            .set_payload(r#"{"colour": "red"}"#)
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let req = test::TestRequest::post().uri("/12/save/elf").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let req = test::TestRequest::post()
+            .uri("/12/save/bad")
+            .set_payload("{not json")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 400);
+        let req = test::TestRequest::post()
+            .uri("/12/save/forever?ttl=18446744073709551615")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 400);
+
+        clock.advance(Duration::from_secs(4));
+        let req = test::TestRequest::get().uri("/12/keys/sled").to_request();
+        let entry: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            entry,
+            json!({"key": "sled", "age_secs": 4, "expires_in_secs": 6, "value": {"colour": "red"}})
+        );
+
+        clock.advance(Duration::from_secs(6));
+        let req = test::TestRequest::get().uri("/12/keys").to_request();
+        let keys: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            keys,
+            json!([{"key": "elf", "age_secs": 10, "expires_in_secs": null}])
+        );
+        let req = test::TestRequest::get().uri("/12/load/sled").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 400);
+
+        let req = test::TestRequest::delete().uri("/12/keys/elf").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 204);
+        let req = test::TestRequest::delete().uri("/12/keys/elf").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 404);
+    }
+
     #[actix_web::test]
     async fn test_convert_ulids_to_uuids() {
         let app = test::init_service(App::new().service(convert_ulids_to_uuids)).await;
@@ -195,6 +408,135 @@ mod test {
         assert_eq!(returned_ulids, expected_uuids);
     }
 
+    #[actix_web::test]
+    async fn test_convert_with_invalid_items() {
+        let app = test::init_service(
+            App::new()
+                .service(convert_ulids_to_uuids)
+                .service(convert_uuids_to_ulids),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/12/ulids")
+            .set_json(json!(["01BJQ0E1C3Z56ABCD0E11HYX4M", "nope"]))
+            .to_request();
+        let res: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(res[0]["input"], "nope");
+        assert!(res[0]["error"].is_string());
+        assert_eq!(res[1], "015cae07-0583-f94c-a5b1-a070431f7494");
+
+        let req = test::TestRequest::post()
+            .uri("/12/uuids")
+            .set_json(json!(["015cae07-0583-f94c-a5b1-a070431f7494", "nope"]))
+            .to_request();
+        let res: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(res[0], "01BJQ0E1C3Z56ABCD0E11HYX4M");
+        assert_eq!(res[1]["input"], "nope");
+    }
+
+    #[actix_web::test]
+    async fn test_generate_and_inspect() {
+        let app = test::init_service(
+            App::new()
+                .app_data(set_up_state(Arc::new(ManualClock::new())))
+                .service(generate_ulids)
+                .service(inspect_id),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/12/generate?count=20&timestamp=2023-12-24T00:00:00Z")
+            .to_request();
+        let ulids: Vec<String> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(ulids.len(), 20);
+        assert!(ulids.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/12/inspect/{}", ulids[0]))
+            .to_request();
+        let info: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(info["timestamp_ms"], 1_703_376_000_000_u64);
+
+        let req = test::TestRequest::post()
+            .uri("/12/generate?count=3")
+            .to_request();
+        let first: Vec<String> = test::call_and_read_body_json(&app, req).await;
+        let req = test::TestRequest::post()
+            .uri("/12/generate?count=3")
+            .to_request();
+        let second: Vec<String> = test::call_and_read_body_json(&app, req).await;
+        assert!(first[2] < second[0]);
+
+        for uri in [
+            "/12/generate?count=0",
+            "/12/generate?count=1001",
+            "/12/generate?timestamp=soon",
+        ] {
+            let req = test::TestRequest::post().uri(uri).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), 400, "{}", uri);
+        }
+        let req = test::TestRequest::get()
+            .uri("/12/inspect/nope")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_analyze_ulids_in_time_zone() {
+        let app = test::init_service(App::new().service(analyze_ulids)).await;
+
+        // 2023-12-23T23:30:00Z, a Saturday in UTC but Sunday Christmas Eve in Oslo
+        let ulid = Ulid::from_parts(1_703_374_200_000, 1).to_string();
+        let req = test::TestRequest::post()
+            .uri("/12/ulids/6?tz=Europe/Oslo")
+            .set_json(json!([ulid]))
+            .to_request();
+        let result: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(result["christmas eve"], 1);
+        assert_eq!(result["weekday"], 1);
+
+        for uri in ["/12/ulids/7", "/12/ulids/6?tz=Nowhere"] {
+            let req = test::TestRequest::post()
+                .uri(uri)
+                .set_json(json!([ulid]))
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), 400, "{}", uri);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_ulid_analytics() {
+        let app = test::init_service(App::new().service(ulid_analytics)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/12/analytics")
+            .set_json(json!({
+                "ulids": [
+                    Ulid::from_parts(1_703_374_200_000, 1).to_string(),
+                    Ulid::from_parts(1_703_419_200_000, 1).to_string(),
+                    "bogus"
+                ],
+                "tz": "America/New_York",
+                "filter": {"weekdays": ["Sat", "Sun"]}
+            }))
+            .to_request();
+        let result: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(result["total"], 3);
+        assert_eq!(result["matched"], 2);
+        assert_eq!(result["by_hour"][18], 1);
+        assert_eq!(result["by_hour"][7], 1);
+        assert_eq!(result["by_day"], json!({"2023-12-23": 1, "2023-12-24": 1}));
+        assert_eq!(result["by_weekday"], json!([0, 0, 0, 0, 0, 1, 1]));
+        assert_eq!(result["invalid"][0]["input"], "bogus");
+
+        let req = test::TestRequest::post()
+            .uri("/12/analytics")
+            .set_json(json!({"ulids": [], "filter": {"months": [0]}}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 400);
+    }
+
     #[actix_web::test]
     async fn test_analyze_ulids() {
         let app = test::init_service(App::new().service(analyze_ulids)).await;
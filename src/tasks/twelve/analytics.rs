@@ -0,0 +1,215 @@
+use chrono::{DateTime, Datelike, NaiveDate, TimeZone, Timelike, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use ulid::Ulid;
+
+pub fn parse_tz(tz: Option<&str>) -> Result<Tz, String> {
+    match tz {
+        Some(name) => name
+            .parse()
+            .map_err(|_| format!("Unknown time zone: {}", name)),
+        None => Ok(Tz::UTC),
+    }
+}
+
+pub fn local_datetime(ulid: &Ulid, tz: &Tz) -> Option<DateTime<Tz>> {
+    Utc.timestamp_millis_opt(ulid.timestamp_ms() as i64)
+        .single()
+        .map(|datetime| datetime.with_timezone(tz))
+}
+
+// Every predicate that is set has to hold; a list matches when any of its entries does.
+// All of them are evaluated against the local calendar of the requested time zone.
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DateFilter {
+    // Inclusive bounds
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    pub months: Option<Vec<u32>>,
+    pub days: Option<Vec<u32>>,
+    pub weekdays: Option<Vec<Weekday>>,
+}
+
+impl DateFilter {
+    pub fn validate(&self) -> Result<(), String> {
+        if self
+            .months
+            .iter()
+            .flatten()
+            .any(|month| !(1..=12).contains(month))
+        {
+            return Err("months must be between 1 and 12".to_string());
+        }
+        if self
+            .days
+            .iter()
+            .flatten()
+            .any(|day| !(1..=31).contains(day))
+        {
+            return Err("days must be between 1 and 31".to_string());
+        }
+        if let (Some(from), Some(to)) = (self.from, self.to) {
+            if from > to {
+                return Err("from must not be after to".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn matches(&self, datetime: &DateTime<Tz>) -> bool {
+        let date = datetime.date_naive();
+        self.from.is_none_or(|from| from <= date)
+            && self.to.is_none_or(|to| date <= to)
+            && self
+                .months
+                .as_ref()
+                .is_none_or(|months| months.contains(&date.month()))
+            && self
+                .days
+                .as_ref()
+                .is_none_or(|days| days.contains(&date.day()))
+            && self
+                .weekdays
+                .as_ref()
+                .is_none_or(|weekdays| weekdays.contains(&date.weekday()))
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct InvalidUlid {
+    pub input: String,
+    pub error: String,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Analytics {
+    pub total: usize,
+    pub matched: usize,
+    pub in_the_future: usize,
+    // Local hour of day, 0 to 23
+    pub by_hour: Vec<usize>,
+    // Local calendar date
+    pub by_day: BTreeMap<NaiveDate, usize>,
+    // Monday first
+    pub by_weekday: Vec<usize>,
+    pub invalid: Vec<InvalidUlid>,
+}
+
+pub fn analyze(ulids: &[String], tz: &Tz, filter: &DateFilter, now: DateTime<Utc>) -> Analytics {
+    let mut analytics = Analytics {
+        total: ulids.len(),
+        matched: 0,
+        in_the_future: 0,
+        by_hour: vec![0; 24],
+        by_day: BTreeMap::new(),
+        by_weekday: vec![0; 7],
+        invalid: Vec::new(),
+    };
+
+    for input in ulids {
+        let datetime = Ulid::from_string(input)
+            .map_err(|e| e.to_string())
+            .and_then(|ulid| {
+                local_datetime(&ulid, tz).ok_or_else(|| "Timestamp out of range".to_string())
+            });
+        let datetime = match datetime {
+            Ok(datetime) => datetime,
+            Err(error) => {
+                analytics.invalid.push(InvalidUlid {
+                    input: input.clone(),
+                    error,
+                });
+                continue;
+            }
+        };
+        if !filter.matches(&datetime) {
+            continue;
+        }
+
+        analytics.matched += 1;
+        if datetime > now {
+            analytics.in_the_future += 1;
+        }
+        analytics.by_hour[datetime.hour() as usize] += 1;
+        *analytics.by_day.entry(datetime.date_naive()).or_default() += 1;
+        analytics.by_weekday[datetime.weekday().num_days_from_monday() as usize] += 1;
+    }
+
+    analytics
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ulid_at(rfc3339: &str) -> String {
+        let datetime = DateTime::parse_from_rfc3339(rfc3339).unwrap();
+        Ulid::from_parts(datetime.timestamp_millis() as u64, 1).to_string()
+    }
+
+    #[test]
+    fn test_local_calendar() {
+        // 23:30 UTC on the 23rd is already Christmas Eve in Oslo
+        let ulids = vec![
+            ulid_at("2023-12-23T23:30:00Z"),
+            ulid_at("2023-12-24T12:00:00Z"),
+            "not a ulid".to_string(),
+        ];
+        let filter: DateFilter =
+            serde_json::from_str(r#"{"months": [12], "days": [24], "weekdays": ["Sun"]}"#).unwrap();
+        let now = Utc.with_ymd_and_hms(2023, 12, 25, 0, 0, 0).unwrap();
+
+        let utc = analyze(&ulids, &parse_tz(None).unwrap(), &filter, now);
+        assert_eq!(utc.matched, 1);
+        assert_eq!(utc.invalid.len(), 1);
+
+        let oslo = analyze(
+            &ulids,
+            &parse_tz(Some("Europe/Oslo")).unwrap(),
+            &filter,
+            now,
+        );
+        assert_eq!(oslo.matched, 2);
+        assert_eq!(oslo.by_hour[0], 1);
+        assert_eq!(oslo.by_hour[13], 1);
+        assert_eq!(oslo.by_weekday[6], 2);
+        assert_eq!(
+            oslo.by_day
+                .get(&NaiveDate::from_ymd_opt(2023, 12, 24).unwrap()),
+            Some(&2)
+        );
+        assert_eq!(oslo.in_the_future, 0);
+    }
+
+    #[test]
+    fn test_date_range() {
+        let ulids = vec![
+            ulid_at("2023-01-31T12:00:00Z"),
+            ulid_at("2023-02-01T12:00:00Z"),
+            ulid_at("2023-02-28T12:00:00Z"),
+            ulid_at("2023-03-01T12:00:00Z"),
+        ];
+        let filter: DateFilter =
+            serde_json::from_str(r#"{"from": "2023-02-01", "to": "2023-02-28"}"#).unwrap();
+        let result = analyze(&ulids, &Tz::UTC, &filter, Utc::now());
+        assert_eq!(result.matched, 2);
+        assert_eq!(result.by_day.len(), 2);
+    }
+
+    #[test]
+    fn test_invalid_filters() {
+        assert!(parse_tz(Some("Mars/Olympus_Mons")).is_err());
+        for filter in [
+            r#"{"months": [13]}"#,
+            r#"{"days": [0]}"#,
+            r#"{"from": "2023-02-01", "to": "2023-01-01"}"#,
+        ] {
+            let filter: DateFilter = serde_json::from_str(filter).unwrap();
+            assert!(filter.validate().is_err());
+        }
+        assert!(serde_json::from_str::<DateFilter>(r#"{"weekdays": ["Caturday"]}"#).is_err());
+        assert!(serde_json::from_str::<DateFilter>(r#"{"month": [1]}"#).is_err());
+    }
+}
@@ -0,0 +1,132 @@
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Serialize;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use ulid::{Generator, Ulid};
+use uuid::Uuid;
+
+pub const MAX_GENERATE: usize = 1000;
+// ULID timestamps are 48 bits of milliseconds
+const MAX_TIMESTAMP_MS: u64 = (1 << 48) - 1;
+
+// Both IDs are 128 bits, so conversion is a reinterpretation of the same bytes
+pub fn ulid_to_uuid(ulid_str: &str) -> Result<Uuid, String> {
+    let ulid = Ulid::from_string(ulid_str.trim())
+        .map_err(|e| format!("Invalid ULID {}: {}", ulid_str, e))?;
+    Ok(Uuid::from_bytes(ulid.to_bytes()))
+}
+
+pub fn uuid_to_ulid(uuid_str: &str) -> Result<Ulid, String> {
+    let uuid = Uuid::parse_str(uuid_str.trim())
+        .map_err(|e| format!("Invalid UUID {}: {}", uuid_str, e))?;
+    Ok(Ulid::from_bytes(uuid.into_bytes()))
+}
+
+// Milliseconds since the epoch or an RFC 3339 date-time
+pub fn parse_timestamp(value: &str) -> Result<SystemTime, String> {
+    let ms = match value.parse::<u64>() {
+        Ok(ms) => ms,
+        Err(_) => {
+            let datetime = DateTime::parse_from_rfc3339(value)
+                .map_err(|e| format!("Invalid timestamp {}: {}", value, e))?;
+            u64::try_from(datetime.timestamp_millis())
+                .map_err(|_| format!("Timestamp {} is before 1970", value))?
+        }
+    };
+    if ms > MAX_TIMESTAMP_MS {
+        return Err(format!("Timestamp {} does not fit in a ULID", value));
+    }
+    Ok(UNIX_EPOCH + Duration::from_millis(ms))
+}
+
+// IDs from one call share the generator, so they sort in the order they were made
+pub fn generate(
+    generator: &mut Generator,
+    count: usize,
+    at: Option<SystemTime>,
+) -> Result<Vec<Ulid>, String> {
+    (0..count)
+        .map(|_| match at {
+            Some(at) => generator.generate_from_datetime(at),
+            None => generator.generate(),
+        })
+        .collect::<Result<_, _>>()
+        .map_err(|_| "Ran out of ULIDs for this millisecond".to_string())
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct IdInfo {
+    pub ulid: String,
+    pub uuid: String,
+    pub timestamp_ms: u64,
+    pub timestamp: String,
+    // The 80 random bits, in hex
+    pub entropy: String,
+    // The version nibble when the bytes are read as a UUID
+    pub version: usize,
+}
+
+// Accepts either form, telling them apart by length
+pub fn inspect(id: &str) -> Result<IdInfo, String> {
+    let ulid = if id.trim().len() == ulid::ULID_LEN {
+        Ulid::from_string(id.trim()).map_err(|e| format!("Invalid ULID {}: {}", id, e))?
+    } else {
+        uuid_to_ulid(id)?
+    };
+    let uuid = Uuid::from_bytes(ulid.to_bytes());
+    let timestamp_ms = ulid.timestamp_ms();
+
+    Ok(IdInfo {
+        ulid: ulid.to_string(),
+        uuid: uuid.to_string(),
+        timestamp_ms,
+        timestamp: Utc
+            .timestamp_millis_opt(timestamp_ms as i64)
+            .single()
+            .map(|datetime| datetime.to_rfc3339())
+            .unwrap_or_default(),
+        entropy: format!("{:020x}", ulid.random()),
+        version: uuid.get_version_num(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let uuid = ulid_to_uuid("01BJQ0E1C3Z56ABCD0E11HYX4M").unwrap();
+        assert_eq!(uuid.to_string(), "015cae07-0583-f94c-a5b1-a070431f7494");
+        assert_eq!(
+            uuid_to_ulid(&uuid.to_string()).unwrap().to_string(),
+            "01BJQ0E1C3Z56ABCD0E11HYX4M"
+        );
+        assert!(ulid_to_uuid("not a ulid").is_err());
+        assert!(uuid_to_ulid("015cae07").is_err());
+    }
+
+    #[test]
+    fn test_generate_is_monotonic() {
+        let at = parse_timestamp("2023-12-24T00:00:00Z").unwrap();
+        let ulids = generate(&mut Generator::new(), 50, Some(at)).unwrap();
+        assert!(ulids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(ulids
+            .iter()
+            .all(|ulid| ulid.timestamp_ms() == 1_703_376_000_000));
+
+        assert_eq!(parse_timestamp("1703376000000").unwrap(), at);
+        assert!(parse_timestamp("281474976710656").is_err());
+        assert!(parse_timestamp("yesterday").is_err());
+    }
+
+    #[test]
+    fn test_inspect() {
+        let from_ulid = inspect("01BJQ0E1C3Z56ABCD0E11HYX4M").unwrap();
+        let from_uuid = inspect("015cae07-0583-f94c-a5b1-a070431f7494").unwrap();
+        assert_eq!(from_ulid, from_uuid);
+        assert_eq!(from_ulid.timestamp_ms, 1_497_568_314_755);
+        assert_eq!(from_ulid.timestamp, "2017-06-15T23:11:54.755+00:00");
+        assert_eq!(from_ulid.entropy, "f94ca5b1a070431f7494");
+        assert_eq!(from_ulid.version, 15);
+    }
+}
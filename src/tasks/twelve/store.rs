@@ -0,0 +1,264 @@
+use crate::config::env_or;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+// A clock that only moves when told to, so tests can skip ahead without sleeping
+#[cfg(test)]
+pub struct ManualClock {
+    start: Instant,
+    offset: Mutex<Duration>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new() -> Self {
+        ManualClock {
+            start: Instant::now(),
+            offset: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.offset.lock().unwrap() += by;
+    }
+}
+
+#[cfg(test)]
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + *self.offset.lock().unwrap()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TimedStoreConfig {
+    // Applied when a save does not ask for its own TTL; `None` keeps keys forever
+    pub default_ttl: Option<Duration>,
+    pub sweep_interval: Duration,
+}
+
+impl Default for TimedStoreConfig {
+    fn default() -> Self {
+        TimedStoreConfig {
+            default_ttl: None,
+            sweep_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+impl TimedStoreConfig {
+    pub fn from_env() -> Self {
+        let default = TimedStoreConfig::default();
+        TimedStoreConfig {
+            default_ttl: std::env::var("TIMED_STORE_DEFAULT_TTL_SECS")
+                .ok()
+                .and_then(|secs| secs.parse().ok())
+                .map(Duration::from_secs),
+            sweep_interval: Duration::from_secs(env_or(
+                "TIMED_STORE_SWEEP_SECS",
+                default.sweep_interval.as_secs(),
+            )),
+        }
+    }
+}
+
+struct Entry {
+    saved_at: Instant,
+    expires_at: Option<Instant>,
+    value: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct EntryInfo {
+    pub key: String,
+    pub age_secs: u64,
+    pub expires_in_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<serde_json::Value>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct TtlOutOfRange;
+
+pub struct TimedStore {
+    config: TimedStoreConfig,
+    clock: Arc<dyn Clock>,
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+impl TimedStore {
+    pub fn new(config: TimedStoreConfig, clock: Arc<dyn Clock>) -> Self {
+        TimedStore {
+            config,
+            clock,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Fails when the TTL ends further out than the clock can count
+    pub fn save(
+        &self,
+        key: &str,
+        ttl: Option<Duration>,
+        value: Option<serde_json::Value>,
+    ) -> Result<(), TtlOutOfRange> {
+        let now = self.clock.now();
+        let expires_at = match ttl.or(self.config.default_ttl) {
+            Some(ttl) => Some(now.checked_add(ttl).ok_or(TtlOutOfRange)?),
+            None => None,
+        };
+        self.entries.lock().unwrap().insert(
+            key.to_string(),
+            Entry {
+                saved_at: now,
+                expires_at,
+                value,
+            },
+        );
+        Ok(())
+    }
+
+    // Expired keys read as missing even before the sweeper has removed them
+    pub fn get(&self, key: &str) -> Option<EntryInfo> {
+        let now = self.clock.now();
+        self.entries
+            .lock()
+            .unwrap()
+            .get(key)
+            .filter(|entry| is_live(entry, now))
+            .map(|entry| describe(key, entry, now, true))
+    }
+
+    pub fn list(&self) -> Vec<EntryInfo> {
+        let now = self.clock.now();
+        let mut listed: Vec<EntryInfo> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, entry)| is_live(entry, now))
+            .map(|(key, entry)| describe(key, entry, now, false))
+            .collect();
+        listed.sort_by(|a, b| a.key.cmp(&b.key));
+        listed
+    }
+
+    pub fn delete(&self, key: &str) -> bool {
+        let now = self.clock.now();
+        self.entries
+            .lock()
+            .unwrap()
+            .remove(key)
+            .is_some_and(|entry| is_live(&entry, now))
+    }
+
+    pub fn spawn_sweeper(&self) {
+        let entries = self.entries.clone();
+        let clock = self.clock.clone();
+        let period = self.config.sweep_interval.max(Duration::from_secs(1));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                purge(&entries, clock.now());
+            }
+        });
+    }
+}
+
+fn is_live(entry: &Entry, now: Instant) -> bool {
+    entry.expires_at.is_none_or(|expires_at| now < expires_at)
+}
+
+fn purge(entries: &Mutex<HashMap<String, Entry>>, now: Instant) -> usize {
+    let mut entries = entries.lock().unwrap();
+    let before = entries.len();
+    entries.retain(|_, entry| is_live(entry, now));
+    before - entries.len()
+}
+
+fn describe(key: &str, entry: &Entry, now: Instant, with_value: bool) -> EntryInfo {
+    EntryInfo {
+        key: key.to_string(),
+        age_secs: now.duration_since(entry.saved_at).as_secs(),
+        expires_in_secs: entry
+            .expires_at
+            .map(|expires_at| expires_at.duration_since(now).as_secs()),
+        value: if with_value {
+            entry.value.clone()
+        } else {
+            None
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_entries_expire() {
+        let clock = Arc::new(ManualClock::new());
+        let store = TimedStore::new(TimedStoreConfig::default(), clock.clone());
+
+        store
+            .save("short", Some(Duration::from_secs(5)), None)
+            .unwrap();
+        store
+            .save("forever", None, Some(serde_json::json!({"gift": "sled"})))
+            .unwrap();
+        clock.advance(Duration::from_secs(3));
+
+        let short = store.get("short").unwrap();
+        assert_eq!(short.age_secs, 3);
+        assert_eq!(short.expires_in_secs, Some(2));
+        assert_eq!(store.list().len(), 2);
+
+        clock.advance(Duration::from_secs(2));
+        assert!(store.get("short").is_none());
+        assert_eq!(store.list().len(), 1);
+        assert_eq!(purge(&store.entries, clock.now()), 1);
+        assert_eq!(
+            store.get("forever").unwrap().value,
+            Some(serde_json::json!({"gift": "sled"}))
+        );
+
+        assert!(store.delete("forever"));
+        assert!(!store.delete("forever"));
+    }
+
+    #[test]
+    fn test_default_ttl() {
+        let clock = Arc::new(ManualClock::new());
+        let config = TimedStoreConfig {
+            default_ttl: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let store = TimedStore::new(config, clock.clone());
+
+        store.save("packet", None, None).unwrap();
+        clock.advance(Duration::from_secs(10));
+        assert!(store.get("packet").is_none());
+    }
+}